@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context, Result};
+use phylotree::tree::{Node, Tree};
+use serde::{Deserialize, Serialize};
+
+use phylocompare::io::{self, is_newick, ReadOpts};
+
+// Serializable representation of a tree node
+#[derive(Serialize, Deserialize)]
+struct CachedNode {
+    name: Option<String>,
+    // Index of the parent in the preorder node list
+    parent: Option<usize>,
+    parent_edge: Option<f64>,
+}
+
+// Flatten a tree into its nodes in preorder
+fn to_cached(tree: &Tree) -> Result<Vec<CachedNode>> {
+    let root = tree.get_root()?;
+    let order = tree.preorder(&root)?;
+    let index: HashMap<_, _> = order.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+
+    order
+        .iter()
+        .map(|id| {
+            let node = tree.get(id)?;
+            Ok(CachedNode {
+                name: node.name.clone(),
+                parent: node.parent.map(|p| index[&p]),
+                parent_edge: node.parent_edge,
+            })
+        })
+        .collect()
+}
+
+// Rebuild a tree from its nodes in preorder
+fn from_cached(nodes: Vec<CachedNode>) -> Result<Tree> {
+    let mut tree = Tree::new();
+    let mut ids = Vec::with_capacity(nodes.len());
+
+    for cached in nodes {
+        let node = match cached.name {
+            Some(name) => Node::new_named(&name),
+            None => Node::new(),
+        };
+        let id = match cached.parent {
+            None => tree.add(node),
+            Some(parent) => tree.add_child(node, ids[parent], cached.parent_edge)?,
+        };
+        ids.push(id);
+    }
+
+    tree.reset_depths()?;
+
+    Ok(tree)
+}
+
+// Version of the way reference trees are read and cached, to bump when it
+// changes (e.g. how comments are stripped or ids are derived) so that older
+// caches are rebuilt
+const CACHE_VERSION: u32 = 1;
+
+// Settings the trees of a cache were read with, written before them: a cache
+// read with other settings is stale
+#[derive(Serialize, Deserialize, PartialEq)]
+struct CacheKey {
+    version: u32,
+    id_segments: Option<usize>,
+    // Checksum of the tip map
+    tip_map: Option<String>,
+}
+
+impl CacheKey {
+    fn new(opts: &ReadOpts) -> Self {
+        Self {
+            version: CACHE_VERSION,
+            id_segments: opts.id_segments,
+            tip_map: opts.tip_map.as_ref().map(|map| map.checksum()),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    fs::metadata(path)
+        .and_then(|m| m.modified())
+        .context(format!(
+            "Could not get modification time of: {}",
+            path.display()
+        ))
+}
+
+/// Check that a cache exists and is newer than the reference directory (or
+/// archive) and every newick file it contains
+pub fn is_fresh(cache: &Path, ref_dir: &Path) -> Result<bool> {
+    if !cache.exists() {
+        return Ok(false);
+    }
+    let cache_time = modified(cache)?;
+
+    // The directory mtime changes when files are added or removed
+    if modified(ref_dir)? > cache_time {
+        return Ok(false);
+    }
+    // A zip archive is a single file
+    if ref_dir.is_file() {
+        return Ok(true);
+    }
+
+    for path in fs::read_dir(ref_dir)?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| is_newick(p))
+    {
+        if modified(&path)? > cache_time {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Load reference trees, from the cache if it is up to date and was built
+/// with the same read options
+pub fn read_refs(
+    ref_dir: &Path,
+    opts: &ReadOpts,
+    cache_path: Option<&Path>,
+) -> Result<HashMap<String, Tree>> {
+    if let Some(path) = cache_path {
+        if is_fresh(path, ref_dir)? {
+            match load(path, opts) {
+                Ok(Some(trees)) => return Ok(trees),
+                Ok(None) => {}
+                Err(e) => eprintln!("Could not load reference cache, re-reading trees: {e:#}"),
+            }
+        }
+    }
+
+    let trees = io::read_refs(ref_dir, opts)?;
+
+    if let Some(path) = cache_path {
+        save(path, &trees, opts)?;
+    }
+
+    Ok(trees)
+}
+
+/// Load reference trees from a cache file, `None` if it was built with other
+/// read options
+pub fn load(cache: &Path, opts: &ReadOpts) -> Result<Option<HashMap<String, Tree>>> {
+    let mut reader = BufReader::new(File::open(cache)?);
+    let key: CacheKey = bincode::deserialize_from(&mut reader)?;
+    if key != CacheKey::new(opts) {
+        return Ok(None);
+    }
+    let cached: HashMap<String, Vec<CachedNode>> = bincode::deserialize_from(reader)?;
+
+    cached
+        .into_iter()
+        .map(|(id, nodes)| Ok((id, from_cached(nodes)?)))
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// Write reference trees to a cache file, with the read options they were
+/// read with
+pub fn save(cache: &Path, trees: &HashMap<String, Tree>, opts: &ReadOpts) -> Result<()> {
+    let cached = trees
+        .iter()
+        .map(|(id, tree)| Ok((id, to_cached(tree)?)))
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let mut writer = BufWriter::new(
+        File::create(cache).context(format!("Could not create cache file: {}", cache.display()))?,
+    );
+    bincode::serialize_into(&mut writer, &CacheKey::new(opts))?;
+    bincode::serialize_into(&mut writer, &cached)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Position of a tree in a spill file
+#[derive(Debug, Clone, Copy)]
+pub struct Spilled {
+    offset: u64,
+    len: usize,
+}
+
+/// Temporary file holding trees waiting to be compared, to keep them out of
+/// memory. The file is removed when dropped.
+pub struct SpillFile {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl SpillFile {
+    /// Create a spill file in the temporary directory
+    pub fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("phylocompare-{}.spill", std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .context(format!("Could not create spill file: {}", path.display()))?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append a tree to the file
+    pub fn push(&self, tree: &Tree) -> Result<Spilled> {
+        let bytes = bincode::serialize(&to_cached(tree)?)?;
+        let mut file = self.file.lock().unwrap();
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(&bytes).context(format!(
+            "Could not write to spill file: {}",
+            self.path.display()
+        ))?;
+
+        Ok(Spilled {
+            offset,
+            len: bytes.len(),
+        })
+    }
+
+    /// Read a tree back from the file
+    pub fn load(&self, spilled: Spilled) -> Result<Tree> {
+        let mut bytes = vec![0; spilled.len];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(spilled.offset))?;
+            file.read_exact(&mut bytes).context(format!(
+                "Could not read from spill file: {}",
+                self.path.display()
+            ))?;
+        }
+
+        from_cached(bincode::deserialize(&bytes)?)
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A tree waiting to be compared, kept in memory or spilled to disk
+#[derive(Debug)]
+pub enum TaskTree {
+    Loaded(Tree),
+    Spilled(Spilled),
+}
+
+impl TaskTree {
+    /// Spill a tree to the file if there is one, keep it in memory otherwise
+    pub fn new(tree: Tree, spill: Option<&SpillFile>) -> Result<Self> {
+        Ok(match spill {
+            Some(spill) => Self::Spilled(spill.push(&tree)?),
+            None => Self::Loaded(tree),
+        })
+    }
+
+    /// The tree, if it is kept in memory
+    pub fn as_loaded(&self) -> Option<&Tree> {
+        match self {
+            Self::Loaded(tree) => Some(tree),
+            Self::Spilled(_) => None,
+        }
+    }
+
+    /// Get the tree, reading it back from the spill file if needed
+    pub fn load(self, spill: Option<&SpillFile>) -> Result<Tree> {
+        match (self, spill) {
+            (Self::Loaded(tree), _) => Ok(tree),
+            (Self::Spilled(spilled), Some(spill)) => spill.load(spilled),
+            (Self::Spilled(_), None) => bail!("Tree was spilled without a spill file"),
+        }
+    }
+}
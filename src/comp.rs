@@ -1,29 +1,84 @@
-use std::sync::Arc;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
 use itertools::Itertools;
 use phylotree::tree::{Comparison, Tree};
-use serde::Serialize;
+use rayon::prelude::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dist::{self, RefDistCache, TipDistances},
+    io,
+    splits::{self, BranchPairs, SplitWeight},
+    transform,
+};
 
 #[derive(Serialize, Default, Debug)]
 pub struct BranchRecord {
+    #[serde(serialize_with = "io::serialize_id")]
     pub id: Arc<String>,
+    #[serde(serialize_with = "io::serialize_opt_f64")]
     pub ref_len: Option<f64>,
     pub ref_depth: Option<usize>,
+    #[serde(serialize_with = "io::serialize_opt_f64")]
     pub cmp_len: Option<f64>,
     pub cmp_depth: Option<usize>,
-    pub marker: Option<String>,
 }
 
 impl BranchRecord {
     fn from_trees(
         reftree: &Tree,
         cmptree: &Tree,
-        include_tips: bool,
+        opts: &CompOpts,
         id: Arc<String>,
     ) -> Result<Vec<Self>> {
-        let (reference, compared, common) =
-            reftree.compare_branch_lengths(cmptree, include_tips)?;
+        // Only the split based comparison can leave out internal branches
+        let pairs = if opts.tips_only_branches {
+            splits::compare_branches(reftree, cmptree, true, true)?
+        } else {
+            reftree.compare_branch_lengths(cmptree, opts.include_tips)?
+        };
+        Ok(Self::from_pairs(pairs, opts, id))
+    }
+
+    // Build branch records from branch pairs, keeping branches in the depth
+    // range. If `top_branch_diffs` is set, only this many common branches
+    // with the largest absolute length differences are kept.
+    fn from_pairs(
+        (mut reference, mut compared, mut common): BranchPairs,
+        opts: &CompOpts,
+        id: Arc<String>,
+    ) -> Vec<Self> {
+        if opts.min_depth.is_some() || opts.max_depth.is_some() {
+            let in_range = |depth: usize| {
+                !opts.min_depth.is_some_and(|min| depth < min)
+                    && !opts.max_depth.is_some_and(|max| depth > max)
+            };
+            reference.retain(|(d, _)| in_range(*d));
+            compared.retain(|(d, _)| in_range(*d));
+            common.retain(|((rd, _), (cd, _))| match opts.depth_on {
+                DepthSide::Ref => in_range(*rd),
+                DepthSide::Cmp => in_range(*cd),
+                DepthSide::Both => in_range(*rd) && in_range(*cd),
+                DepthSide::Either => in_range(*rd) || in_range(*cd),
+            });
+        }
+
+        if let Some(top) = opts.top_branch_diffs {
+            reference.clear();
+            compared.clear();
+            common.sort_by(|((_, rl1), (_, cl1)), ((_, rl2), (_, cl2))| {
+                (rl2 - cl2).abs().total_cmp(&(rl1 - cl1).abs())
+            });
+            common.truncate(top);
+        }
+
         let mut records = Vec::new();
 
         records.extend(reference.into_iter().map(|(d, l)| BranchRecord {
@@ -46,19 +101,161 @@ impl BranchRecord {
             ref_len: Some(rl),
             cmp_len: Some(cl),
             cmp_depth: Some(cd),
-            ..Default::default()
         }));
 
-        Ok(records)
+        records
+    }
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct BranchRegressionRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub n_branches: usize,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub slope: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub intercept: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub r_squared: f64,
+}
+
+impl BranchRegressionRecord {
+    // Least squares regression of the compared lengths of common branches on
+    // their reference lengths. Values are NaN without at least two branches
+    // of different reference lengths.
+    fn from_branches(branches: &[BranchRecord], id: Arc<String>) -> Self {
+        let pairs: Vec<_> = branches
+            .iter()
+            .filter_map(|b| Some((b.ref_len?, b.cmp_len?)))
+            .collect();
+        let n = pairs.len() as f64;
+        let (mean_x, mean_y) = (
+            pairs.iter().map(|(x, _)| x).sum::<f64>() / n,
+            pairs.iter().map(|(_, y)| y).sum::<f64>() / n,
+        );
+        let (mut sxx, mut syy, mut sxy) = (0., 0., 0.);
+        for (x, y) in pairs.iter() {
+            sxx += (x - mean_x) * (x - mean_x);
+            syy += (y - mean_y) * (y - mean_y);
+            sxy += (x - mean_x) * (y - mean_y);
+        }
+
+        let (slope, r_squared) = if pairs.len() < 2 || sxx == 0. {
+            (f64::NAN, f64::NAN)
+        } else {
+            (sxy / sxx, sxy * sxy / (sxx * syy))
+        };
+
+        Self {
+            id,
+            n_branches: pairs.len(),
+            slope,
+            intercept: mean_y - slope * mean_x,
+            r_squared,
+        }
+    }
+}
+
+// Number of distance records in each chunk passed to a distance sink
+const DIST_CHUNK_SIZE: usize = 4096;
+
+// Weighted sums over the pairs of tips of a tree, accumulated as distances
+// are compared so that they can be summarized even when streamed. Means and
+// sums of squared deviations are updated at each pair (West's weighted
+// version of Welford's algorithm), which stays accurate when the distances
+// are large compared to their spread.
+#[derive(Default)]
+struct WeightedSums {
+    n_pairs: usize,
+    n_zero_dist: usize,
+    w: f64,
+    mean_x: f64,
+    mean_y: f64,
+    m2_x: f64,
+    m2_y: f64,
+    co_xy: f64,
+    sq_diff: f64,
+}
+
+impl WeightedSums {
+    fn add(&mut self, weighting: DistanceWeight, ref_dist: f64, cmp_dist: f64) {
+        if !ref_dist.is_finite() || !cmp_dist.is_finite() {
+            return;
+        }
+        let Some(w) = weighting.weight(ref_dist) else {
+            self.n_zero_dist += 1;
+            return;
+        };
+        let (x, y) = (ref_dist, cmp_dist);
+        self.n_pairs += 1;
+        self.w += w;
+        let (dx, dy) = (x - self.mean_x, y - self.mean_y);
+        self.mean_x += w / self.w * dx;
+        self.mean_y += w / self.w * dy;
+        self.m2_x += w * dx * (x - self.mean_x);
+        self.m2_y += w * dy * (y - self.mean_y);
+        self.co_xy += w * dx * (y - self.mean_y);
+        self.sq_diff += w * (y - x) * (y - x);
     }
 }
 
+/// Agreement of the tip distances of a tree with the ones of its reference,
+/// each pair of tips being weighted according to `--distance-weight`
+#[derive(Debug, Default, Serialize)]
+pub struct DistanceSummaryRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub n_pairs: usize,
+    pub n_zero_dist: usize,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub correlation: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub rmsd: f64,
+}
+
+impl DistanceSummaryRecord {
+    // Weighted Pearson correlation and root mean squared difference of the
+    // distances. Values are NaN without pairs, and the correlation is NaN if
+    // either distance is constant.
+    fn from_sums(sums: &WeightedSums, id: Arc<String>) -> Self {
+        // The weights cancel out of the correlation
+        let correlation = if sums.m2_x > 0. && sums.m2_y > 0. {
+            sums.co_xy / (sums.m2_x * sums.m2_y).sqrt()
+        } else {
+            f64::NAN
+        };
+
+        Self {
+            id,
+            n_pairs: sums.n_pairs,
+            n_zero_dist: sums.n_zero_dist,
+            correlation,
+            rmsd: (sums.sq_diff / sums.w).sqrt(),
+        }
+    }
+}
+
+/// Receives chunks of distance records as they are computed
+pub type DistSink<'a> = dyn FnMut(Vec<DistanceRecord>) + 'a;
+
 #[derive(Default, Debug, Serialize)]
 pub struct DistanceRecord {
+    #[serde(serialize_with = "io::serialize_id")]
     pub id: Arc<String>,
+    #[serde(serialize_with = "io::serialize_f64")]
     pub ref_dist: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
     pub cmp_dist: f64,
-    pub marker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_1: Option<Arc<str>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_2: Option<Arc<str>>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "io::serialize_opt_f64"
+    )]
+    pub dist_transform: Option<f64>,
 }
 
 impl DistanceRecord {
@@ -66,37 +263,644 @@ impl DistanceRecord {
         size * (size - 1) / 2
     }
 
-    fn from_trees(reftree: &Tree, cmptree: &Tree, id: Arc<String>) -> Result<Vec<Self>> {
-        let mut dists = Vec::with_capacity(Self::get_cap(reftree.n_leaves()));
-        let ref_dists = reftree.distance_matrix()?;
-        let cmp_dists = cmptree.distance_matrix()?;
+    // Compare the tip distances of two trees, reusing the cached distances of
+    // the reference if a cache is given
+    fn from_trees(
+        reftree: &Tree,
+        cmptree: &Tree,
+        opts: &CompOpts,
+        id: Arc<String>,
+        sink: Option<&mut DistSink>,
+        ref_cache: Option<(&str, &RefDistCache)>,
+    ) -> Result<(Vec<Self>, Option<DistanceSummaryRecord>)> {
+        let compute_ref = || TipDistances::from_tree(reftree, opts.topological_distances);
+        let ref_dists = match ref_cache {
+            Some((key, cache)) => cache.get(key, compute_ref)?,
+            None => Arc::new(compute_ref()?),
+        };
+        let cmp_dists = TipDistances::from_tree(cmptree, opts.topological_distances)?;
+
+        Self::from_distances(&ref_dists, &cmp_dists, opts, id, sink)
+    }
 
-        for (tip_1, tip_2) in ref_dists.taxa.iter().tuple_combinations() {
+    // Compare the distances between each pair of tips of either tree. Tips
+    // missing from the reference come after the reference tips, so that
+    // their pairs are handled by the NaN policy instead of being ignored.
+    // The compared pairs are summarized if a weighting is set. Distances are
+    // divided by the diameter of their tree, computed once, before being
+    // filtered or transformed.
+    fn from_distances(
+        ref_dists: &TipDistances,
+        cmp_dists: &TipDistances,
+        opts: &CompOpts,
+        id: Arc<String>,
+        mut sink: Option<&mut DistSink>,
+    ) -> Result<(Vec<Self>, Option<DistanceSummaryRecord>)> {
+        let cmp_only: Vec<_> = cmp_dists
+            .taxa
+            .iter()
+            .filter(|t| ref_dists.get(t, t).is_none())
+            .collect();
+
+        let capacity = match sink {
+            Some(_) => DIST_CHUNK_SIZE,
+            None => Self::get_cap(ref_dists.taxa.len() + cmp_only.len()),
+        };
+        let mut dists = Vec::with_capacity(capacity);
+        let mut sums = WeightedSums::default();
+
+        // A tree with a diameter of 0 only has distances of 0, which are
+        // left as they are instead of giving NaN
+        let (ref_scale, cmp_scale) = if opts.normalize_by_diameter {
+            let scale = |diameter: f64| if diameter > 0. { diameter } else { 1. };
+            (scale(ref_dists.diameter()), scale(cmp_dists.diameter()))
+        } else {
+            (1., 1.)
+        };
+
+        // Check for missing tips before any records are streamed
+        if opts.nan_policy == NanPolicy::Error {
+            if let Some(tip) = ref_dists
+                .taxa
+                .iter()
+                .find(|t| cmp_dists.get(t, t).is_none())
+            {
+                bail!("Tip {tip} is missing from the comparison tree of {id}")
+            }
+            if let Some(tip) = cmp_only.first() {
+                bail!("Tip {tip} is missing from the reference tree of {id}")
+            }
+        }
+
+        let taxa = ref_dists.taxa.iter().chain(cmp_only.iter().copied());
+        for (tip_1, tip_2) in taxa.tuple_combinations() {
+            // Only keep pairs of tips from different groups
+            let groups = match opts.distance_groups.as_ref() {
+                Some(groups) => match (groups.get(tip_1), groups.get(tip_2)) {
+                    (Some(group_1), Some(group_2)) if group_1 != group_2 => {
+                        (Some(group_1.clone()), Some(group_2.clone()))
+                    }
+                    _ => continue,
+                },
+                None => (None, None),
+            };
+
+            let ref_dist = ref_dists.get(tip_1, tip_2);
+            let cmp_dist = cmp_dists.get(tip_1, tip_2);
 
-            let &ref_dist = ref_dists.get(tip_1, tip_2).unwrap_or(&f64::NAN);
-            let &cmp_dist = cmp_dists.get(tip_1, tip_2).unwrap_or(&f64::NAN);
+            let (ref_dist, cmp_dist) = match (opts.nan_policy, ref_dist, cmp_dist) {
+                (_, Some(r), Some(c)) => (r, c),
+                (NanPolicy::Emit, r, c) => (r.unwrap_or(f64::NAN), c.unwrap_or(f64::NAN)),
+                (NanPolicy::Skip, _, _) => continue,
+                (NanPolicy::Error, r, _) => {
+                    let side = if r.is_none() {
+                        "reference"
+                    } else {
+                        "comparison"
+                    };
+                    bail!("Pair ({tip_1}, {tip_2}) is missing from the {side} tree of {id}")
+                }
+            };
+            let (ref_dist, cmp_dist) = (ref_dist / ref_scale, cmp_dist / cmp_scale);
+
+            if let Some(max) = opts.max_distance {
+                let (ref_far, cmp_far) = (ref_dist > max, cmp_dist > max);
+                let far = match opts.max_distance_on {
+                    DistanceSide::Ref => ref_far,
+                    DistanceSide::Cmp => cmp_far,
+                    DistanceSide::Both => ref_far && cmp_far,
+                    DistanceSide::Either => ref_far || cmp_far,
+                };
+                if far {
+                    continue;
+                }
+            }
+
+            if let Some(weighting) = opts.distance_weight {
+                sums.add(weighting, ref_dist, cmp_dist);
+            }
 
             dists.push(Self {
                 id: id.clone(),
                 ref_dist,
                 cmp_dist,
-                ..Default::default()
+                group_1: groups.0,
+                group_2: groups.1,
+                dist_transform: opts.distance_transform.map(|t| t.apply(ref_dist, cmp_dist)),
             });
+
+            if let Some(sink) = sink.as_mut() {
+                if dists.len() >= DIST_CHUNK_SIZE {
+                    sink(std::mem::replace(
+                        &mut dists,
+                        Vec::with_capacity(DIST_CHUNK_SIZE),
+                    ));
+                }
+            }
         }
 
-        Ok(dists)
+        let summary = opts
+            .distance_weight
+            .map(|_| DistanceSummaryRecord::from_sums(&sums, id.clone()));
+
+        if let Some(sink) = sink {
+            if !dists.is_empty() {
+                sink(dists);
+            }
+            return Ok((vec![], summary));
+        }
+
+        Ok((dists, summary))
+    }
+}
+
+// Values of a field across records, as a JSON array. Missing and NaN values
+// are written as null.
+fn json_column<T, V: Serialize>(records: &[T], field: impl Fn(&T) -> V) -> Result<String> {
+    Ok(serde_json::to_string(
+        &records.iter().map(field).collect_vec(),
+    )?)
+}
+
+/// Branches of a tree in a single row, each column holding a JSON array
+/// with one value per branch
+#[derive(Debug, Default, Serialize)]
+pub struct CompactBranchRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub ref_len: String,
+    pub ref_depth: String,
+    pub cmp_len: String,
+    pub cmp_depth: String,
+}
+
+impl CompactBranchRecord {
+    pub fn new(id: Arc<String>, branches: &[BranchRecord]) -> Result<Self> {
+        Ok(Self {
+            id,
+            ref_len: json_column(branches, |b| b.ref_len)?,
+            ref_depth: json_column(branches, |b| b.ref_depth)?,
+            cmp_len: json_column(branches, |b| b.cmp_len)?,
+            cmp_depth: json_column(branches, |b| b.cmp_depth)?,
+        })
+    }
+}
+
+/// Distances of a tree in a single row, each column holding a JSON array
+/// with one value per pair of tips
+#[derive(Debug, Default, Serialize)]
+pub struct CompactDistanceRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub ref_dist: String,
+    pub cmp_dist: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dist_transform: Option<String>,
+}
+
+impl CompactDistanceRecord {
+    pub fn new(id: Arc<String>, dists: &[DistanceRecord], opts: &CompOpts) -> Result<Self> {
+        Ok(Self {
+            id,
+            ref_dist: json_column(dists, |d| d.ref_dist)?,
+            cmp_dist: json_column(dists, |d| d.cmp_dist)?,
+            dist_transform: opts
+                .distance_transform
+                .map(|_| json_column(dists, |d| d.dist_transform))
+                .transpose()?,
+        })
     }
 }
 
 #[derive(Debug, Default, Serialize)]
 pub struct TopologyRecord {
+    #[serde(serialize_with = "io::serialize_id")]
     pub id: Arc<String>,
+    #[serde(serialize_with = "io::serialize_f64")]
     pub rf: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
     pub norm_rf: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
     pub weighted_rf: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
     pub kf_score: f64,
     pub n_tips: usize,
-    pub marker: Option<String>,
+    // Optional metrics only have a column when they are requested, and are
+    // appended in the order they were added to keep earlier columns in place
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub align_dist: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub support_weighted_rf: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_ref_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub clade_rf: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub precision: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub recall: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub f1: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ref_height: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cmp_height: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub height_diff: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub norm_kf_score: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rf_p_value: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rf_info: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mast_size: Option<Option<usize>>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub norm_mast_size: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub split_weighted_rf: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_refinement: Option<Option<bool>>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub refinement_rf: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub shared_weighted_rf: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ref_root_degree: Option<Option<usize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmp_root_degree: Option<Option<usize>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approx_spr: Option<Option<usize>>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub ref_diameter: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub cmp_diameter: Option<f64>,
+    #[serde(
+        serialize_with = "io::serialize_opt_f64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub diameter_diff: Option<f64>,
+}
+
+impl TopologyRecord {
+    /// Row of a tree that could not be compared, with NaN metrics and the
+    /// error message. Optional metrics requested in `opts` are NaN, or empty
+    /// if they are not floats, so that the row has the same columns as the
+    /// others.
+    pub fn failed(id: Arc<String>, n_tips: usize, error: &anyhow::Error, opts: &CompOpts) -> Self {
+        let nan = |requested: bool| requested.then_some(f64::NAN);
+        let empty = |requested: bool| requested.then_some(None);
+        Self {
+            id,
+            rf: f64::NAN,
+            norm_rf: f64::NAN,
+            weighted_rf: f64::NAN,
+            kf_score: f64::NAN,
+            n_tips,
+            align_dist: nan(opts.align),
+            support_weighted_rf: nan(opts.support_weighted_rf.is_some()),
+            clade_rf: nan(opts.clade_rf),
+            precision: nan(opts.prec_recall),
+            recall: nan(opts.prec_recall),
+            f1: nan(opts.prec_recall),
+            ref_height: nan(opts.height_diff),
+            cmp_height: nan(opts.height_diff),
+            height_diff: nan(opts.height_diff),
+            norm_kf_score: nan(opts.normalize_kf),
+            rf_p_value: nan(opts.rf_null.is_some()),
+            rf_info: nan(opts.rf_info),
+            mast_size: empty(opts.mast),
+            norm_mast_size: nan(opts.mast),
+            error: Some(format!("{error:#}")),
+            split_weighted_rf: nan(opts.split_weights.is_some()),
+            is_refinement: empty(opts.allow_refinement),
+            refinement_rf: nan(opts.allow_refinement),
+            shared_weighted_rf: nan(opts.shared_weighted_rf),
+            ref_root_degree: empty(opts.normalize_root.is_some()),
+            cmp_root_degree: empty(opts.normalize_root.is_some()),
+            approx_spr: empty(opts.spr),
+            ref_diameter: nan(opts.diameter_diff),
+            cmp_diameter: nan(opts.diameter_diff),
+            diameter_diff: nan(opts.diameter_diff),
+            ..Default::default()
+        }
+    }
+
+    // Compare the topologies of two trees, reusing the comparison if it was
+    // already computed
+    fn from_trees(
+        reftree: &Tree,
+        cmptree: &Tree,
+        comparison: Option<Comparison>,
+        opts: &CompOpts,
+        id: Arc<String>,
+    ) -> Result<Self> {
+        let comparison = match comparison {
+            Some(comparison) => comparison,
+            None => reftree.compare_topologies(cmptree)?,
+        };
+        let mut topo = TopologyRecord::from(comparison);
+        topo.n_tips = reftree.n_leaves();
+        topo.id = id;
+
+        if let Some(n_replicates) = opts.rf_null {
+            topo.rf_p_value = Some(rf_p_value(
+                reftree,
+                cmptree,
+                topo.rf,
+                n_replicates,
+                opts.seed,
+                &topo.id,
+            )?);
+        }
+
+        if opts.normalize_kf {
+            topo.norm_kf_score = Some(topo.kf_score / dist::tree_length(reftree)?);
+        }
+
+        if opts.align
+            || opts.prec_recall
+            || opts.rf_info
+            || opts.split_weights.is_some()
+            || opts.allow_refinement
+        {
+            let (n, ref_splits, cmp_splits) = splits::get_shared_splits(reftree, cmptree)?;
+
+            if opts.allow_refinement {
+                let refinement_rf = splits::refinement_rf(&ref_splits, &cmp_splits);
+                topo.is_refinement = Some(Some(refinement_rf == 0));
+                topo.refinement_rf = Some(refinement_rf as f64);
+            }
+
+            if let Some(weights) = opts.split_weights.as_ref() {
+                let taxa = splits::get_taxa(reftree)?;
+                topo.split_weighted_rf = Some(splits::split_weighted_rf(
+                    &ref_splits,
+                    &cmp_splits,
+                    &taxa,
+                    weights,
+                ));
+            }
+
+            if opts.prec_recall {
+                let (precision, recall, f1) = splits::precision_recall(&ref_splits, &cmp_splits);
+                topo.precision = Some(precision);
+                topo.recall = Some(recall);
+                topo.f1 = Some(f1);
+            }
+
+            let ref_splits: Vec<_> = ref_splits.into_iter().collect();
+            let cmp_splits: Vec<_> = cmp_splits.into_iter().collect();
+            if opts.align {
+                topo.align_dist = Some(splits::align_distance(&ref_splits, &cmp_splits, n));
+            }
+            if opts.rf_info {
+                topo.rf_info = Some(splits::clustering_info_distance(
+                    &ref_splits,
+                    &cmp_splits,
+                    n,
+                ));
+            }
+        }
+
+        if opts.shared_weighted_rf {
+            let (_, _, common) = splits::compare_branches(
+                reftree,
+                cmptree,
+                opts.include_tips,
+                opts.tips_only_branches,
+            )?;
+            topo.shared_weighted_rf = Some(
+                common
+                    .iter()
+                    .map(|((_, ref_len), (_, cmp_len))| (ref_len - cmp_len).powi(2))
+                    .sum(),
+            );
+        }
+
+        if let Some(missing) = opts.support_weighted_rf {
+            let missing_weight = match missing {
+                MissingSupport::One => Some(1.),
+                MissingSupport::Error => None,
+            };
+            topo.support_weighted_rf = Some(splits::support_weighted_rf(
+                reftree,
+                cmptree,
+                missing_weight,
+            )?);
+        }
+
+        if opts.clade_rf {
+            topo.clade_rf = Some(splits::clade_rf(reftree, cmptree)?);
+        }
+
+        if opts.mast {
+            let mast = splits::mast_size(reftree, cmptree)?;
+            topo.mast_size = Some(Some(mast));
+            topo.norm_mast_size = Some(mast as f64 / topo.n_tips as f64);
+        }
+
+        if opts.spr {
+            topo.approx_spr = Some(Some(splits::approx_spr(reftree, cmptree)?));
+        }
+
+        if opts.height_diff {
+            let ref_height = dist::tree_height(reftree)?;
+            let cmp_height = dist::tree_height(cmptree)?;
+            topo.ref_height = Some(ref_height);
+            topo.cmp_height = Some(cmp_height);
+            topo.height_diff = Some(cmp_height - ref_height);
+        }
+
+        if opts.diameter_diff {
+            let ref_diameter = dist::tree_diameter(reftree)?;
+            let cmp_diameter = dist::tree_diameter(cmptree)?;
+            topo.ref_diameter = Some(ref_diameter);
+            topo.cmp_diameter = Some(cmp_diameter);
+            topo.diameter_diff = Some(cmp_diameter - ref_diameter);
+        }
+
+        Ok(topo)
+    }
+}
+
+// Empirical p-value of an RF distance: the fraction of references with
+// shuffled tip labels that are at least as close to the compared tree,
+// counting the observed reference. Replicates are compared in parallel.
+fn rf_p_value(
+    reftree: &Tree,
+    cmptree: &Tree,
+    rf: f64,
+    n_replicates: usize,
+    seed: u64,
+    id: &str,
+) -> Result<f64> {
+    let n_closer = (0..n_replicates)
+        .into_par_iter()
+        .map(|i| {
+            let seed = transform::hash_u64(&format!("{seed}:{id}:{i}"));
+            let shuffled = transform::shuffle_tips(reftree, seed)?;
+            Ok(usize::from(shuffled.compare_topologies(cmptree)?.rf <= rf))
+        })
+        .sum::<Result<usize>>()?;
+
+    Ok((n_closer + 1) as f64 / (n_replicates + 1) as f64)
+}
+
+impl TopologyRecord {
+    /// Numeric metrics of the record, by column name. Optional metrics that
+    /// were not computed are `None`.
+    pub fn metrics(&self) -> [(&'static str, Option<f64>); 25] {
+        [
+            ("rf", Some(self.rf)),
+            ("norm_rf", Some(self.norm_rf)),
+            ("weighted_rf", Some(self.weighted_rf)),
+            ("kf_score", Some(self.kf_score)),
+            ("align_dist", self.align_dist),
+            ("support_weighted_rf", self.support_weighted_rf),
+            ("clade_rf", self.clade_rf),
+            ("precision", self.precision),
+            ("recall", self.recall),
+            ("f1", self.f1),
+            ("ref_height", self.ref_height),
+            ("cmp_height", self.cmp_height),
+            ("height_diff", self.height_diff),
+            ("norm_kf_score", self.norm_kf_score),
+            ("rf_p_value", self.rf_p_value),
+            ("rf_info", self.rf_info),
+            ("mast_size", self.mast_size.flatten().map(|m| m as f64)),
+            ("norm_mast_size", self.norm_mast_size),
+            ("split_weighted_rf", self.split_weighted_rf),
+            ("refinement_rf", self.refinement_rf),
+            ("shared_weighted_rf", self.shared_weighted_rf),
+            ("approx_spr", self.approx_spr.flatten().map(|s| s as f64)),
+            ("ref_diameter", self.ref_diameter),
+            ("cmp_diameter", self.cmp_diameter),
+            ("diameter_diff", self.diameter_diff),
+        ]
+    }
+}
+
+/// Predicate on a topology metric, e.g. `norm_rf>0.3`
+#[derive(Debug, Clone)]
+pub struct MetricFilter {
+    metric: &'static str,
+    op: &'static str,
+    value: f64,
+}
+
+impl MetricFilter {
+    // Comparison operators, two character ones first so that they are not
+    // mistaken for one character ones
+    const OPERATORS: [&'static str; 6] = [">=", "<=", "==", "!=", ">", "<"];
+
+    /// Check if the metric of a topology record satisfies the predicate.
+    /// Records where the metric was not computed never do.
+    pub fn matches(&self, topo: &TopologyRecord) -> bool {
+        let Some(Some(metric)) = topo
+            .metrics()
+            .into_iter()
+            .find_map(|(name, value)| (name == self.metric).then_some(value))
+        else {
+            return false;
+        };
+        match self.op {
+            ">=" => metric >= self.value,
+            "<=" => metric <= self.value,
+            "==" => metric == self.value,
+            "!=" => metric != self.value,
+            ">" => metric > self.value,
+            _ => metric < self.value,
+        }
+    }
+}
+
+impl std::str::FromStr for MetricFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((start, op)) = Self::OPERATORS
+            .iter()
+            .find_map(|op| s.find(op).map(|start| (start, *op)))
+        else {
+            return Err(format!(
+                "missing comparison operator, expected one of: {}",
+                Self::OPERATORS.join(" ")
+            ));
+        };
+        let (name, value) = (s[..start].trim(), s[start + op.len()..].trim());
+
+        let names = TopologyRecord::default().metrics().map(|(name, _)| name);
+        let Some(&metric) = names.iter().find(|&&n| n == name) else {
+            return Err(format!(
+                "unknown metric `{name}`, expected one of: {}",
+                names.join(", ")
+            ));
+        };
+        let value = value
+            .parse()
+            .map_err(|e| format!("invalid value `{value}`: {e}"))?;
+
+        Ok(Self { metric, op, value })
+    }
 }
 
 impl From<Comparison> for TopologyRecord {
@@ -111,52 +915,1118 @@ impl From<Comparison> for TopologyRecord {
     }
 }
 
-#[derive(Default, Debug)]
+/// Topology metrics of a tree, read from the topology output of a previous run
+#[derive(Debug, Deserialize)]
+pub struct BaselineRecord {
+    pub id: String,
+    pub rf: f64,
+    pub norm_rf: f64,
+    pub kf_score: f64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DeltaRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub rf_delta: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub norm_rf_delta: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub kf_score_delta: f64,
+}
+
+impl DeltaRecord {
+    /// Difference between the topology metrics of a tree and its baseline
+    pub fn new(topo: &TopologyRecord, baseline: &BaselineRecord) -> Self {
+        Self {
+            id: topo.id.clone(),
+            rf_delta: topo.rf - baseline.rf,
+            norm_rf_delta: topo.norm_rf - baseline.norm_rf,
+            kf_score_delta: topo.kf_score - baseline.kf_score,
+        }
+    }
+}
+
+/// A single topology metric of a tree, for the long format metric table
+#[derive(Debug, Default, Serialize)]
+pub struct MetricRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub metric: &'static str,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub value: f64,
+}
+
+impl MetricRecord {
+    /// Melt a topology record into one record per metric. Optional metrics
+    /// are only included when they were computed.
+    pub fn from_topology(topo: &TopologyRecord) -> Vec<Self> {
+        topo.metrics()
+            .into_iter()
+            .filter_map(|(metric, value)| {
+                Some(Self {
+                    id: topo.id.clone(),
+                    metric,
+                    value: value?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A named set of tips
+#[derive(Debug, Clone)]
+pub struct Clade {
+    pub name: String,
+    pub tips: Vec<String>,
+}
+
+/// Field of the tip labels, split on a delimiter, defining groups of tips
+/// (e.g. the species in `species|location|date`)
+#[derive(Debug, Clone)]
+pub struct LabelField {
+    pub delimiter: char,
+    /// Index of the field, starting at 1
+    pub field: usize,
+}
+
+impl LabelField {
+    /// Extract the field of a tip label, if it has enough fields
+    pub fn extract<'a>(&self, label: &'a str) -> Option<&'a str> {
+        label.split(self.delimiter).nth(self.field.checked_sub(1)?)
+    }
+
+    /// Group the tips of a tree by their field, each group being a clade
+    /// named after the field. Tips without the field are ignored.
+    pub fn clades(&self, tree: &Tree) -> Result<Vec<Clade>> {
+        Ok(splits::get_taxa(tree)?
+            .into_iter()
+            .filter_map(|tip| Some((self.extract(&tip)?.to_string(), tip)))
+            .into_group_map()
+            .into_iter()
+            .sorted()
+            .map(|(name, tips)| Clade { name, tips })
+            .collect())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CladeRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub clade_name: String,
+    pub monophyletic_in_ref: Option<bool>,
+    pub monophyletic_in_cmp: Option<bool>,
+}
+
+impl CladeRecord {
+    fn from_trees(
+        reftree: &Tree,
+        cmptree: &Tree,
+        clades: &[Clade],
+        id: Arc<String>,
+    ) -> Result<Vec<Self>> {
+        let tip_sets: Vec<_> = clades.iter().map(|c| c.tips.as_slice()).collect();
+        let in_ref = splits::monophyly(reftree, &tip_sets)?;
+        let in_cmp = splits::monophyly(cmptree, &tip_sets)?;
+
+        Ok(clades
+            .iter()
+            .zip(in_ref.into_iter().zip(in_cmp))
+            .map(|(clade, (in_ref, in_cmp))| Self {
+                id: id.clone(),
+                clade_name: clade.name.clone(),
+                monophyletic_in_ref: in_ref,
+                monophyletic_in_cmp: in_cmp,
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct InternalNodeRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub label: String,
+    pub in_ref: bool,
+    pub in_cmp: bool,
+    pub duplicated: bool,
+    pub same_clade: Option<bool>,
+}
+
+impl InternalNodeRecord {
+    fn from_trees(reftree: &Tree, cmptree: &Tree, id: Arc<String>) -> Result<Vec<Self>> {
+        let taxa = splits::get_union_taxa(reftree, cmptree)?;
+        let ref_labels = splits::get_labeled_clades(reftree, &taxa)?;
+        let cmp_labels = splits::get_labeled_clades(cmptree, &taxa)?;
+
+        Ok(ref_labels
+            .keys()
+            .chain(cmp_labels.keys())
+            .unique()
+            .sorted()
+            .map(|label| {
+                let ref_clade = ref_labels.get(label);
+                let cmp_clade = cmp_labels.get(label);
+                let duplicated = matches!(ref_clade, Some(None)) || matches!(cmp_clade, Some(None));
+                let same_clade = match (ref_clade, cmp_clade) {
+                    (Some(Some(r)), Some(Some(c))) => Some(r == c),
+                    _ => None,
+                };
+
+                Self {
+                    id: id.clone(),
+                    label: label.clone(),
+                    in_ref: ref_clade.is_some(),
+                    in_cmp: cmp_clade.is_some(),
+                    duplicated,
+                    same_clade,
+                }
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CanonicalRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub identical: bool,
+    pub ref_hash: String,
+    pub cmp_hash: String,
+}
+
+impl CanonicalRecord {
+    fn from_trees(
+        reftree: &Tree,
+        cmptree: &Tree,
+        internal_labels: bool,
+        id: Arc<String>,
+    ) -> Result<Self> {
+        let ref_newick = transform::canonical_newick(reftree, internal_labels)?;
+        let cmp_newick = transform::canonical_newick(cmptree, internal_labels)?;
+
+        Ok(Self {
+            id,
+            identical: ref_newick == cmp_newick,
+            ref_hash: transform::hash_string(&ref_newick),
+            cmp_hash: transform::hash_string(&cmp_newick),
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CherryRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub ref_cherries: usize,
+    pub cmp_cherries: usize,
+    pub cherry_diff: i64,
+    pub shared_cherries: usize,
+}
+
+impl CherryRecord {
+    // Count the cherries of both trees, and the ones they share
+    fn from_trees(reftree: &Tree, cmptree: &Tree, id: Arc<String>) -> Result<Self> {
+        let ref_cherries = splits::get_cherries(reftree)?;
+        let cmp_cherries = splits::get_cherries(cmptree)?;
+
+        Ok(Self {
+            id,
+            ref_cherries: ref_cherries.len(),
+            cmp_cherries: cmp_cherries.len(),
+            cherry_diff: cmp_cherries.len() as i64 - ref_cherries.len() as i64,
+            shared_cherries: ref_cherries.intersection(&cmp_cherries).count(),
+        })
+    }
+}
+
+/// Compatibility of a non-trivial split of the reference with the splits of
+/// the compared tree
+#[derive(Debug, Default, Serialize)]
+pub struct CompatibilityRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub clade_hash: String,
+    pub clade_size: usize,
+    pub clade: String,
+    pub in_cmp: bool,
+    pub compatible: bool,
+    pub n_conflicts: usize,
+}
+
+impl CompatibilityRecord {
+    // One row per split of the reference, given by its side without the
+    // first tip and sorted by hash. A split is compatible if it conflicts
+    // with no split of the compared tree, i.e. it is either found in the
+    // compared tree or only unresolved there.
+    fn from_trees(reftree: &Tree, cmptree: &Tree, id: Arc<String>) -> Result<Vec<Self>> {
+        let (_, ref_splits, cmp_splits) = splits::get_shared_splits(reftree, cmptree)?;
+        let taxa = splits::get_taxa(reftree)?;
+
+        Ok(ref_splits
+            .iter()
+            .map(|split| {
+                let clade = split.ones().map(|i| taxa[i].as_str()).join(";");
+                let n_conflicts = cmp_splits
+                    .iter()
+                    .filter(|other| !splits::are_compatible(split, other))
+                    .count();
+                Self {
+                    id: id.clone(),
+                    clade_hash: transform::hash_string(&clade),
+                    clade_size: split.count_ones(..),
+                    clade,
+                    in_cmp: cmp_splits.contains(split),
+                    compatible: n_conflicts == 0,
+                    n_conflicts,
+                }
+            })
+            .sorted_by(|a, b| a.clade_hash.cmp(&b.clade_hash))
+            .collect())
+    }
+}
+
+/// Outcome of the comparison of a tree for each of the topology, branch and
+/// distance comparisons. Comparisons that were not enabled are empty.
+#[derive(Debug, Default, Serialize)]
+pub struct StatusRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub topo_ok: Option<bool>,
+    pub brlen_ok: Option<bool>,
+    pub dist_ok: Option<bool>,
+    pub error: String,
+}
+
+impl StatusRecord {
+    /// Status of a compared tree. A failed comparison fails all the enabled
+    /// ones, except for distances which can fail on their own.
+    pub fn new(record: &ComparisonRecord, opts: &CompOpts) -> Self {
+        let failed = record.failure.is_some();
+        let ok = |enabled: bool| enabled.then_some(!failed);
+        let error = record
+            .failure
+            .as_ref()
+            .or(record.distance_error.as_ref())
+            .map(|e| format!("{e:#}"))
+            .unwrap_or_default();
+
+        Self {
+            id: record.id.clone(),
+            topo_ok: ok(opts.topology),
+            brlen_ok: ok(opts.lengths),
+            dist_ok: ok(opts.distances).map(|ok| ok && record.distance_error.is_none()),
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RootRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub same_topology: bool,
+    pub same_root: Option<bool>,
+    #[serde(serialize_with = "io::serialize_opt_f64")]
+    pub root_distance: Option<f64>,
+}
+
+impl RootRecord {
+    // Compare the root positions of two trees, if they have the same
+    // unrooted topology
+    fn from_trees(reftree: &Tree, cmptree: &Tree, id: Arc<String>) -> Result<Self> {
+        let (_, ref_splits, cmp_splits) = splits::get_shared_splits(reftree, cmptree)?;
+        if ref_splits != cmp_splits {
+            return Ok(Self {
+                id,
+                ..Default::default()
+            });
+        }
+
+        let taxa = splits::get_taxa(reftree)?;
+        let root_distance = splits::root_distance(reftree, cmptree, &taxa)?;
+
+        Ok(Self {
+            id,
+            same_topology: true,
+            same_root: Some(root_distance == 0.),
+            root_distance: Some(root_distance),
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct CoverageRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
+    pub n_tips: usize,
+    pub n_missing: usize,
+    pub union_size: usize,
+    pub intersection_size: usize,
+}
+
+/// Compute the tip coverage of each tree relative to the union of the tips
+/// of all the trees
+pub fn tip_coverage<'a>(trees: impl Iterator<Item = (&'a str, &'a Tree)>) -> Vec<CoverageRecord> {
+    let tips: Vec<(&str, HashSet<String>)> = trees
+        .map(|(id, tree)| (id, tree.get_leaf_names().into_iter().flatten().collect()))
+        .collect();
+
+    let union: HashSet<&String> = tips.iter().flat_map(|(_, t)| t.iter()).collect();
+    let intersection_size = union
+        .iter()
+        .filter(|tip| tips.iter().all(|(_, t)| t.contains(**tip)))
+        .count();
+
+    tips.iter()
+        .map(|(id, t)| CoverageRecord {
+            id: Arc::new(id.to_string()),
+            n_tips: t.len(),
+            n_missing: union.len() - t.len(),
+            union_size: union.len(),
+            intersection_size,
+        })
+        .collect()
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RogueRecord {
+    pub rank: usize,
+    pub taxon: String,
+    pub n_trees: usize,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub mean_rf_drop: f64,
+    pub marker: Option<String>,
+}
+
+/// Rank tips by how much pruning them decreases the RF distance between
+/// the trees and their reference, on average over the pairs of trees where
+/// they appear. Tips most often in disagreeing positions (rogue taxa) come
+/// first. Pairs that cannot be compared are reported as errors.
+pub fn rogue_taxa<'a>(
+    pairs: impl ParallelIterator<Item = (&'a str, &'a Tree, &'a Tree)>,
+) -> (Vec<RogueRecord>, Vec<anyhow::Error>) {
+    let drops: Vec<_> = pairs
+        .map(|(id, reftree, cmptree)| {
+            splits::rf_drop_by_tip(reftree, cmptree)
+                .context(format!("Could not find rogue taxa of tree: {id}"))
+        })
+        .collect();
+
+    let mut errors = vec![];
+    let mut totals: HashMap<String, (usize, f64)> = HashMap::new();
+    for drop in drops {
+        match drop {
+            Ok(drop) => {
+                for (taxon, rf_drop) in drop {
+                    let total = totals.entry(taxon).or_default();
+                    total.0 += 1;
+                    total.1 += rf_drop;
+                }
+            }
+            Err(e) => errors.push(e),
+        }
+    }
+
+    let records = totals
+        .into_iter()
+        .map(|(taxon, (n_trees, sum))| (taxon, n_trees, sum / n_trees as f64))
+        .sorted_by(|(t1, _, d1), (t2, _, d2)| d2.total_cmp(d1).then_with(|| t1.cmp(t2)))
+        .enumerate()
+        .map(|(i, (taxon, n_trees, mean_rf_drop))| RogueRecord {
+            rank: i + 1,
+            taxon,
+            n_trees,
+            mean_rf_drop,
+            ..Default::default()
+        })
+        .collect();
+
+    (records, errors)
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ConsistencyRecord {
+    pub group: String,
+    pub n_trees: usize,
+    pub n_pairs: usize,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub mean_rf: f64,
+    #[serde(serialize_with = "io::serialize_f64")]
+    pub median_rf: f64,
+    pub marker: Option<String>,
+}
+
+impl ConsistencyRecord {
+    /// Summarize the RF distances between all pairs of trees of a group
+    pub fn from_trees(group: &str, trees: &[Tree]) -> Result<Self> {
+        let mut rfs = trees
+            .iter()
+            .tuple_combinations()
+            .map(|(a, b)| Ok(a.compare_topologies(b)?.rf))
+            .collect::<Result<Vec<f64>>>()?;
+        rfs.sort_by(f64::total_cmp);
+
+        let n = rfs.len();
+        let (mean_rf, median_rf) = match n {
+            0 => (f64::NAN, f64::NAN),
+            _ if n % 2 == 0 => (
+                rfs.iter().sum::<f64>() / n as f64,
+                (rfs[n / 2 - 1] + rfs[n / 2]) / 2.,
+            ),
+            _ => (rfs.iter().sum::<f64>() / n as f64, rfs[n / 2]),
+        };
+
+        Ok(Self {
+            group: group.into(),
+            n_trees: trees.len(),
+            n_pairs: n,
+            mean_rf,
+            median_rf,
+            ..Default::default()
+        })
+    }
+}
+
+/// What to do with tip pairs missing from one of the distance matrices
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NanPolicy {
+    /// Write the missing distance as NaN
+    #[default]
+    Emit,
+    /// Drop the pair from the output
+    Skip,
+    /// Report an error for the whole tree
+    Error,
+}
+
+/// Which distances of a pair are checked against the maximum distance
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DistanceSide {
+    /// Skip pairs whose reference distance is too large
+    #[default]
+    Ref,
+    /// Skip pairs whose comparison distance is too large
+    Cmp,
+    /// Skip pairs whose distances are both too large
+    Both,
+    /// Skip pairs where either distance is too large
+    Either,
+}
+
+/// How the roots of trees are made comparable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum RootNormalization {
+    /// Resolve roots with more than two children into a bifurcation
+    Bifurcate,
+    /// Collapse a bifurcating root into a trifurcation
+    Trifurcate,
+}
+
+impl RootNormalization {
+    fn apply(self, tree: &Tree) -> Result<Tree> {
+        match self {
+            Self::Bifurcate => transform::bifurcate_root(tree),
+            Self::Trifurcate => transform::trifurcate_root(tree),
+        }
+    }
+}
+
+// Number of children of the root of a tree
+fn root_degree(tree: &Tree) -> Result<usize> {
+    Ok(tree.get(&tree.get_root()?)?.children.len())
+}
+
+/// Value derived from the reference and comparison distances of a pair
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DistanceTransform {
+    /// Comparison distance minus reference distance
+    Diff,
+    /// Comparison distance over reference distance, NaN if the reference
+    /// distance is 0
+    Ratio,
+    /// Natural log of the ratio, NaN if either distance is not positive
+    Logratio,
+}
+
+impl DistanceTransform {
+    fn apply(self, ref_dist: f64, cmp_dist: f64) -> f64 {
+        match self {
+            Self::Diff => cmp_dist - ref_dist,
+            Self::Ratio if ref_dist == 0. => f64::NAN,
+            Self::Ratio => cmp_dist / ref_dist,
+            Self::Logratio if ref_dist <= 0. || cmp_dist <= 0. => f64::NAN,
+            Self::Logratio => (cmp_dist / ref_dist).ln(),
+        }
+    }
+}
+
+/// Weight of each pair of tips in the distance summary, as a function of its
+/// reference distance `d`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DistanceWeight {
+    /// w = 1, all pairs count the same
+    Uniform,
+    /// w = 1 / d, pairs with d = 0 are left out
+    Inverse,
+    /// w = 1 / d², pairs with d = 0 are left out
+    InverseSq,
+}
+
+impl DistanceWeight {
+    // Weight of a pair, None if it is undefined at a reference distance of 0
+    fn weight(self, ref_dist: f64) -> Option<f64> {
+        match self {
+            Self::Uniform => Some(1.),
+            _ if ref_dist == 0. => None,
+            Self::Inverse => Some(1. / ref_dist),
+            Self::InverseSq => Some(1. / (ref_dist * ref_dist)),
+        }
+    }
+}
+
+/// Which depths of a common branch are checked against the depth range
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DepthSide {
+    /// Keep branches whose reference depth is in the range
+    #[default]
+    Ref,
+    /// Keep branches whose comparison depth is in the range
+    Cmp,
+    /// Keep branches whose depths are both in the range
+    Both,
+    /// Keep branches with either depth in the range
+    Either,
+}
+
+/// What to do with reference splits that have no support value
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MissingSupport {
+    /// Give the split a weight of 1
+    #[default]
+    One,
+    /// Report an error for the whole tree
+    Error,
+}
+
+/// Which modalities to compare, and how
+#[derive(Debug, Default, Clone)]
+pub struct CompOpts {
+    pub topology: bool,
+    pub lengths: bool,
+    pub distances: bool,
+    pub include_tips: bool,
+    pub tips_only_branches: bool,
+    pub nan_policy: NanPolicy,
+    pub align: bool,
+    pub midpoint_root: bool,
+    pub unit_height: bool,
+    pub normalize_root: Option<RootNormalization>,
+    pub clades: Option<Vec<Clade>>,
+    pub label_field: Option<LabelField>,
+    pub internal_labels: bool,
+    pub keep_tips: Option<Regex>,
+    pub topological_distances: bool,
+    pub support_weighted_rf: Option<MissingSupport>,
+    pub split_weights: Option<Vec<SplitWeight>>,
+    pub canonical: bool,
+    pub canonical_internal_labels: bool,
+    pub cherries: bool,
+    pub root_agreement: bool,
+    pub branch_regression: bool,
+    pub compatibility: bool,
+    pub top_branch_diffs: Option<usize>,
+    pub collapse_sweep: Option<Vec<f64>>,
+    pub clade_rf: bool,
+    pub prec_recall: bool,
+    pub height_diff: bool,
+    pub diameter_diff: bool,
+    pub normalize_kf: bool,
+    pub max_distance: Option<f64>,
+    pub max_distance_on: DistanceSide,
+    pub distance_transform: Option<DistanceTransform>,
+    pub normalize_by_diameter: bool,
+    pub distance_weight: Option<DistanceWeight>,
+    pub distance_groups: Option<HashMap<String, Arc<str>>>,
+    pub rf_null: Option<usize>,
+    pub seed: u64,
+    pub min_depth: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub depth_on: DepthSide,
+    pub rf_info: bool,
+    pub mast: bool,
+    pub spr: bool,
+    pub allow_refinement: bool,
+    pub shared_weighted_rf: bool,
+}
+
+impl CompOpts {
+    /// Check that at least one comparison modality is enabled
+    pub fn has_modality(&self) -> bool {
+        self.topology
+            || self.lengths
+            || self.distances
+            || self.clades.is_some()
+            || self.label_field.is_some()
+            || self.internal_labels
+            || self.canonical
+            || self.cherries
+            || self.root_agreement
+            || self.branch_regression
+            || self.compatibility
+    }
+}
+
+#[derive(Default, Debug, Serialize)]
 pub struct ComparisonRecord {
+    #[serde(serialize_with = "io::serialize_id")]
+    pub id: Arc<String>,
     pub topology: Option<TopologyRecord>,
     pub branches: Option<Vec<BranchRecord>>,
     pub distances: Option<Vec<DistanceRecord>>,
+    pub distance_summary: Option<DistanceSummaryRecord>,
+    pub clades: Option<Vec<CladeRecord>>,
+    pub internal_nodes: Option<Vec<InternalNodeRecord>>,
+    pub canonical: Option<CanonicalRecord>,
+    pub cherries: Option<CherryRecord>,
+    pub roots: Option<RootRecord>,
+    pub branch_regression: Option<BranchRegressionRecord>,
+    pub compatibility: Option<Vec<CompatibilityRecord>>,
+    /// Topology comparisons at each collapse threshold of the sweep
+    pub topology_sweep: Option<Vec<TopologyRecord>>,
+    /// Partial record holding a chunk of streamed distances
+    #[serde(skip)]
+    pub is_chunk: bool,
+    /// Why distances could not be compared, the other comparisons of the
+    /// record being kept
+    #[serde(skip)]
+    pub distance_error: Option<anyhow::Error>,
+    /// Why the tree could not be compared, for records standing in for a
+    /// failed comparison
+    #[serde(skip)]
+    pub failure: Option<anyhow::Error>,
+    /// Warnings about the comparison, e.g. trees rescaled to a unit height
+    /// although they were not ultrametric
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+    /// Where the comparison tree comes from, written in the last columns of
+    /// its rows
+    #[serde(flatten)]
+    pub provenance: io::Provenance,
+}
+
+/// Distances between the tips of a tree and of its reference, as they are
+/// compared: topological or branch length ones, divided by the diameter of
+/// their tree if `normalize_by_diameter` is set
+pub fn tip_distances(
+    reftree: &Tree,
+    cmptree: &Tree,
+    opts: &CompOpts,
+) -> Result<(TipDistances, TipDistances)> {
+    let mut ref_dists = TipDistances::from_tree(reftree, opts.topological_distances)?;
+    let mut cmp_dists = TipDistances::from_tree(cmptree, opts.topological_distances)?;
+    if opts.normalize_by_diameter {
+        ref_dists.normalize_by_diameter();
+        cmp_dists.normalize_by_diameter();
+    }
+
+    Ok((ref_dists, cmp_dists))
+}
+
+/// Apply the transformations of the options to a tree and its reference,
+/// as they are before being compared. Also returns warnings about the
+/// transformations, e.g. trees rescaled although they were not ultrametric.
+#[allow(clippy::type_complexity)]
+pub fn transform_trees<'a>(
+    id: &str,
+    reftree: &'a Tree,
+    cmptree: &'a Tree,
+    opts: &CompOpts,
+) -> Result<(Cow<'a, Tree>, Cow<'a, Tree>, Vec<String>)> {
+    let mut warnings = vec![];
+    // Transformations are applied to copies of the trees
+    let (mut reftree, mut cmptree) = (Cow::Borrowed(reftree), Cow::Borrowed(cmptree));
+
+    if let Some(re) = opts.keep_tips.as_ref() {
+        reftree = Cow::Owned(transform::keep_tips(&reftree, re)?);
+        cmptree = Cow::Owned(transform::keep_tips(&cmptree, re)?);
+    }
+
+    if opts.midpoint_root {
+        reftree = Cow::Owned(transform::midpoint_root(&reftree)?);
+        cmptree = Cow::Owned(transform::midpoint_root(&cmptree)?);
+    }
+
+    if let Some(normalization) = opts.normalize_root {
+        reftree = Cow::Owned(normalization.apply(&reftree)?);
+        cmptree = Cow::Owned(normalization.apply(&cmptree)?);
+    }
+
+    if opts.unit_height {
+        for (tree, side) in [(&mut reftree, "reference"), (&mut cmptree, "comparison")] {
+            let (rescaled, ultrametric) = transform::unit_height(&**tree)?;
+            if !ultrametric {
+                warnings.push(format!(
+                    "The {side} tree of {id} is not ultrametric, it was rescaled by its height"
+                ));
+            }
+            *tree = Cow::Owned(rescaled);
+        }
+    }
+
+    Ok((reftree, cmptree, warnings))
 }
 
+/// Compare a tree to its reference. If a distance sink is given, distance
+/// records are passed to it in chunks as they are computed instead of being
+/// stored in the returned record. If a reference cache is given, with the key
+/// of the reference, its tip distances are shared with other trees compared
+/// to the same reference.
 pub fn compare_trees(
     id: impl Into<String>,
     reftree: &Tree,
     cmptree: &Tree,
-    compare_topo: bool,
-    compare_lens: bool,
-    compare_dist: bool,
-    include_tips: bool,
+    opts: &CompOpts,
+    dist_sink: Option<&mut DistSink>,
+    ref_cache: Option<(&str, &RefDistCache)>,
 ) -> Result<Box<ComparisonRecord>> {
+    let id = Arc::new(id.into());
+
     let mut record = Box::new(ComparisonRecord {
+        id: id.clone(),
         topology: None,
         branches: None,
         distances: None,
+        distance_summary: None,
+        clades: None,
+        internal_nodes: None,
+        canonical: None,
+        cherries: None,
+        roots: None,
+        branch_regression: None,
+        compatibility: None,
+        topology_sweep: None,
+        is_chunk: false,
+        distance_error: None,
+        failure: None,
+        warnings: vec![],
+        provenance: io::Provenance::default(),
     });
 
-    let id = Arc::new(id.into());
+    // Root degrees of the trees as they were read
+    let root_degrees = match opts.normalize_root {
+        Some(_) => Some((root_degree(reftree)?, root_degree(cmptree)?)),
+        None => None,
+    };
+
+    let (reftree, cmptree, warnings) = transform_trees(&id, reftree, cmptree, opts)?;
+    record.warnings = warnings;
+
+    // When comparing both topologies and branches, extract splits only once
+    let (fused_topo, fused_branches) = if opts.topology
+        && opts.collapse_sweep.is_none()
+        && (opts.lengths || opts.branch_regression)
+    {
+        let (topo, branches) = splits::compare_topologies_and_branches(
+            &reftree,
+            &cmptree,
+            opts.include_tips,
+            opts.tips_only_branches,
+        )?;
+        (Some(topo), Some(branches))
+    } else {
+        (None, None)
+    };
 
     // Compare topologies
-    if compare_topo {
-        let mut topo = TopologyRecord::from(reftree.compare_topologies(cmptree)?);
-        topo.n_tips = reftree.n_leaves();
-        topo.id = id.clone();
-        record.topology = Some(topo);
+    if opts.topology {
+        match opts.collapse_sweep.as_ref() {
+            None => {
+                record.topology = Some(TopologyRecord::from_trees(
+                    &reftree,
+                    &cmptree,
+                    fused_topo,
+                    opts,
+                    id.clone(),
+                )?)
+            }
+            Some(thresholds) => {
+                let sweep = thresholds
+                    .iter()
+                    .map(|&threshold| {
+                        let collapsed = transform::collapse_support(&cmptree, threshold)?;
+                        let mut topo = TopologyRecord::from_trees(
+                            &reftree,
+                            &collapsed,
+                            None,
+                            opts,
+                            id.clone(),
+                        )?;
+                        topo.threshold = Some(threshold);
+                        Ok(topo)
+                    })
+                    .collect::<Result<_>>()?;
+                record.topology_sweep = Some(sweep);
+            }
+        }
+
+        if let Some((ref_degree, cmp_degree)) = root_degrees {
+            for topo in record
+                .topology
+                .iter_mut()
+                .chain(record.topology_sweep.iter_mut().flatten())
+            {
+                topo.ref_root_degree = Some(Some(ref_degree));
+                topo.cmp_root_degree = Some(Some(cmp_degree));
+            }
+        }
     }
 
     // Compare edges
-    if compare_lens {
-        record.branches = Some(BranchRecord::from_trees(
-            reftree,
-            cmptree,
-            include_tips,
+    if opts.lengths || opts.branch_regression {
+        let branches = match fused_branches {
+            Some(pairs) => BranchRecord::from_pairs(pairs, opts, id.clone()),
+            None => BranchRecord::from_trees(&reftree, &cmptree, opts, id.clone())?,
+        };
+        if opts.branch_regression {
+            record.branch_regression =
+                Some(BranchRegressionRecord::from_branches(&branches, id.clone()));
+        }
+        if opts.lengths {
+            record.branches = Some(branches);
+        }
+    }
+
+    // Check clade monophyly, the clades being given or defined by the tip
+    // labels of the reference
+    let label_clades = opts
+        .label_field
+        .as_ref()
+        .map(|field| field.clades(&reftree))
+        .transpose()?;
+    if let Some(clades) = opts.clades.as_ref().or(label_clades.as_ref()) {
+        record.clades = Some(CladeRecord::from_trees(
+            &reftree,
+            &cmptree,
+            clades,
+            id.clone(),
+        )?);
+    }
+
+    // Compare labeled internal nodes
+    if opts.internal_labels {
+        record.internal_nodes = Some(InternalNodeRecord::from_trees(
+            &reftree,
+            &cmptree,
+            id.clone(),
+        )?);
+    }
+
+    // Compare canonical newick strings
+    if opts.canonical {
+        record.canonical = Some(CanonicalRecord::from_trees(
+            &reftree,
+            &cmptree,
+            opts.canonical_internal_labels,
             id.clone(),
         )?);
     }
 
-    // Compare distances
-    if compare_dist {
-        record.distances = Some(DistanceRecord::from_trees(reftree, cmptree, id)?);
+    // Compare cherries
+    if opts.cherries {
+        record.cherries = Some(CherryRecord::from_trees(&reftree, &cmptree, id.clone())?);
+    }
+
+    // Compare root positions
+    if opts.root_agreement {
+        record.roots = Some(RootRecord::from_trees(&reftree, &cmptree, id.clone())?);
+    }
+
+    // Check the compatibility of reference splits
+    if opts.compatibility {
+        record.compatibility = Some(CompatibilityRecord::from_trees(
+            &reftree,
+            &cmptree,
+            id.clone(),
+        )?);
+    }
+
+    // Compare distances last, so that no distances are streamed for trees
+    // failing another comparison. A failure (e.g. missing branch lengths) is
+    // reported without discarding the other comparisons.
+    if opts.distances {
+        match DistanceRecord::from_trees(&reftree, &cmptree, opts, id, dist_sink, ref_cache) {
+            Ok((dists, summary)) => {
+                record.distances = Some(dists);
+                record.distance_summary = summary;
+            }
+            Err(e) => record.distance_error = Some(e),
+        }
     }
 
     Ok(record)
 }
+
+/// Compare the tip distances of a reference tree to a precomputed distance
+/// matrix, e.g. read from a PHYLIP file. Only distances are compared.
+pub fn compare_matrix(
+    id: impl Into<String>,
+    reftree: &Tree,
+    cmp_dists: &TipDistances,
+    opts: &CompOpts,
+) -> Result<Box<ComparisonRecord>> {
+    let id = Arc::new(id.into());
+
+    let reftree = match opts.keep_tips.as_ref() {
+        Some(re) => Cow::Owned(transform::keep_tips(reftree, re)?),
+        None => Cow::Borrowed(reftree),
+    };
+    let ref_dists = TipDistances::from_tree(&reftree, opts.topological_distances)?;
+    let (distances, summary) =
+        DistanceRecord::from_distances(&ref_dists, cmp_dists, opts, id.clone(), None)?;
+
+    Ok(Box::new(ComparisonRecord {
+        id,
+        distances: Some(distances),
+        distance_summary: summary,
+        ..Default::default()
+    }))
+}
+
+/// A pool of reference trees, each comparison tree being compared to all the
+/// references with the same tips
+pub struct RefPool {
+    // Reference id, sorted tip names and tree, sorted by id
+    refs: Vec<(String, Vec<String>, Tree)>,
+}
+
+impl RefPool {
+    pub fn new(trees: HashMap<String, Tree>) -> Result<Self> {
+        let refs = trees
+            .into_iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(id, tree)| {
+                let taxa = splits::get_taxa(&tree)
+                    .context(format!("Could not get tips of reference: {id}"))?;
+                Ok((id, taxa, tree))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { refs })
+    }
+
+    // References with the same tips as the tree
+    fn matching<'a>(&'a self, tree: &Tree) -> impl Iterator<Item = (&'a str, &'a Tree)> {
+        let taxa = splits::get_taxa(tree).ok();
+        self.refs
+            .iter()
+            .filter(move |(_, t, _)| taxa.as_ref() == Some(t))
+            .map(|(id, _, tree)| (id.as_str(), tree))
+    }
+
+    /// Check if at least one reference has the same tips as the tree
+    pub fn has_match(&self, tree: &Tree) -> bool {
+        self.matching(tree).next().is_some()
+    }
+
+    /// Compare a tree to every reference with the same tips and only keep
+    /// the comparison with the lowest RF distance. Ties are broken by the
+    /// reference id.
+    pub fn compare_best(
+        &self,
+        id: &str,
+        cmptree: &Tree,
+        opts: &CompOpts,
+    ) -> Result<Box<ComparisonRecord>> {
+        let mut best: Option<(f64, Box<ComparisonRecord>)> = None;
+
+        for (ref_id, reftree) in self.matching(cmptree) {
+            let mut record = compare_trees(id, reftree, cmptree, opts, None, None)
+                .context(format!("Could not compare to reference: {ref_id}"))?;
+            let Some(topo) = record.topology.as_mut() else {
+                bail!("The topology must be compared to find the best reference")
+            };
+            topo.best_ref_id = Some(ref_id.into());
+
+            let rf = topo.rf;
+            let is_best = match &best {
+                Some((best_rf, _)) => rf < *best_rf,
+                None => true,
+            };
+            if is_best {
+                best = Some((rf, record));
+            }
+        }
+
+        best.map(|(_, record)| record)
+            .context("No reference has the same tips as the tree")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(newick: &str) -> Tree {
+        Tree::from_newick(newick).unwrap()
+    }
+
+    #[test]
+    fn topology_does_not_depend_on_lengths() {
+        let pairs = [
+            // Rooted
+            (
+                "((A:1,B:2):1,((C:1,D:1):0.5,E:3):2);",
+                "((A:1,C:2):1,((B:1,D:1):0.5,E:3):2);",
+            ),
+            // Unrooted
+            (
+                "((A:1,B:2):1,(C:1,D:1):0.5,E:3);",
+                "((A:1,C:2):1,(B:1,D:1):0.5,E:3);",
+            ),
+        ];
+        let topo_only = CompOpts {
+            topology: true,
+            ..Default::default()
+        };
+        let with_lengths = CompOpts {
+            topology: true,
+            lengths: true,
+            ..Default::default()
+        };
+
+        for (reftree, cmptree) in pairs {
+            let (reftree, cmptree) = (parse(reftree), parse(cmptree));
+            let topo = |opts: &CompOpts| {
+                compare_trees("tree", &reftree, &cmptree, opts, None, None)
+                    .unwrap()
+                    .topology
+                    .unwrap()
+            };
+            let (alone, both) = (topo(&topo_only), topo(&with_lengths));
+
+            assert_eq!(alone.rf, both.rf);
+            assert_eq!(alone.norm_rf, both.norm_rf);
+            assert_eq!(alone.weighted_rf, both.weighted_rf);
+            assert_eq!(alone.kf_score, both.kf_score);
+        }
+    }
+
+    #[test]
+    fn distance_correlation_of_large_distances() {
+        // Distances with a small spread around a large value, where sums of
+        // squares lose all precision
+        let mut sums = WeightedSums::default();
+        for i in 0..100 {
+            let x = 1e9 + f64::from(i % 7);
+            sums.add(DistanceWeight::Uniform, x, x + 1.);
+        }
+        let summary = DistanceSummaryRecord::from_sums(&sums, Arc::new("tree".into()));
+
+        assert_eq!(summary.n_pairs, 100);
+        assert!((summary.correlation - 1.).abs() < 1e-9);
+        assert!((summary.rmsd - 1.).abs() < 1e-9);
+    }
+}
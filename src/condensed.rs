@@ -0,0 +1,101 @@
+use std::{io::Write, path::PathBuf, sync::Mutex};
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use phylotree::tree::Tree;
+
+use phylocompare::{
+    comp::{tip_distances, CompOpts},
+    io::{check_overwrite, get_suffixed_filenme, init_writer},
+};
+
+/// Writes the distances of each tree in condensed form, as in
+/// `scipy.spatial.distance.squareform`: the upper triangle of the distance
+/// matrix, row by row. Each tree gets a headerless csv file with the
+/// reference and comparison distance of each pair of tips, and a single
+/// index file maps positions in these vectors to pairs of tips.
+pub struct CondensedWriter {
+    prefix: PathBuf,
+    zipped: bool,
+    index_path: PathBuf,
+    // Sorted taxa of the index, once it is written
+    taxa: Mutex<Option<Vec<String>>>,
+}
+
+impl CondensedWriter {
+    /// Create a writer for the condensed distances of these trees, checking
+    /// that neither the index nor the file of any tree exist yet unless they
+    /// can be overwritten
+    pub fn new<'a>(
+        prefix: &PathBuf,
+        zipped: bool,
+        ids: impl Iterator<Item = &'a str>,
+        force: bool,
+    ) -> Result<Self> {
+        let writer = Self {
+            prefix: prefix.clone(),
+            zipped,
+            index_path: get_suffixed_filenme(prefix, "condensed_index", "csv", zipped)?,
+            taxa: Mutex::new(None),
+        };
+        let mut paths = vec![writer.index_path.clone()];
+        for id in ids {
+            paths.push(writer.path(id)?);
+        }
+        check_overwrite(paths, force)?;
+
+        Ok(writer)
+    }
+
+    // Path of the condensed distances of a tree
+    fn path(&self, id: &str) -> Result<PathBuf> {
+        get_suffixed_filenme(&self.prefix, &format!("{id}_condensed"), "csv", self.zipped)
+    }
+
+    // Write the index on the first call, and check that later trees have the
+    // same tips
+    fn check_index(&self, taxa: &[String]) -> Result<()> {
+        let mut index = self.taxa.lock().unwrap();
+        match index.as_deref() {
+            Some(index) if index == taxa => return Ok(()),
+            Some(_) => bail!(
+                "Tips differ from the ones of the condensed distance index: {}",
+                self.index_path.display()
+            ),
+            None => {}
+        }
+
+        let mut writer = init_writer(self.index_path.clone(), self.zipped)?;
+        writeln!(writer, "position,tip_1,tip_2")?;
+        for (position, (tip_1, tip_2)) in taxa.iter().tuple_combinations().enumerate() {
+            writeln!(writer, "{position},{tip_1},{tip_2}")?;
+        }
+        writer.flush()?;
+        *index = Some(taxa.to_vec());
+
+        Ok(())
+    }
+
+    /// Write the condensed distances of a tree and its reference, in the
+    /// order of the sorted reference tips. Distances involving tips missing
+    /// from the comparison tree are NaN.
+    pub fn write(&self, id: &str, reftree: &Tree, cmptree: &Tree, opts: &CompOpts) -> Result<()> {
+        let path = self.path(id)?;
+
+        let (ref_dists, cmp_dists) = tip_distances(reftree, cmptree, opts)?;
+        let taxa: Vec<_> = ref_dists.taxa.iter().cloned().sorted().collect();
+        self.check_index(&taxa)?;
+
+        let mut writer = init_writer(path.clone(), self.zipped)?;
+        for (tip_1, tip_2) in taxa.iter().tuple_combinations() {
+            let ref_dist = ref_dists.get(tip_1, tip_2).unwrap_or(f64::NAN);
+            let cmp_dist = cmp_dists.get(tip_1, tip_2).unwrap_or(f64::NAN);
+            writeln!(writer, "{ref_dist},{cmp_dist}")?;
+        }
+        writer
+            .flush()
+            .context(format!("Could not write: {}", path.display()))?;
+
+        Ok(())
+    }
+}
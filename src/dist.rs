@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context, Result};
+use phylotree::tree::{NodeId, Tree};
+
+/// Pairwise distances between the tips of a tree
+#[derive(Debug, Clone)]
+pub struct TipDistances {
+    pub taxa: Vec<String>,
+    index: HashMap<String, usize>,
+    dists: Vec<f64>,
+}
+
+impl TipDistances {
+    fn new(taxa: Vec<String>) -> Self {
+        let index = taxa
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.clone(), i))
+            .collect();
+        let size = taxa.len();
+
+        Self {
+            taxa,
+            index,
+            dists: vec![f64::NAN; size * size],
+        }
+    }
+
+    fn set(&mut self, i: usize, j: usize, dist: f64) {
+        let size = self.taxa.len();
+        self.dists[i * size + j] = dist;
+        self.dists[j * size + i] = dist;
+    }
+
+    /// Distance between two tips, if both are in the tree
+    pub fn get(&self, tip_1: &str, tip_2: &str) -> Option<f64> {
+        let (i, j) = (self.index.get(tip_1)?, self.index.get(tip_2)?);
+        Some(self.dists[i * self.taxa.len() + j])
+    }
+
+    /// Largest distance between two tips, i.e. the diameter of the tree.
+    /// Missing distances are ignored, and a tree without pairs of tips has a
+    /// diameter of 0.
+    pub fn diameter(&self) -> f64 {
+        self.dists.iter().copied().fold(0., f64::max)
+    }
+
+    /// Divide the distances by the diameter of the tree. A tree with a
+    /// diameter of 0 only has distances of 0, which are left as they are
+    /// instead of giving NaN.
+    pub fn normalize_by_diameter(&mut self) {
+        let diameter = self.diameter();
+        if diameter > 0. {
+            for dist in self.dists.iter_mut() {
+                *dist /= diameter;
+            }
+        }
+    }
+
+    /// Dense row-major matrix of the distances between the given tips, in
+    /// that order. Distances involving tips missing from the tree are NaN.
+    pub fn matrix(&self, taxa: &[String]) -> Vec<f64> {
+        let mut matrix = Vec::with_capacity(taxa.len() * taxa.len());
+        for tip_1 in taxa {
+            for tip_2 in taxa {
+                matrix.push(self.get(tip_1, tip_2).unwrap_or(f64::NAN));
+            }
+        }
+        matrix
+    }
+
+    /// Get the distances between tips of a tree: topological ones (number of
+    /// branches) or branch length ones
+    pub fn from_tree(tree: &Tree, topological: bool) -> Result<Self> {
+        if topological {
+            Self::from_topology(tree)
+        } else {
+            Self::from_lengths(tree)
+        }
+    }
+
+    /// Get the branch length distances between tips of a tree
+    pub fn from_lengths(tree: &Tree) -> Result<Self> {
+        let matrix = tree.distance_matrix()?;
+        let taxa: Vec<String> = matrix.taxa.iter().cloned().collect();
+        let mut dists = Self::new(taxa.clone());
+
+        for (i, tip_1) in taxa.iter().enumerate() {
+            for (j, tip_2) in taxa.iter().enumerate().skip(i) {
+                let dist = if i == j {
+                    0.
+                } else {
+                    matrix.get(tip_1, tip_2).ok().copied().unwrap_or(f64::NAN)
+                };
+                dists.set(i, j, dist);
+            }
+        }
+
+        Ok(dists)
+    }
+
+    /// Read distances from a square PHYLIP distance matrix: the number of
+    /// taxa, followed by one row per taxon with its name and its distances
+    /// to every taxon. Names and distances are separated by whitespace, and
+    /// rows may span several lines.
+    pub fn from_phylip(content: &str) -> Result<Self> {
+        let mut tokens = content.split_whitespace();
+        let size: usize = tokens
+            .next()
+            .context("Empty distance matrix")?
+            .parse()
+            .context("The distance matrix must start with the number of taxa")?;
+
+        let mut taxa = Vec::with_capacity(size);
+        let mut values = Vec::with_capacity(size * size);
+        for i in 0..size {
+            let name = tokens
+                .next()
+                .context(format!("Missing row {} of the distance matrix", i + 1))?;
+            for j in 0..size {
+                let value = tokens
+                    .next()
+                    .context(format!("Missing distance {} on the row of {name}", j + 1))?;
+                let value: f64 = value
+                    .parse()
+                    .context(format!("Invalid distance on the row of {name}: {value}"))?;
+                values.push(value);
+            }
+            taxa.push(name.to_string());
+        }
+        if tokens.next().is_some() {
+            bail!("Unexpected values after the {size} rows of the distance matrix")
+        }
+
+        let mut dists = Self::new(taxa);
+        if dists.index.len() != size {
+            bail!("Taxa names of the distance matrix are not unique")
+        }
+        for i in 0..size {
+            for j in i..size {
+                if values[i * size + j] != values[j * size + i] {
+                    bail!(
+                        "The distance matrix is not symmetric between {} and {}",
+                        dists.taxa[i],
+                        dists.taxa[j]
+                    )
+                }
+                dists.set(i, j, values[i * size + j]);
+            }
+        }
+
+        Ok(dists)
+    }
+
+    /// Get the topological distances between tips of a tree, i.e. the number
+    /// of branches on the path between them. Branch lengths are not needed.
+    pub fn from_topology(tree: &Tree) -> Result<Self> {
+        let root = tree.get_root()?;
+        let mut adj: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+        let mut tips = vec![];
+
+        for id in tree.preorder(&root)? {
+            let node = tree.get(&id)?;
+            if node.is_tip() {
+                if let Some(name) = node.name.as_ref() {
+                    tips.push((id, name.clone()));
+                }
+            }
+            for child in node.children.iter() {
+                adj.entry(id).or_default().push(*child);
+                adj.entry(*child).or_default().push(id);
+            }
+        }
+
+        let mut dists = Self::new(tips.iter().map(|(_, name)| name.clone()).collect());
+        let tip_index: HashMap<_, _> = tips
+            .iter()
+            .enumerate()
+            .map(|(i, (id, _))| (*id, i))
+            .collect();
+
+        for (i, (start, _)) in tips.iter().enumerate() {
+            dists.set(i, i, 0.);
+            let mut stack = vec![(*start, *start, 0)];
+            while let Some((node, from, n_edges)) = stack.pop() {
+                if let Some(&j) = tip_index.get(&node) {
+                    if j > i {
+                        dists.set(i, j, n_edges as f64);
+                    }
+                }
+                for &next in adj.get(&node).into_iter().flatten() {
+                    if next != from {
+                        stack.push((next, node, n_edges + 1));
+                    }
+                }
+            }
+        }
+
+        Ok(dists)
+    }
+}
+
+/// Tip distances of references compared to several trees, computed once and
+/// dropped after their last use
+pub struct RefDistCache {
+    // Distances (once computed) and number of remaining uses of each reference
+    entries: Mutex<HashMap<String, (Option<Arc<TipDistances>>, usize)>>,
+}
+
+impl RefDistCache {
+    /// Create a cache from the number of trees compared to each reference.
+    /// References used only once are not cached.
+    pub fn new(uses: HashMap<String, usize>) -> Self {
+        let entries = uses
+            .into_iter()
+            .filter(|(_, n)| *n > 1)
+            .map(|(key, n)| (key, (None, n)))
+            .collect();
+        Self {
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Get the distances of a reference, computing them if they are not
+    /// cached yet. Distances are computed outside of the lock, so that other
+    /// references are not blocked.
+    pub fn get(
+        &self,
+        key: &str,
+        compute: impl FnOnce() -> Result<TipDistances>,
+    ) -> Result<Arc<TipDistances>> {
+        let cached = {
+            let mut entries = self.entries.lock().unwrap();
+            match entries.get_mut(key) {
+                None => return Ok(Arc::new(compute()?)),
+                Some((Some(dists), uses)) => {
+                    let dists = dists.clone();
+                    *uses -= 1;
+                    if *uses == 0 {
+                        entries.remove(key);
+                    }
+                    Some(dists)
+                }
+                Some((None, _)) => None,
+            }
+        };
+        if let Some(dists) = cached {
+            return Ok(dists);
+        }
+
+        let dists = Arc::new(compute()?);
+        let mut entries = self.entries.lock().unwrap();
+        if let Some((cached, uses)) = entries.get_mut(key) {
+            *uses -= 1;
+            if *uses == 0 {
+                entries.remove(key);
+            } else if cached.is_none() {
+                *cached = Some(dists.clone());
+            }
+        }
+
+        Ok(dists)
+    }
+}
+
+/// Height of a tree: the distance from the root to its farthest tip
+pub fn tree_height(tree: &Tree) -> Result<f64> {
+    let root = tree.get_root()?;
+    let mut depths: HashMap<NodeId, f64> = HashMap::from([(root, 0.)]);
+    let mut height: f64 = 0.;
+
+    for id in tree.preorder(&root)? {
+        let node = tree.get(&id)?;
+        let Some(parent) = node.parent else {
+            continue;
+        };
+        let len = node
+            .parent_edge
+            .context("All branches must have a length to get the tree height")?;
+        let depth = depths[&parent] + len;
+        depths.insert(id, depth);
+        if node.is_tip() {
+            height = height.max(depth);
+        }
+    }
+
+    Ok(height)
+}
+
+/// Diameter of a tree: the length of the longest path between two of its
+/// tips. Unlike the height, it does not depend on the root position.
+pub fn tree_diameter(tree: &Tree) -> Result<f64> {
+    let root = tree.get_root()?;
+    // Length of the longest path from each node down to one of its tips
+    let mut down: HashMap<NodeId, f64> = HashMap::new();
+    let mut diameter: f64 = 0.;
+
+    for id in tree.preorder(&root)?.into_iter().rev() {
+        let node = tree.get(&id)?;
+        if node.is_tip() {
+            down.insert(id, 0.);
+            continue;
+        }
+
+        // Two longest paths down through different children
+        let (mut first, mut second) = (f64::NEG_INFINITY, f64::NEG_INFINITY);
+        for child in node.children.iter() {
+            let len = tree
+                .get(child)?
+                .parent_edge
+                .context("All branches must have a length to get the tree diameter")?;
+            let path = down[child] + len;
+            if path > first {
+                second = first;
+                first = path;
+            } else if path > second {
+                second = path;
+            }
+        }
+        down.insert(id, first);
+        diameter = diameter.max(first + second);
+    }
+
+    Ok(diameter)
+}
+
+/// Total length of a tree: the sum of its branch lengths
+pub fn tree_length(tree: &Tree) -> Result<f64> {
+    let root = tree.get_root()?;
+    let mut length = 0.;
+
+    for id in tree.preorder(&root)? {
+        let node = tree.get(&id)?;
+        if node.parent.is_none() {
+            continue;
+        }
+        length += node
+            .parent_edge
+            .context("All branches must have a length to get the tree length")?;
+    }
+
+    Ok(length)
+}
@@ -0,0 +1,71 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use phylotree::tree::Tree;
+
+use phylocompare::splits::{get_clades, get_splits, get_union_taxa};
+
+// Color of the branches whose split is missing from the comparison tree
+const CONFLICT_COLOR: &str = "red";
+
+// Quote a label for DOT
+fn quote(label: &str) -> String {
+    format!("\"{}\"", label.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Write the reference tree as a GraphViz DOT graph to `<dir>/<id>.dot`,
+/// highlighting the branches whose split is not in the comparison tree
+pub fn write_conflicts(dir: &Path, id: &str, reftree: &Tree, cmptree: &Tree) -> Result<()> {
+    let path = dir.join(format!("{id}.dot"));
+
+    let taxa = get_union_taxa(reftree, cmptree)?;
+    let n = taxa.len();
+    let cmp_splits = get_splits(cmptree, &taxa)?;
+    let clades = get_clades(reftree, &taxa)?;
+    // Nodes whose parent branch holds a split missing from the comparison
+    let conflicts: HashSet<_> = clades
+        .into_iter()
+        .filter_map(|(node, mut split)| {
+            if split.contains(0) {
+                split.toggle_range(..);
+            }
+            let size = split.count_ones(..);
+            (size > 1 && size + 1 < n && !cmp_splits.contains(&split)).then_some(node)
+        })
+        .collect();
+
+    let mut dot = format!("digraph {} {{\n", quote(id));
+    dot.push_str("  node [shape=point];\n");
+    for node_id in reftree.preorder(&reftree.get_root()?)? {
+        let node = reftree.get(&node_id)?;
+        if node.is_tip() {
+            let name = node.name.as_deref().unwrap_or_default();
+            dot.push_str(&format!(
+                "  n{node_id} [shape=plaintext, label={}];\n",
+                quote(name)
+            ));
+        }
+        if let Some(parent) = node.parent {
+            let style = if conflicts.contains(&node_id) {
+                format!(" [color={CONFLICT_COLOR}, penwidth=2]")
+            } else {
+                String::new()
+            };
+            dot.push_str(&format!("  n{parent} -> n{node_id}{style};\n"));
+        }
+    }
+    dot.push_str("}\n");
+
+    let mut writer = BufWriter::new(
+        File::create(&path).context(format!("Could not write: {}", path.display()))?,
+    );
+    writer.write_all(dot.as_bytes())?;
+    writer.flush()?;
+
+    Ok(())
+}
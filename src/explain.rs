@@ -0,0 +1,142 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use fixedbitset::FixedBitSet;
+use itertools::Itertools;
+use phylotree::tree::Tree;
+
+use phylocompare::{
+    io::round_float,
+    splits::{get_branches, get_shared_splits, get_taxa},
+};
+
+// Number of shared branches listed, by decreasing length change
+const N_CHANGED_BRANCHES: usize = 10;
+
+// List the tips of the smaller side of a split
+fn describe(split: &FixedBitSet, taxa: &[String]) -> String {
+    let mut side = split.clone();
+    if side.count_ones(..) * 2 > taxa.len() {
+        side.toggle_range(..);
+    }
+    format!("{{{}}}", side.ones().map(|i| taxa[i].as_str()).join(", "))
+}
+
+// Write a list of clades, or a placeholder if there are none
+fn write_clades(text: &mut String, header: &str, clades: &[String]) -> Result<()> {
+    writeln!(text, "\n{header}:")?;
+    if clades.is_empty() {
+        writeln!(text, "  none")?;
+    }
+    for clade in clades {
+        writeln!(text, "  {clade}")?;
+    }
+    Ok(())
+}
+
+/// Describe in plain English how a tree differs from its reference: the
+/// clades found in only one of them, how they add up to the RF distance,
+/// and the shared branches whose length changed the most. Clades are the
+/// non-trivial splits of the unrooted trees, given by their smaller side.
+pub fn explain(
+    ref_id: &str,
+    reftree: &Tree,
+    cmp_id: &str,
+    cmptree: &Tree,
+    precision: Option<usize>,
+) -> Result<String> {
+    let (n, ref_splits, cmp_splits) = get_shared_splits(reftree, cmptree)?;
+    let taxa = get_taxa(reftree)?;
+    let missing = ref_splits
+        .difference(&cmp_splits)
+        .map(|split| describe(split, &taxa))
+        .sorted()
+        .collect_vec();
+    let extra = cmp_splits
+        .difference(&ref_splits)
+        .map(|split| describe(split, &taxa))
+        .sorted()
+        .collect_vec();
+
+    let mut text = String::new();
+    writeln!(
+        text,
+        "Comparing tree {cmp_id} to reference {ref_id}, with {n} tips."
+    )?;
+    writeln!(
+        text,
+        "The reference has {} clades, {} of which are missing from the compared tree.",
+        ref_splits.len(),
+        missing.len()
+    )?;
+    writeln!(
+        text,
+        "The compared tree has {} clades, {} of which are not in the reference.",
+        cmp_splits.len(),
+        extra.len()
+    )?;
+    writeln!(
+        text,
+        "The RF distance is {} = {} missing + {} extra clades, out of at most {}.",
+        missing.len() + extra.len(),
+        missing.len(),
+        extra.len(),
+        ref_splits.len() + cmp_splits.len()
+    )?;
+
+    write_clades(
+        &mut text,
+        "Clades of the reference missing from the compared tree",
+        &missing,
+    )?;
+    write_clades(
+        &mut text,
+        "Clades of the compared tree not in the reference",
+        &extra,
+    )?;
+
+    let (ref_branches, cmp_branches) =
+        match (get_branches(reftree, &taxa), get_branches(cmptree, &taxa)) {
+            (Ok(ref_branches), Ok(cmp_branches)) => (ref_branches, cmp_branches),
+            _ => {
+                writeln!(
+                    text,
+                    "\nBranch lengths are not compared, some branches have no length."
+                )?;
+                return Ok(text);
+            }
+        };
+    let changed = ref_branches
+        .iter()
+        .filter_map(|(split, &(_, ref_len))| {
+            let &(_, cmp_len) = cmp_branches.get(split)?;
+            Some((describe(split, &taxa), ref_len, cmp_len))
+        })
+        .sorted_by(|(a, ref_a, cmp_a), (b, ref_b, cmp_b)| {
+            (cmp_b - ref_b)
+                .abs()
+                .total_cmp(&(cmp_a - ref_a).abs())
+                .then_with(|| a.cmp(b))
+        })
+        .take(N_CHANGED_BRANCHES)
+        .collect_vec();
+
+    writeln!(
+        text,
+        "\nShared branches with the largest length changes (reference -> compared):"
+    )?;
+    if changed.is_empty() {
+        writeln!(text, "  none")?;
+    }
+    for (clade, ref_len, cmp_len) in changed {
+        writeln!(
+            text,
+            "  {clade}: {} -> {} ({:+})",
+            round_float(ref_len, precision),
+            round_float(cmp_len, precision),
+            round_float(cmp_len - ref_len, precision)
+        )?;
+    }
+
+    Ok(text)
+}
@@ -1,71 +1,613 @@
+use crate::{
+    comp::{
+        BaselineRecord, BranchRecord, BranchRegressionRecord, CanonicalRecord, CherryRecord, Clade,
+        CladeRecord, CompOpts, ComparisonRecord, CompatibilityRecord, ConsistencyRecord,
+        CoverageRecord, DeltaRecord, DistanceRecord, DistanceSummaryRecord, InternalNodeRecord,
+        MetricRecord, MissingSupport, RogueRecord, RootNormalization, RootRecord, StatusRecord,
+        TopologyRecord,
+    },
+    dist::TipDistances,
+    splits::SplitWeight,
+    transform::hash_string,
+};
 use anyhow::{bail, Context, Result};
-use flate2::{write::GzEncoder, Compression};
+use clap::ValueEnum;
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use itertools::Itertools;
+use memmap2::Mmap;
 use phylotree::tree::Tree;
+use serde::{Serialize, Serializer};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    cell::RefCell,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
     ffi::{OsStr, OsString},
     fs::{self, metadata, File},
-    io::{self},
+    io::{self, Read, Write},
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime},
 };
 
-/// Check if path exists and is a directory
-pub fn check_dir(path: &Path) -> Result<()> {
-    if !metadata(path)
-        .context(format!("Could not read directory: {}", path.display()))?
-        .is_dir()
-    {
+/// Check if path exists and is a directory, or a zip archive where they can
+/// be read (`allow_zip`)
+pub fn check_dir(path: &Path, allow_zip: bool) -> Result<()> {
+    let metadata =
+        metadata(path).context(format!("Could not read directory: {}", path.display()))?;
+    if is_zip(path) && metadata.is_file() {
+        if !allow_zip {
+            bail!(
+                "Zip archives cannot be read in this mode, extract it first: {}",
+                path.display()
+            )
+        }
+        return Ok(());
+    }
+    if !metadata.is_dir() {
         bail!("{} is not a directory", path.display());
     }
 
     Ok(())
 }
 
+// Check if a path is a zip archive
+pub fn is_zip(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str) == Some("zip")
+}
+
 // Check if file extensions match newick ones
 pub fn is_newick(path: &Path) -> bool {
     let ext = path.extension().and_then(OsStr::to_str);
     ext == Some("nwk") || ext == Some("newick")
 }
 
-// Extract file stem as an identifier
-pub fn get_file_id(path: &Path) -> Result<String> {
+// Check if file extensions match PHYLIP distance matrix ones
+pub fn is_phylip(path: &Path) -> bool {
+    let ext = path.extension().and_then(OsStr::to_str);
+    ext == Some("phy") || ext == Some("phylip") || ext == Some("dist")
+}
+
+// Extract file stem as an identifier, made of its leading dot-delimited
+// segments. Stems with fewer segments are used whole.
+pub fn get_file_id(path: &Path, opts: &ReadOpts) -> Result<String> {
     let id = path
         .file_stem()
         .and_then(OsStr::to_str)
         .context(format!("Could not extract ID from: {}", path.display()))?;
 
-    Ok(id
-        .split('.')
-        .next()
-        .context(format!("Could not get ID for {}", path.display()))?
-        .into())
+    Ok(id.split('.').take(opts.id_segments()).join("."))
+}
+
+/// Where the marker is added to ids, instead of its own column
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MarkerPosition {
+    /// Before the id
+    Prefix,
+    /// After the id
+    Suffix,
+}
+
+/// Options controlling how records are written to the outputs
+#[derive(Debug, Clone, Default)]
+pub struct WriteOpts {
+    /// Number of significant figures floats are rounded to (full precision
+    /// if unset)
+    pub float_precision: Option<usize>,
+    /// Marker written in ids instead of its own column, with its separator
+    pub id_marker: Option<(MarkerPosition, String)>,
+    /// Header names of renamed columns
+    pub column_names: Option<HashMap<String, String>>,
+    /// Start every csv output with a `# phylocompare schema v<N>` comment line
+    pub schema_header: bool,
+    /// Paths of the output files with these suffixes (e.g. `topo`), instead
+    /// of deriving them from the output prefix
+    pub output_paths: HashMap<&'static str, PathBuf>,
+}
+
+impl WriteOpts {
+    /// Write the marker in ids, joined with this separator, instead of the
+    /// marker column. Markers that are JSON objects are written as their
+    /// values in key order, joined with the same separator.
+    pub fn set_id_marker(&mut self, marker: &str, position: MarkerPosition, separator: &str) {
+        let marker = match serde_json::from_str::<BTreeMap<String, String>>(marker) {
+            Ok(markers) => markers.values().join(separator),
+            Err(_) => marker.to_string(),
+        };
+        let marker = match position {
+            MarkerPosition::Prefix => format!("{marker}{separator}"),
+            MarkerPosition::Suffix => format!("{separator}{marker}"),
+        };
+        self.id_marker = Some((position, marker));
+    }
+}
+
+thread_local! {
+    // Options of the records being serialized on this thread, for the serde
+    // helpers of record fields which cannot be given them as arguments
+    static CURRENT_OPTS: RefCell<Option<Arc<WriteOpts>>> = const { RefCell::new(None) };
+}
+
+// Serialize records with these options
+fn with_write_opts<T>(opts: &Arc<WriteOpts>, f: impl FnOnce() -> T) -> T {
+    let previous = CURRENT_OPTS.with(|current| current.replace(Some(opts.clone())));
+    let res = f();
+    CURRENT_OPTS.with(|current| *current.borrow_mut() = previous);
+    res
+}
+
+// Run `f` on the options of the records being serialized, if any
+fn current_opts<T>(f: impl FnOnce(Option<&WriteOpts>) -> T) -> T {
+    CURRENT_OPTS.with(|current| f(current.borrow().as_deref()))
+}
+
+/// Serialize an id, with the marker if it is written in ids
+pub fn serialize_id<S: Serializer>(id: &Arc<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    current_opts(|opts| match opts.and_then(|o| o.id_marker.as_ref()) {
+        Some((MarkerPosition::Prefix, marker)) => serializer.collect_str(&format!("{marker}{id}")),
+        Some((MarkerPosition::Suffix, marker)) => serializer.collect_str(&format!("{id}{marker}")),
+        None => serializer.serialize_str(id),
+    })
+}
+
+// Whether the marker column is left out, because the marker is in ids
+pub fn marker_in_id(_: &Option<String>) -> bool {
+    current_opts(|opts| opts.is_some_and(|o| o.id_marker.is_some()))
+}
+
+/// Where a compared tree comes from, written in the last columns of every
+/// row of the tree
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Provenance {
+    /// Path of the tree file, if it is written to the outputs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_path: Option<String>,
+    /// Modification time of the tree file, if it is written to the outputs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<String>,
+    #[serde(skip_serializing_if = "marker_in_id")]
+    pub marker: Option<String>,
+}
+
+/// Options controlling how newick files are read
+#[derive(Debug, Clone, Default)]
+pub struct ReadOpts {
+    /// Files at least this large (in bytes) are memory-mapped (0 = never)
+    pub mmap_threshold: u64,
+    /// Number of times transient IO errors are retried
+    pub io_retries: u32,
+    /// Translation of the tip labels of comparison trees
+    pub tip_map: Option<Arc<TipMap>>,
+    /// Number of leading dot-delimited segments of file stems forming tree
+    /// ids (the first segment if unset)
+    pub id_segments: Option<usize>,
+}
+
+impl ReadOpts {
+    // Number of segments of file stems forming tree ids
+    fn id_segments(&self) -> usize {
+        self.id_segments.unwrap_or(1)
+    }
+}
+
+/// What to do with comparison tips missing from the tip map
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnmappedTips {
+    /// Keep the original label
+    #[default]
+    Keep,
+    /// Report an error for the tree
+    Error,
+}
+
+/// Translation table from the tip labels of comparison trees to the labels
+/// of reference trees
+#[derive(Debug, Clone)]
+pub struct TipMap {
+    labels: HashMap<String, String>,
+    unmapped: UnmappedTips,
+}
+
+impl TipMap {
+    /// Read a tip map: one tip per line, with the comparison label and the
+    /// reference label separated by a tab
+    pub fn from_file(path: &Path, unmapped: UnmappedTips) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .context(format!("Could not read tip map: {}", path.display()))?;
+
+        let labels = content
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(|line| {
+                let (cmp_label, ref_label) = line
+                    .split_once('\t')
+                    .context(format!("Malformed tip map entry: {line}"))?;
+                Ok((cmp_label.trim().to_string(), ref_label.trim().to_string()))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { labels, unmapped })
+    }
+
+    /// Checksum of the map, the same however its lines were ordered
+    pub fn checksum(&self) -> String {
+        let labels = self
+            .labels
+            .iter()
+            .sorted()
+            .map(|(cmp, reference)| format!("{cmp}\t{reference}"))
+            .join("\n");
+        hash_string(&format!("{labels}\n{:?}", self.unmapped))
+    }
+
+    /// Rename the tips of a tree
+    pub fn relabel(&self, tree: &mut Tree) -> Result<()> {
+        for id in tree.get_leaves() {
+            let node = tree.get_mut(&id)?;
+            let Some(name) = node.name.as_ref() else {
+                continue;
+            };
+            match (self.labels.get(name), self.unmapped) {
+                (Some(label), _) => node.name = Some(label.clone()),
+                (None, UnmappedTips::Keep) => {}
+                (None, UnmappedTips::Error) => bail!("Tip {name} is not in the tip map"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Delay before retrying an IO operation, multiplied by the attempt number
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+// Check if an IO error may not happen again when retrying
+fn is_transient(e: &io::Error) -> bool {
+    const EIO: i32 = 5;
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    ) || e.raw_os_error() == Some(EIO)
+}
+
+// Run an IO operation, retrying it on transient errors
+fn with_retries<T>(retries: u32, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match op() {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                attempt += 1;
+                thread::sleep(RETRY_BACKOFF * attempt);
+            }
+            res => return res,
+        }
+    }
+}
+
+// Memory-map a file, returning None if mapping is not possible
+fn map_file(path: &Path) -> Option<Mmap> {
+    let file = File::open(path).ok()?;
+    // Safety: the mapping is read-only and dropped as soon as the tree is parsed
+    unsafe { Mmap::map(&file) }.ok()
+}
+
+// Remove bracketed comments, such as NHX `[&&NHX:key=value]` or
+// `[&key=value]` annotations, from a newick string. Brackets inside quoted
+// labels are kept.
+fn strip_comments(newick: &str) -> Cow<'_, str> {
+    if !newick.contains('[') {
+        return Cow::Borrowed(newick);
+    }
+
+    let mut stripped = String::with_capacity(newick.len());
+    let (mut quoted, mut in_comment) = (false, false);
+    for c in newick.chars() {
+        match c {
+            ']' if in_comment => in_comment = false,
+            _ if in_comment => {}
+            '[' if !quoted => in_comment = true,
+            '\'' => {
+                quoted = !quoted;
+                stripped.push(c)
+            }
+            _ => stripped.push(c),
+        }
+    }
+
+    Cow::Owned(stripped)
+}
+
+// Parse a newick string, ignoring comments
+fn from_newick(newick: &str) -> Result<Tree> {
+    Ok(Tree::from_newick(&strip_comments(newick.trim()))?)
+}
+
+// Parse a newick file, memory-mapping it if it is large enough
+fn parse_newick(treepath: &Path, size: u64, opts: &ReadOpts) -> Result<Tree> {
+    if opts.mmap_threshold > 0 && size >= opts.mmap_threshold {
+        if let Some(mmap) = map_file(treepath) {
+            let newick = std::str::from_utf8(&mmap)
+                .context(format!("File is not valid UTF-8: {}", treepath.display()))?;
+            return from_newick(newick);
+        }
+    }
+
+    // Only reading the file is retried, parse errors are not transient
+    let newick = with_retries(opts.io_retries, || fs::read_to_string(treepath))
+        .context(format!("Could not read file: {}", treepath.display()))?;
+
+    from_newick(&newick)
+}
+
+// Check if a node label is an extended newick hybrid node label, i.e.
+// `#H`, `#LGT` or `#R` followed by a number
+fn is_hybrid_label(name: &str) -> bool {
+    let Some((_, label)) = name.split_once('#') else {
+        return false;
+    };
+    ["H", "LGT", "R"].iter().any(|kind| {
+        label
+            .strip_prefix(kind)
+            .is_some_and(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()))
+    })
+}
+
+// Find a hybrid node label in a tree parsed from extended newick, which is
+// parsed as an ordinary node name
+fn find_hybrid_label(tree: &Tree) -> Result<Option<String>> {
+    let root = tree.get_root()?;
+    for id in tree.preorder(&root)? {
+        if let Some(name) = tree.get(&id)?.name.as_ref() {
+            if is_hybrid_label(name) {
+                return Ok(Some(name.clone()));
+            }
+        }
+    }
+
+    Ok(None)
 }
 
-// Read a newick file and extract the identifier
-pub fn read_tree(treepath: &Path) -> Result<(String, Tree)> {
-    let mut tree = Tree::from_file(treepath).context(format!(
+// Read a newick file and extract the identifier, along with the modification
+// time of the file
+pub fn read_tree(treepath: &Path, opts: &ReadOpts) -> Result<(String, Tree, SystemTime)> {
+    let metadata = with_retries(opts.io_retries, || metadata(treepath))
+        .context(format!("Could not read file: {}", treepath.display()))?;
+    let mtime = metadata.modified().context(format!(
+        "Could not get modification time of: {}",
+        treepath.display()
+    ))?;
+
+    let tree = parse_newick(treepath, metadata.len(), opts).context(format!(
         "Could not parse newick file: {}",
         treepath.display()
     ))?;
+    let tree = check_tree(tree, treepath)?;
+
+    Ok((get_file_id(treepath, opts)?, tree, mtime))
+}
+
+// Check that a parsed tree is not a network and initialize its depths
+fn check_tree(mut tree: Tree, treepath: &Path) -> Result<Tree> {
+    if let Some(label) = find_hybrid_label(&tree)? {
+        bail!(
+            "{} looks like a phylogenetic network in extended newick (hybrid node {label}), networks are not supported",
+            treepath.display()
+        )
+    }
 
     tree.reset_depths()?;
 
-    Ok((get_file_id(treepath)?, tree))
+    Ok(tree)
 }
 
-// Load reference trees
-pub fn read_refs(ref_dir: &Path) -> Result<HashMap<String, Tree>> {
-    let trees: Result<Vec<_>> = trees_iter(ref_dir)?.collect();
-    Ok(HashMap::from_iter(trees?))
+// Parse the newick entries of a zip archive, including those in nested
+// directories. Entries are identified by their file name.
+pub fn zip_trees(path: &Path, opts: &ReadOpts) -> Result<Vec<(String, Tree)>> {
+    let file = File::open(path).context(format!("Could not open archive: {}", path.display()))?;
+    let mut archive = zip::ZipArchive::new(io::BufReader::new(file))
+        .context(format!("Could not read archive: {}", path.display()))?;
+
+    let mut trees = vec![];
+    let mut entry_paths = HashMap::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let entry_path = PathBuf::from(entry.name());
+        if !entry.is_file() || !is_newick(&entry_path) {
+            continue;
+        }
+
+        let mut newick = String::new();
+        entry.read_to_string(&mut newick).context(format!(
+            "Could not read {} in archive: {}",
+            entry_path.display(),
+            path.display()
+        ))?;
+        let tree = from_newick(&newick).context(format!(
+            "Could not parse newick entry {} in archive: {}",
+            entry_path.display(),
+            path.display()
+        ))?;
+        let tree = check_tree(tree, &entry_path)?;
+
+        // Entries of nested directories may have the same id
+        let id = get_file_id(&entry_path, opts)?;
+        if let Some(other) = entry_paths.insert(id.clone(), entry_path.clone()) {
+            bail!(
+                "Entries {} and {} of archive {} have the same id: {id}",
+                other.display(),
+                entry_path.display(),
+                path.display()
+            )
+        }
+        trees.push((id, tree));
+    }
+
+    Ok(trees)
+}
+
+// Read a comparison tree, translating its tip labels if needed
+pub fn read_cmp_tree(treepath: &Path, opts: &ReadOpts) -> Result<(String, Tree, SystemTime)> {
+    let (id, mut tree, mtime) = read_tree(treepath, opts)?;
+    if let Some(tip_map) = opts.tip_map.as_ref() {
+        tip_map.relabel(&mut tree).context(format!(
+            "Could not translate tip labels of: {}",
+            treepath.display()
+        ))?;
+    }
+
+    Ok((id, tree, mtime))
+}
+
+// Load reference trees from a directory or a zip archive
+pub fn read_refs(ref_dir: &Path, opts: &ReadOpts) -> Result<HashMap<String, Tree>> {
+    if is_zip(ref_dir) {
+        Ok(zip_trees(ref_dir, opts)?.into_iter().collect())
+    } else {
+        trees_iter(ref_dir, opts)?
+            .map_ok(|(id, tree, _)| (id, tree))
+            .collect()
+    }
+}
+
+/// Id of the reference tree given on the command line
+pub const INLINE_REF_ID: &str = "inline_ref";
+
+// Parse the reference tree given on the command line
+pub fn read_inline_ref(newick: &str) -> Result<HashMap<String, Tree>> {
+    let tree = from_newick(newick).context("Could not parse the inline reference")?;
+    let tree = check_tree(tree, Path::new(INLINE_REF_ID))?;
+
+    Ok(HashMap::from([(INLINE_REF_ID.to_string(), tree)]))
 }
 
 // Iterate over newick files in a directory and parse them
-pub fn trees_iter(dir: &Path) -> Result<impl Iterator<Item = Result<(String, Tree)>>> {
+pub fn trees_iter(
+    dir: &Path,
+    opts: &ReadOpts,
+) -> Result<impl Iterator<Item = Result<(String, Tree, SystemTime)>>> {
+    let opts = opts.clone();
+    Ok(newick_paths(dir)?.map(move |p| read_tree(&p, &opts)))
+}
+
+// Iterate over paths of newick files in a directory
+pub fn newick_paths(dir: &Path) -> Result<impl Iterator<Item = PathBuf>> {
     Ok(fs::read_dir(dir)?
         .flatten()
         .map(|e| e.path())
-        .filter(|p| is_newick(p))
-        .map(|p| read_tree(&p)))
+        .filter(|p| is_newick(p)))
+}
+
+// Check if two paths point to the same file or directory
+pub fn same_path(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+// Check if two files have the same content. Files that cannot be read are
+// considered different, the error being reported when parsing them.
+pub fn identical_files(a: &Path, b: &Path) -> bool {
+    let len = |path: &Path| metadata(path).map(|m| m.len()).ok();
+    if len(a).is_none() || len(a) != len(b) {
+        return false;
+    }
+    match (fs::read(a), fs::read(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+// Iterate over paths of PHYLIP distance matrices in a directory
+pub fn phylip_paths(dir: &Path) -> Result<impl Iterator<Item = PathBuf>> {
+    Ok(fs::read_dir(dir)?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| is_phylip(p)))
+}
+
+// Read a PHYLIP distance matrix and extract its identifier
+pub fn read_matrix(path: &Path, opts: &ReadOpts) -> Result<(String, TipDistances)> {
+    let content = fs::read_to_string(path).context(format!(
+        "Could not read distance matrix: {}",
+        path.display()
+    ))?;
+    let dists = TipDistances::from_phylip(&content).context(format!(
+        "Could not parse distance matrix: {}",
+        path.display()
+    ))?;
+
+    Ok((get_file_id(path, opts)?, dists))
+}
+
+/// Whether a newick file could be parsed, written by `--validate-newick`
+#[derive(Debug, Default, Serialize)]
+pub struct ValidationRecord {
+    pub path: String,
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub marker: Option<String>,
+}
+
+// Read a list of tree ids, one per line
+pub fn read_ids(path: &Path) -> Result<HashSet<String>> {
+    let content =
+        fs::read_to_string(path).context(format!("Could not read ids file: {}", path.display()))?;
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// A comparison job: explicit paths of a reference and a comparison tree
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// Line of the job in the jobs file (1-based)
+    pub line: usize,
+    pub ref_path: PathBuf,
+    pub cmp_path: PathBuf,
+    pub id: String,
+}
+
+// Read comparison jobs: one job per line, with the reference tree path, the
+// comparison tree path and the job id separated by tabs
+pub fn read_jobs(path: &Path) -> Result<Vec<Job>> {
+    let content = fs::read_to_string(path)
+        .context(format!("Could not read jobs file: {}", path.display()))?;
+
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, line)| {
+            let fields: Vec<_> = line.split('\t').map(str::trim).collect();
+            let [ref_path, cmp_path, id] = fields[..] else {
+                bail!("Malformed job on line {}: {line}", i + 1)
+            };
+            Ok(Job {
+                line: i + 1,
+                ref_path: ref_path.into(),
+                cmp_path: cmp_path.into(),
+                id: id.into(),
+            })
+        })
+        .collect()
+}
+
+// Load the reference and comparison trees of a job, along with the
+// modification time of the comparison tree
+pub fn read_job(job: &Job, opts: &ReadOpts) -> Result<(String, Tree, Tree, SystemTime)> {
+    let context = || format!("Could not load job on line {} ({})", job.line, job.id);
+    let (_, reftree, _) = read_tree(&job.ref_path, opts).with_context(context)?;
+    let (_, cmptree, mtime) = read_cmp_tree(&job.cmp_path, opts).with_context(context)?;
+
+    Ok((job.id.clone(), reftree, cmptree, mtime))
 }
 
 // Add .gz extension to filepath if needed
@@ -91,8 +633,425 @@ pub fn init_writer(path: PathBuf, zipped: bool) -> Result<Box<dyn io::Write + 's
 }
 
 // Create CSV wrriter from IO writer
-pub fn from_writer<W: io::Write>(wtr: W) -> csv::Writer<W> {
-    csv::Writer::from_writer(wtr)
+pub fn from_writer(wtr: Box<dyn io::Write>, opts: &Arc<WriteOpts>) -> CsvWriter {
+    // Headers are written by the CsvWriter, which may rename them
+    CsvWriter {
+        inner: csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(wtr),
+        opts: opts.clone(),
+        header_written: false,
+        pending: HashMap::new(),
+        pending_header: None,
+    }
+}
+
+// Read clade definitions: one clade per line, with the clade name and a
+// comma separated list of tips separated by a tab
+pub fn read_clades(path: &Path) -> Result<Vec<Clade>> {
+    let content = fs::read_to_string(path)
+        .context(format!("Could not read clades file: {}", path.display()))?;
+
+    content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            let (name, tips) = line
+                .split_once('\t')
+                .context(format!("Malformed clade definition: {line}"))?;
+            Ok(Clade {
+                name: name.trim().into(),
+                tips: tips.split(',').map(|t| t.trim().to_string()).collect(),
+            })
+        })
+        .collect()
+}
+
+// Read the groups of tips: one tip per line, with its name and the name of
+// its group separated by a tab
+pub fn read_distance_groups(path: &Path) -> Result<HashMap<String, Arc<str>>> {
+    let content = fs::read_to_string(path).context(format!(
+        "Could not read tip groups file: {}",
+        path.display()
+    ))?;
+
+    content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            let (tip, group) = line
+                .split_once('\t')
+                .context(format!("Malformed tip group: {line}"))?;
+            Ok((tip.trim().to_string(), group.trim().into()))
+        })
+        .collect()
+}
+
+// Read split weights: one split per line, with a comma separated list of
+// the tips on one side of the split and its weight separated by a tab
+pub fn read_split_weights(path: &Path) -> Result<Vec<SplitWeight>> {
+    let content = fs::read_to_string(path).context(format!(
+        "Could not read split weights file: {}",
+        path.display()
+    ))?;
+
+    content
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            let (tips, weight) = line
+                .split_once('\t')
+                .context(format!("Malformed split weight: {line}"))?;
+            Ok(SplitWeight {
+                tips: tips.split(',').map(|t| t.trim().to_string()).collect(),
+                weight: weight
+                    .trim()
+                    .parse()
+                    .context(format!("Invalid weight in: {line}"))?,
+            })
+        })
+        .collect()
+}
+
+// Read a metadata table mapping comparison tree ids to reference tree ids,
+// from the two named columns of a csv file with a header
+pub fn read_ref_metadata(
+    path: &Path,
+    cmp_column: &str,
+    ref_column: &str,
+) -> Result<HashMap<String, String>> {
+    let mut reader = csv::Reader::from_path(path)
+        .context(format!("Could not open metadata: {}", path.display()))?;
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h == name)
+            .context(format!("No column {name} in metadata: {}", path.display()))
+    };
+    let (cmp_idx, ref_idx) = (column(cmp_column)?, column(ref_column)?);
+
+    reader
+        .records()
+        .map(|row| {
+            let row = row.context(format!("Could not read metadata: {}", path.display()))?;
+            Ok((row[cmp_idx].to_string(), row[ref_idx].to_string()))
+        })
+        .collect()
+}
+
+// Read the topology output of a previous run, compressed or not
+pub fn read_baseline(path: &Path) -> Result<HashMap<String, BaselineRecord>> {
+    let file = File::open(path).context(format!("Could not open baseline: {}", path.display()))?;
+    let reader: Box<dyn io::Read> = match path.extension().and_then(OsStr::to_str) {
+        Some("gz") => Box::new(MultiGzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+
+    csv::Reader::from_reader(reader)
+        .deserialize()
+        .map(|row| {
+            let row: BaselineRecord =
+                row.context(format!("Could not read baseline: {}", path.display()))?;
+            Ok((row.id.clone(), row))
+        })
+        .collect()
+}
+
+// Column names of a record, as written in the csv header
+fn header_of<S: Serialize>(record: &S) -> Result<Vec<String>> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.serialize(record)?;
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    let mut reader = csv::Reader::from_reader(bytes.as_slice());
+
+    Ok(reader.headers()?.iter().map(String::from).collect())
+}
+
+// All the columns of the csv outputs
+fn column_names() -> Result<HashSet<String>> {
+    let provenance = Provenance {
+        source_path: Some(String::new()),
+        mtime: Some(String::new()),
+        marker: None,
+    };
+    let with_provenance = |header: Vec<String>| -> Result<Vec<String>> {
+        Ok(header.into_iter().chain(header_of(&provenance)?).collect())
+    };
+    // Optional topology metrics only have a column when requested
+    let all_metrics = CompOpts {
+        align: true,
+        support_weighted_rf: Some(MissingSupport::One),
+        clade_rf: true,
+        prec_recall: true,
+        height_diff: true,
+        normalize_kf: true,
+        rf_null: Some(1),
+        rf_info: true,
+        mast: true,
+        split_weights: Some(vec![]),
+        allow_refinement: true,
+        shared_weighted_rf: true,
+        normalize_root: Some(RootNormalization::Bifurcate),
+        spr: true,
+        diameter_diff: true,
+        ..Default::default()
+    };
+    let topology = TopologyRecord {
+        best_ref_id: Some(String::new()),
+        threshold: Some(0.),
+        ..TopologyRecord::failed(Arc::default(), 0, &anyhow::anyhow!(""), &all_metrics)
+    };
+    let headers = [
+        with_provenance(header_of(&topology)?)?,
+        with_provenance(header_of(&BranchRecord::default())?)?,
+        with_provenance(header_of(&DistanceRecord {
+            group_1: Some("".into()),
+            group_2: Some("".into()),
+            dist_transform: Some(0.),
+            ..Default::default()
+        })?)?,
+        with_provenance(header_of(&DistanceSummaryRecord::default())?)?,
+        with_provenance(header_of(&CladeRecord::default())?)?,
+        with_provenance(header_of(&InternalNodeRecord::default())?)?,
+        with_provenance(header_of(&CanonicalRecord::default())?)?,
+        with_provenance(header_of(&CherryRecord::default())?)?,
+        with_provenance(header_of(&BranchRegressionRecord::default())?)?,
+        with_provenance(header_of(&CompatibilityRecord::default())?)?,
+        with_provenance(header_of(&StatusRecord::default())?)?,
+        with_provenance(header_of(&RootRecord::default())?)?,
+        with_provenance(header_of(&CoverageRecord::default())?)?,
+        with_provenance(header_of(&MetricRecord::default())?)?,
+        with_provenance(header_of(&DeltaRecord::default())?)?,
+        header_of(&ConsistencyRecord::default())?,
+        header_of(&RogueRecord::default())?,
+        header_of(&ValidationRecord::default())?,
+    ];
+
+    Ok(headers.into_iter().flatten().collect())
+}
+
+/// Read new names of columns of the csv outputs, from a JSON object mapping
+/// column names to header names. Unknown column names are reported as errors.
+pub fn read_column_names(path: &Path) -> Result<HashMap<String, String>> {
+    let file = File::open(path).context(format!("Could not open: {}", path.display()))?;
+    let names: HashMap<String, String> = serde_json::from_reader(io::BufReader::new(file))
+        .context(format!("Could not read column names: {}", path.display()))?;
+
+    let columns = column_names()?;
+    let unknown: Vec<_> = names
+        .keys()
+        .filter(|name| !columns.contains(*name))
+        .sorted()
+        .collect();
+    if !unknown.is_empty() {
+        bail!("Unknown columns to rename: {}", unknown.iter().join(", "))
+    }
+
+    Ok(names)
+}
+
+/// Round a float to a number of significant figures, if set
+pub fn round_float(value: f64, precision: Option<usize>) -> f64 {
+    match precision {
+        Some(digits) if value.is_finite() => format!("{value:.*e}", digits.saturating_sub(1))
+            .parse()
+            .unwrap_or(value),
+        _ => value,
+    }
+}
+
+// Round a float to the precision of the records being serialized
+fn round_output(value: f64) -> f64 {
+    current_opts(|opts| round_float(value, opts.and_then(|o| o.float_precision)))
+}
+
+/// Serialize a float field rounded to the output precision
+pub fn serialize_f64<S: Serializer>(value: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(round_output(*value))
+}
+
+/// Serialize an optional float field rounded to the output precision
+pub fn serialize_opt_f64<S: Serializer>(
+    value: &Option<f64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_some(&round_output(*value)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Version of the layout of the csv outputs, to bump whenever columns are
+/// added, removed or reordered
+pub const SCHEMA_VERSION: u32 = 15;
+
+// Number of pending row files created by this process, to name them
+static PENDING_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// Rows of a tree kept aside in a temporary file until the tree is committed.
+// The file is removed when dropped.
+struct PendingRows {
+    path: PathBuf,
+    writer: csv::Writer<File>,
+}
+
+impl PendingRows {
+    fn new() -> Result<Self> {
+        let n = PENDING_FILES.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("phylocompare-{}-{n}.pending", std::process::id()));
+        let file = File::create(&path).context(format!(
+            "Could not create pending rows file: {}",
+            path.display()
+        ))?;
+        let writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(file);
+
+        Ok(Self { path, writer })
+    }
+}
+
+impl Drop for PendingRows {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Csv writer, renaming header columns if requested
+pub struct CsvWriter {
+    inner: csv::Writer<Box<dyn io::Write>>,
+    opts: Arc<WriteOpts>,
+    header_written: bool,
+    // Rows of trees that are not fully compared yet, by tree id
+    pending: HashMap<String, PendingRows>,
+    // Header of the pending rows, written when the first tree is committed
+    pending_header: Option<Vec<String>>,
+}
+
+impl CsvWriter {
+    // Write the header, and the schema comment before it if requested
+    fn write_header(&mut self, header: &[String]) -> Result<()> {
+        // Nothing was written to the inner writer yet, so the comment comes first
+        if self.opts.schema_header {
+            writeln!(
+                self.inner.get_mut(),
+                "# phylocompare schema v{SCHEMA_VERSION}"
+            )?;
+        }
+        let names = self.opts.column_names.as_ref();
+        self.inner.write_record(
+            header
+                .iter()
+                .map(|h| names.and_then(|n| n.get(h)).unwrap_or(h)),
+        )?;
+        self.header_written = true;
+
+        Ok(())
+    }
+
+    /// Serialize a record, writing the header before the first record
+    pub fn serialize<S: Serialize>(&mut self, record: S) -> Result<()> {
+        let opts = self.opts.clone();
+        with_write_opts(&opts, || -> Result<()> {
+            if !self.header_written {
+                let header = header_of(&record)?;
+                self.write_header(&header)?;
+            }
+
+            Ok(self.inner.serialize(record)?)
+        })
+    }
+
+    /// Serialize a row of a tree that is not fully compared yet, with its
+    /// provenance. The rows of the tree are kept aside until it is committed,
+    /// so that the output only holds trees whose comparison succeeded.
+    pub fn serialize_pending<S: Serialize>(
+        &mut self,
+        id: &str,
+        record: S,
+        provenance: &Provenance,
+    ) -> Result<()> {
+        let opts = self.opts.clone();
+        with_write_opts(&opts, || -> Result<()> {
+            if !self.header_written && self.pending_header.is_none() {
+                self.pending_header = Some(header_of(&(&record, provenance))?);
+            }
+            let pending = match self.pending.entry(id.to_string()) {
+                Entry::Occupied(e) => e.into_mut(),
+                Entry::Vacant(e) => e.insert(PendingRows::new()?),
+            };
+
+            Ok(pending.writer.serialize((record, provenance))?)
+        })
+    }
+
+    /// Write the pending rows of a tree after the rows already written
+    pub fn commit(&mut self, id: &str) -> Result<()> {
+        let Some(mut pending) = self.pending.remove(id) else {
+            return Ok(());
+        };
+        pending.writer.flush()?;
+        if let (false, Some(header)) = (self.header_written, self.pending_header.take()) {
+            self.write_header(&header)?;
+        }
+
+        let context = || format!("Could not read pending rows: {}", pending.path.display());
+        let file = File::open(&pending.path).with_context(context)?;
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(io::BufReader::new(file));
+        for row in reader.byte_records() {
+            self.inner.write_byte_record(&row.with_context(context)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop the pending rows of a tree
+    pub fn discard(&mut self, id: &str) {
+        self.pending.remove(id);
+    }
+
+    /// Serialize a record of a tree followed by the columns of its
+    /// provenance. The csv serializer does not support flattened structs, so
+    /// both are written as a tuple, giving the same columns.
+    pub fn serialize_with_provenance<S: Serialize>(
+        &mut self,
+        record: S,
+        provenance: &Provenance,
+    ) -> Result<()> {
+        self.serialize((record, provenance))
+    }
+
+    /// Write rows of a previous output file with their header, if there are
+    /// any. Records serialized afterwards must have the same columns.
+    pub fn write_previous(
+        &mut self,
+        header: &csv::StringRecord,
+        rows: impl Iterator<Item = csv::StringRecord>,
+    ) -> Result<()> {
+        for row in rows {
+            if !self.header_written {
+                if self.opts.schema_header {
+                    writeln!(
+                        self.inner.get_mut(),
+                        "# phylocompare schema v{SCHEMA_VERSION}"
+                    )?;
+                }
+                self.inner.write_record(header)?;
+                self.header_written = true;
+            }
+            self.inner.write_record(&row)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 // Get output writer, zipped or not
@@ -100,9 +1059,10 @@ pub fn get_output(
     path: PathBuf,
     zipped: bool,
     is_some: bool,
-) -> Result<Option<csv::Writer<Box<dyn io::Write>>>> {
+    opts: &Arc<WriteOpts>,
+) -> Result<Option<CsvWriter>> {
     Ok(if is_some {
-        Some(from_writer(init_writer(path, zipped)?))
+        Some(from_writer(init_writer(path, zipped)?, opts))
     } else {
         None
     })
@@ -130,8 +1090,433 @@ pub fn get_suffixed_filenme(path: &PathBuf, suffix: &str, ext: &str, zip: bool)
         .to_str()
         .context("Could not convert output file name to string")?;
 
-    pb.set_file_name(format!("{stem_str}_{suffix}"));
-    pb.set_extension(ext);
+    // Suffixes can contain dots (e.g. tree ids), so the extension is not
+    // set with `set_extension`
+    pb.set_file_name(format!("{stem_str}_{suffix}.{ext}"));
 
     Ok(if zip { add_gz_ext(pb) } else { pb })
 }
+
+/// Fail if any of these output files already exists, unless they can be
+/// overwritten
+pub fn check_overwrite<P: AsRef<Path>>(
+    paths: impl IntoIterator<Item = P>,
+    force: bool,
+) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    let existing: Vec<_> = paths
+        .into_iter()
+        .filter(|path| path.as_ref().exists())
+        .map(|path| path.as_ref().display().to_string())
+        .collect();
+    if !existing.is_empty() {
+        bail!(
+            "Output files already exist, use --force to overwrite them: {}",
+            existing.join(", ")
+        )
+    }
+
+    Ok(())
+}
+
+/// Write a comparison record as a single nested JSON document, to
+/// `<prefix>_<id>.json`
+pub fn write_json_record(
+    prefix: &PathBuf,
+    record: &ComparisonRecord,
+    zipped: bool,
+    opts: &Arc<WriteOpts>,
+    force: bool,
+) -> Result<()> {
+    let path = get_suffixed_filenme(prefix, &record.id, "json", zipped)?;
+    check_overwrite([&path], force)?;
+
+    let mut writer = init_writer(path.clone(), zipped)?;
+    with_write_opts(opts, || serde_json::to_writer(&mut writer, record))
+        .context(format!("Could not write JSON record: {}", path.display()))?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Format a modification time as an ISO-8601 timestamp (UTC)
+pub fn format_mtime(mtime: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(mtime).to_string()
+}
+
+/// Output file of a previous run, moved aside to `<path>.previous` until its
+/// rows are copied to the new output file
+pub struct PreviousFile {
+    path: PathBuf,
+    zipped: bool,
+}
+
+impl PreviousFile {
+    // Move an output file aside. If a previous file is already there, a run
+    // updating the output stopped before copying its rows, so the output only
+    // holds part of them and the previous file is kept instead.
+    fn move_aside(path: &Path, zipped: bool) -> Result<Option<Self>> {
+        let mut previous = path.as_os_str().to_owned();
+        previous.push(".previous");
+        let previous = PathBuf::from(previous);
+
+        if !previous.exists() {
+            if !path.exists() {
+                return Ok(None);
+            }
+            fs::rename(path, &previous).context(format!(
+                "Could not move previous output: {}",
+                path.display()
+            ))?;
+        }
+
+        Ok(Some(Self {
+            path: previous,
+            zipped,
+        }))
+    }
+
+    /// Header and rows of the file, up to the first row that cannot be read:
+    /// the file may have been cut off when the run writing it stopped. A file
+    /// without a readable header has no rows.
+    pub fn rows(&self) -> Result<(csv::StringRecord, impl Iterator<Item = csv::StringRecord>)> {
+        let file = File::open(&self.path).context(format!(
+            "Could not read previous output: {}",
+            self.path.display()
+        ))?;
+        let reader: Box<dyn io::Read> = if self.zipped {
+            Box::new(MultiGzDecoder::new(io::BufReader::new(file)))
+        } else {
+            Box::new(io::BufReader::new(file))
+        };
+        let mut reader = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .from_reader(reader);
+        let header = reader.headers().ok().cloned();
+        let rows = header
+            .is_some()
+            .then(|| reader.into_records().map_while(Result::ok))
+            .into_iter()
+            .flatten();
+
+        Ok((header.unwrap_or_default(), rows))
+    }
+
+    // Remove the file once its rows were copied
+    fn remove(self) -> Result<()> {
+        fs::remove_file(&self.path).context(format!(
+            "Could not remove previous output: {}",
+            self.path.display()
+        ))
+    }
+}
+
+/// Output files of a previous run, by file suffix
+pub type PreviousRows = HashMap<&'static str, PreviousFile>;
+
+/// Ids of the trees with rows in the topology, branch and distance outputs of
+/// a previous run, to only compare each tree on the modalities it has no rows
+/// for yet
+pub struct Resume {
+    done: HashMap<&'static str, HashSet<String>>,
+}
+
+impl Resume {
+    /// Find the trees compared in a previous run from its rows. The rows of a
+    /// tree are written together once it is compared, so only the last tree
+    /// of each file may have been cut off when the run stopped: it is not
+    /// counted, so that it is compared again.
+    pub fn new(previous: &PreviousRows) -> Result<Self> {
+        let mut done = HashMap::new();
+        for (suffix, file) in previous {
+            let mut ids = HashSet::new();
+            let mut last: Option<String> = None;
+            for row in file.rows()?.1 {
+                let Some(id) = row.get(0) else { continue };
+                if last.as_deref() != Some(id) {
+                    ids.extend(last.replace(id.to_string()));
+                }
+            }
+            // An id written before the last tree may be written again by it
+            if let Some(last) = last {
+                ids.remove(&last);
+            }
+            done.insert(*suffix, ids);
+        }
+
+        Ok(Self { done })
+    }
+
+    /// Whether an output of the previous run has rows for a tree
+    pub fn has_rows(&self, suffix: &str, id: &str) -> bool {
+        self.done.get(suffix).is_some_and(|ids| ids.contains(id))
+    }
+
+    /// Options to compare a tree with, without the modalities it already has
+    /// rows for. `None` if the tree has rows for all of them.
+    pub fn opts_for<'a>(&self, id: &str, opts: &'a CompOpts) -> Option<Cow<'a, CompOpts>> {
+        let topology = opts.topology && !self.has_rows("topo", id);
+        let lengths = opts.lengths && !self.has_rows("brlen", id);
+        let distances = opts.distances && !self.has_rows("dist", id);
+
+        if !(topology || lengths || distances) {
+            None
+        } else if (topology, lengths, distances) == (opts.topology, opts.lengths, opts.distances) {
+            Some(Cow::Borrowed(opts))
+        } else {
+            Some(Cow::Owned(CompOpts {
+                topology,
+                lengths,
+                distances,
+                ..opts.clone()
+            }))
+        }
+    }
+}
+
+/// Output files for each of the comparison modalities
+#[derive(Default)]
+pub struct Outputs {
+    pub topo: Option<(PathBuf, CsvWriter)>,
+    pub brlen: Option<(PathBuf, CsvWriter)>,
+    pub dist: Option<(PathBuf, CsvWriter)>,
+    pub clades: Option<(PathBuf, CsvWriter)>,
+    pub internal: Option<(PathBuf, CsvWriter)>,
+    pub canonical: Option<(PathBuf, CsvWriter)>,
+    pub cherries: Option<(PathBuf, CsvWriter)>,
+    pub roots: Option<(PathBuf, CsvWriter)>,
+    pub regression: Option<(PathBuf, CsvWriter)>,
+    pub dist_summary: Option<(PathBuf, CsvWriter)>,
+    pub compat: Option<(PathBuf, CsvWriter)>,
+}
+
+impl Outputs {
+    // Suffixes of the output files, with whether they are enabled
+    fn suffixes(opts: &CompOpts) -> [(&'static str, bool); 11] {
+        [
+            ("dist", opts.distances),
+            ("topo", opts.topology),
+            ("brlen", opts.lengths),
+            (
+                "clades",
+                opts.clades.is_some() || opts.label_field.is_some(),
+            ),
+            ("internal", opts.internal_labels),
+            ("canonical", opts.canonical),
+            ("cherries", opts.cherries),
+            ("roots", opts.root_agreement),
+            ("regression", opts.branch_regression),
+            ("dist_summary", opts.distance_weight.is_some()),
+            ("compat", opts.compatibility),
+        ]
+    }
+
+    // Get the path of an output file, with whether it is compressed. Paths
+    // set explicitly are compressed if they end with `.gz`.
+    fn path(
+        prefix: &PathBuf,
+        group: Option<&str>,
+        suffix: &str,
+        zipped: bool,
+        write_opts: &WriteOpts,
+    ) -> Result<(PathBuf, bool)> {
+        if let (None, Some(path)) = (group, write_opts.output_paths.get(suffix)) {
+            let zipped = path.extension().and_then(OsStr::to_str) == Some("gz");
+            return Ok((path.clone(), zipped));
+        }
+        let suffix = match group {
+            Some(group) => format!("{group}_{suffix}"),
+            None => suffix.into(),
+        };
+        Ok((
+            get_suffixed_filenme(prefix, &suffix, "csv", zipped)?,
+            zipped,
+        ))
+    }
+
+    /// Fail if any of the enabled output files already exists
+    pub fn check_existing(
+        prefix: &PathBuf,
+        group: Option<&str>,
+        zipped: bool,
+        opts: &CompOpts,
+        write_opts: &WriteOpts,
+    ) -> Result<()> {
+        let paths = Self::suffixes(opts)
+            .into_iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(suffix, _)| Ok(Self::path(prefix, group, suffix, zipped, write_opts)?.0))
+            .collect::<Result<Vec<_>>>()?;
+
+        check_overwrite(paths, false)
+    }
+
+    /// Create the output files of the enabled modalities. If a group is
+    /// specified it is added to the file names, after the prefix. Existing
+    /// files are only overwritten if `force` is set.
+    pub fn new(
+        prefix: &PathBuf,
+        group: Option<&str>,
+        zipped: bool,
+        opts: &CompOpts,
+        write_opts: &Arc<WriteOpts>,
+        force: bool,
+    ) -> Result<Self> {
+        if !force {
+            Self::check_existing(prefix, group, zipped, opts, write_opts)?;
+        }
+
+        let [dist, topo, brlen, clades, internal, canonical, cherries, roots, regression, dist_summary, compat] =
+            Self::suffixes(opts).map(|(suffix, enabled)| {
+                let (path, zipped) = Self::path(prefix, group, suffix, zipped, write_opts)?;
+                Ok(get_output(path.clone(), zipped, enabled, write_opts)?.map(|w| (path, w)))
+            });
+
+        Ok(Self {
+            dist: dist?,
+            topo: topo?,
+            brlen: brlen?,
+            clades: clades?,
+            internal: internal?,
+            canonical: canonical?,
+            cherries: cherries?,
+            roots: roots?,
+            regression: regression?,
+            dist_summary: dist_summary?,
+            compat: compat?,
+        })
+    }
+
+    /// Move the enabled output files of a previous run aside, before they are
+    /// overwritten. Missing files are ignored.
+    pub fn read_previous(
+        prefix: &PathBuf,
+        zipped: bool,
+        opts: &CompOpts,
+        write_opts: &WriteOpts,
+    ) -> Result<PreviousRows> {
+        let mut previous = HashMap::new();
+        for (suffix, enabled) in Self::suffixes(opts) {
+            if !enabled {
+                continue;
+            }
+            let (path, zipped) = Self::path(prefix, None, suffix, zipped, write_opts)?;
+            if let Some(file) = PreviousFile::move_aside(&path, zipped)? {
+                previous.insert(suffix, file);
+            }
+        }
+
+        Ok(previous)
+    }
+
+    /// Write the rows of a previous run whose id (first column) satisfies
+    /// `keep` for the suffix of their file, before any new row. The previous
+    /// files are removed once their rows are flushed to the new outputs.
+    pub fn write_previous(
+        &mut self,
+        mut previous: PreviousRows,
+        keep: impl Fn(&str, &str) -> bool,
+    ) -> Result<()> {
+        for (suffix, output) in [
+            ("dist", &mut self.dist),
+            ("topo", &mut self.topo),
+            ("brlen", &mut self.brlen),
+            ("clades", &mut self.clades),
+            ("internal", &mut self.internal),
+            ("canonical", &mut self.canonical),
+            ("cherries", &mut self.cherries),
+            ("roots", &mut self.roots),
+            ("regression", &mut self.regression),
+            ("dist_summary", &mut self.dist_summary),
+            ("compat", &mut self.compat),
+        ] {
+            if let (Some((_, writer)), Some(file)) = (output, previous.remove(suffix)) {
+                let (header, rows) = file.rows()?;
+                writer.write_previous(
+                    &header,
+                    rows.filter(|row| row.get(0).is_some_and(|id| keep(suffix, id))),
+                )?;
+                writer.flush()?;
+                file.remove()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        for (_, writer) in [
+            &mut self.dist,
+            &mut self.brlen,
+            &mut self.topo,
+            &mut self.clades,
+            &mut self.internal,
+            &mut self.canonical,
+            &mut self.cherries,
+            &mut self.roots,
+            &mut self.regression,
+            &mut self.dist_summary,
+            &mut self.compat,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Write the streamed distances of a tree once it was fully compared
+    pub fn commit(&mut self, id: &str) -> Result<()> {
+        if let Some((_, writer)) = &mut self.dist {
+            writer.commit(id)?;
+        }
+        Ok(())
+    }
+
+    /// Drop the streamed distances of a tree whose comparison failed
+    pub fn discard(&mut self, id: &str) {
+        if let Some((_, writer)) = &mut self.dist {
+            writer.discard(id);
+        }
+    }
+
+    // Print where the outputs were written
+    pub fn report(&self) {
+        if let Some((path, _)) = &self.dist {
+            eprintln!("Wrote distance comparison to:  {}", path.display())
+        }
+        if let Some((path, _)) = &self.topo {
+            eprintln!("Wrote topology comparison to:  {}", path.display())
+        }
+        if let Some((path, _)) = &self.brlen {
+            eprintln!("Wrote branch   comparison to:  {}", path.display())
+        }
+        if let Some((path, _)) = &self.clades {
+            eprintln!("Wrote clade    comparison to:  {}", path.display())
+        }
+        if let Some((path, _)) = &self.internal {
+            eprintln!("Wrote internal comparison to:  {}", path.display())
+        }
+        if let Some((path, _)) = &self.canonical {
+            eprintln!("Wrote canonical comparison to: {}", path.display())
+        }
+        if let Some((path, _)) = &self.cherries {
+            eprintln!("Wrote cherry   comparison to:  {}", path.display())
+        }
+        if let Some((path, _)) = &self.roots {
+            eprintln!("Wrote root     comparison to:  {}", path.display())
+        }
+        if let Some((path, _)) = &self.regression {
+            eprintln!("Wrote branch   regression to:  {}", path.display())
+        }
+        if let Some((path, _)) = &self.dist_summary {
+            eprintln!("Wrote distance summary to:     {}", path.display())
+        }
+        if let Some((path, _)) = &self.compat {
+            eprintln!("Wrote clade compatibility to:  {}", path.display())
+        }
+    }
+}
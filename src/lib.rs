@@ -0,0 +1,52 @@
+//! Compare phylogenetic trees to reference trees: topologies, branch
+//! lengths and tip distances. This is the library behind the `phylocompare`
+//! binary, which reads trees from directories and compares them in
+//! parallel; [`compare_stream`] compares trees one at a time instead, for
+//! callers driving the comparison themselves.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use phylotree::tree::Tree;
+
+use comp::{CompOpts, ComparisonRecord};
+
+pub mod comp;
+pub mod dist;
+// pub mod csv;
+pub mod io;
+pub mod splits;
+pub mod transform;
+
+/// Find the reference of a comparison tree: the reference with the same id,
+/// or the one mapped to the tree in `ref_ids` if given
+pub fn find_reference<'a>(
+    refs: &'a HashMap<String, Tree>,
+    id: &str,
+    ref_ids: Option<&HashMap<String, String>>,
+) -> Option<(&'a String, &'a Tree)> {
+    let ref_id = match ref_ids {
+        Some(ref_ids) => ref_ids.get(id)?.as_str(),
+        None => id,
+    };
+    refs.get_key_value(ref_id)
+}
+
+/// Lazily compare each tree to its reference, in the order they are given.
+/// Trees are paired with references by id, and trees without a reference
+/// give an error. Comparisons run on the calling thread, one tree at a time
+/// as records are consumed.
+pub fn compare_stream<'a>(
+    refs: &'a HashMap<String, Tree>,
+    cmp_trees: impl IntoIterator<Item = (String, Tree)> + 'a,
+    opts: &'a CompOpts,
+) -> impl Iterator<Item = Result<ComparisonRecord>> + 'a {
+    cmp_trees.into_iter().map(move |(id, cmptree)| {
+        let (_, reftree) = find_reference(refs, &id, None)
+            .ok_or_else(|| anyhow!("No reference tree for: {id}"))?;
+        let record = comp::compare_trees(&id, reftree, &cmptree, opts, None, None)
+            .context(format!("Could not compare tree: {id}"))?;
+
+        Ok(*record)
+    })
+}
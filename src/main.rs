@@ -1,33 +1,154 @@
 use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fs,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use clap::Parser;
-use crossbeam_channel::unbounded;
-use indicatif::{ParallelProgressIterator, ProgressBar, ProgressStyle};
+use crossbeam_channel::bounded;
+use itertools::Itertools;
+use phylotree::tree::Tree;
 use rayon::prelude::*;
+use regex::Regex;
 
-mod comp;
-// mod csv;
-mod io;
+use phylocompare::{comp, dist, find_reference, io, transform};
+
+mod cache;
+mod condensed;
+mod dot;
+mod explain;
+mod manifest;
+mod nexus;
+mod npy;
+mod progress;
+mod sqlite;
+
+use cache::TaskTree;
+use comp::{
+    CompOpts, CompactBranchRecord, CompactDistanceRecord, ComparisonRecord, DeltaRecord, DepthSide,
+    DistanceRecord, DistanceSide, DistanceTransform, DistanceWeight, MetricFilter, MetricRecord,
+    MissingSupport, NanPolicy, RootNormalization, StatusRecord, TopologyRecord,
+};
+use progress::{Progress, ProgressFormat};
+use sqlite::SqliteOutput;
+
+// Maximum number of results waiting to be written
+const CHANNEL_CAPACITY: usize = 256;
 
 #[derive(Parser)]
 /// Compare trees to reference trees
 struct Cli {
-    /// Directory containing reference trees
-    ref_trees: PathBuf,
-    /// Directory containing trees to compare
+    /// Directory (or zip archive) containing reference trees (or the trees to compare when
+    /// using `--multi-ref` or `--self-consistency`)
+    #[arg(required_unless_present_any = ["jobs_file", "benchmark"])]
+    ref_trees: Option<PathBuf>,
+    /// Directories containing trees to compare. Tree ids must be unique
+    /// across directories.
     cmp_trees: Vec<PathBuf>,
+    /// Read comparison jobs from this file instead of matching trees of the
+    /// reference and comparison directories. Each line describes a job: the
+    /// path of the reference tree, the path of the comparison tree and the
+    /// job id, separated by tabs. Errors are reported with the job line.
+    #[arg(long, conflicts_with_all = ["ref_trees", "cmp_trees", "ref_cache"])]
+    jobs_file: Option<PathBuf>,
+    /// Compare each tree to every reference of this directory with the same
+    /// tips, and only output the comparison to the reference with the lowest
+    /// RF distance. The id of this reference is written to the `best_ref_id`
+    /// column of the topology output, which must be enabled. The only
+    /// positional argument is then the directory of trees to compare.
+    #[arg(long, conflicts_with_all = ["jobs_file", "cmp_trees"])]
+    multi_ref: Option<PathBuf>,
+    /// Compare every tree to this single reference tree, given as a newick
+    /// string, instead of reading references from a directory. The
+    /// reference id is `inline_ref`. The only positional argument is then
+    /// the directory of trees to compare.
+    #[arg(long, value_name = "NEWICK", conflicts_with_all = ["jobs_file", "cmp_trees", "multi_ref", "swap", "ref_metadata", "ref_cache", "cmp_matrices", "self_consistency", "validate_newick"])]
+    ref_newick: Option<String>,
+    /// Compare the reference trees to the PHYLIP distance matrices of this
+    /// directory instead of comparison trees, e.g. distances computed from an
+    /// alignment. Matrices are matched to references by their file stem and
+    /// only distances can be compared.
+    #[arg(long, conflicts_with_all = ["cmp_trees", "jobs_file", "multi_ref", "self_consistency", "sqlite", "group_regex", "npy", "dot", "nexus", "baseline", "tip_coverage", "rogue_taxa"])]
+    cmp_matrices: Option<PathBuf>,
+    /// Use the first directory of trees to compare as the reference directory,
+    /// and compare the trees of the reference directory to it. Reference and
+    /// comparison columns of the outputs follow the swapped roles.
+    #[arg(long, conflicts_with_all = ["jobs_file", "multi_ref", "self_consistency", "cmp_matrices"])]
+    swap: bool,
+    /// Read the trees of each directory to compare from its own thread
+    /// instead of reading directories one after the other, to overlap IO on
+    /// fast storage
+    #[arg(long, conflicts_with_all = ["jobs_file", "multi_ref", "swap", "cmp_matrices"])]
+    parallel_dirs: bool,
+    /// Number of leading dot-delimited segments of file stems forming tree
+    /// ids, e.g. 2 to get `genus.species` from `genus.species.aln.treefile`.
+    /// Ids are the first segment by default. A reference cache built with
+    /// another number of segments is read again.
+    #[arg(long, value_name = "N")]
+    id_segments: Option<usize>,
+    /// Pair comparison trees with references through this csv metadata table
+    /// instead of matching their ids. Each row maps the id of a comparison
+    /// tree to the id of its reference, read from the columns named with
+    /// `--metadata-cmp-column` and `--metadata-ref-column`.
+    #[arg(long, conflicts_with_all = ["jobs_file", "multi_ref"])]
+    ref_metadata: Option<PathBuf>,
+    /// Column of the metadata table holding comparison tree ids
+    #[arg(long, default_value = "cmp_id", requires = "ref_metadata")]
+    metadata_cmp_column: String,
+    /// Column of the metadata table holding reference tree ids
+    #[arg(long, default_value = "ref_id", requires = "ref_metadata")]
+    metadata_ref_column: String,
     /// Output file prefix that will be used for all output files
     #[arg(short, long)]
     output_prefix: PathBuf,
+    /// Write the topology comparison to this path instead of deriving it
+    /// from the output prefix. It is compressed if it ends with `.gz`.
+    #[arg(long, value_name = "PATH")]
+    topo_out: Option<PathBuf>,
+    /// Write the branch comparison to this path instead of deriving it from
+    /// the output prefix. It is compressed if it ends with `.gz`.
+    #[arg(long, value_name = "PATH")]
+    brlen_out: Option<PathBuf>,
+    /// Write the distance comparison to this path instead of deriving it
+    /// from the output prefix. It is compressed if it ends with `.gz`.
+    #[arg(long, value_name = "PATH")]
+    dist_out: Option<PathBuf>,
     /// Add `marker` columns to csv output with this constant.  
     /// If unset, the column will be empty in the output file
     #[arg(short, long)]
     marker: Option<String>,
+    /// Add the marker before or after the id of all output records, instead
+    /// of writing a marker column. Markers given as JSON objects are added
+    /// as their values in key order, joined with the separator.
+    #[arg(
+        long,
+        value_name = "POSITION",
+        requires = "marker",
+        conflicts_with_all = ["sqlite", "since_last_run"]
+    )]
+    marker_in_id: Option<io::MarkerPosition>,
+    /// Separator between the marker and the id, with `--marker-in-id`
+    #[arg(long, default_value = "_", requires = "marker_in_id")]
+    marker_separator: String,
+    /// Write comparisons to separate files according to a group extracted
+    /// from the tree id with this regex. The first capture group is used as
+    /// the group name (or the whole match if there are no capture groups).
+    /// Trees whose id does not match are written to the usual output files.
+    #[arg(long)]
+    group_regex: Option<Regex>,
+    /// Write comparisons to separate files according to a group extracted
+    /// from the id of the reference tree with this regex, as with
+    /// `--group-regex`. With `--multi-ref` the best reference is used.
+    #[arg(long, conflicts_with_all = ["group_regex", "sqlite"])]
+    partition_by_ref_group: Option<Regex>,
     /// Compare branch lengths instead of tree metrics
     #[arg(short, long)]
     lengths: bool,
@@ -35,24 +156,535 @@ struct Cli {
     /// used when the `--lengths` flag is specified)
     #[arg(short = 'i', long)]
     include_tips: bool,
+    /// Only compare terminal branches (leading to tips) in the branch
+    /// comparison, leaving out internal branches
+    #[arg(long, conflicts_with = "include_tips")]
+    tips_only_branches: bool,
+    /// Only output the N branches common to both trees with the largest
+    /// absolute length differences. Branches present in a single tree are
+    /// then left out of the branch output.
+    #[arg(long, value_name = "N")]
+    top_branch_diffs: Option<usize>,
+    /// Only output branches at least this deep (as reported in the depth
+    /// columns of the branch output)
+    #[arg(long)]
+    min_depth: Option<usize>,
+    /// Only output branches at most this deep
+    #[arg(long)]
+    max_depth: Option<usize>,
+    /// Which depths of branches common to both trees are checked against
+    /// `--min-depth` and `--max-depth`: the reference one, the comparison
+    /// one, both of them or either of them. Branches found in a single tree
+    /// are checked against their own depth.
+    #[arg(long, value_enum, default_value_t = DepthSide::Ref)]
+    depth_on: DepthSide,
     /// If specified compare pairwise distances
     #[arg(short, long)]
     distances: bool,
+    /// Compare topological distances (number of branches on the path between
+    /// two tips) instead of branch length distances. Distance columns then
+    /// hold integer values, and trees do not need branch lengths.
+    #[arg(long)]
+    topological_distances: bool,
+    /// Write the reference and comparison distance matrices of each tree as
+    /// NumPy .npy files (dense float64), named after the output prefix and
+    /// the tree id, with a JSON file listing the taxon of each row. Both
+    /// matrices follow the order of the reference tips, and are computed on
+    /// the trees as they are compared, e.g. after `--midpoint-root`, and
+    /// normalized with `--normalize-by-diameter`.
+    #[arg(long, conflicts_with = "multi_ref")]
+    npy: bool,
+    /// Write the distances of each tree in condensed form (the upper triangle
+    /// of the distance matrix, as in scipy) to a headerless csv file named
+    /// after the output prefix and the tree id, with the reference and
+    /// comparison distance of each pair of tips on a line, as for `--npy`. A
+    /// single `_condensed_index.csv` file maps line positions to pairs of
+    /// tips, so all trees must have the same tips.
+    #[arg(long, conflicts_with = "multi_ref")]
+    condensed_distances: bool,
+    /// Write each reference tree as a GraphViz DOT graph to `<DOT>/<id>.dot`,
+    /// with the branches whose split is missing from the comparison tree
+    /// highlighted in red. Trees are drawn after the transformations applied
+    /// before comparing them (e.g. `--keep-tips`, `--midpoint-root`).
+    #[arg(long, conflicts_with = "multi_ref")]
+    dot: Option<PathBuf>,
+    /// Write each reference tree as a Nexus file to `<NEXUS>/<id>.nex`, for
+    /// tree viewers such as FigTree. Each branch common to both trees is
+    /// annotated with the absolute difference of its lengths in a
+    /// `[&diff=...]` comment. Trees are written after the transformations
+    /// applied before comparing them.
+    #[arg(long, conflicts_with = "multi_ref")]
+    nexus: Option<PathBuf>,
+    /// Also write the comparison of each tree as a single JSON document named
+    /// after the output prefix and the tree id (`<prefix>_<id>.json`), with
+    /// the records of all compared modalities nested in it, along with the
+    /// marker and, if requested, the source path and modification time of
+    /// the tree. Distances are then kept in memory until the whole tree is
+    /// compared.
+    #[arg(long)]
+    json_per_tree: bool,
+    /// Write the branches and distances of each tree on a single row of
+    /// their outputs, each column holding a JSON array with one value per
+    /// branch or pair of tips (missing values are `null`), so that all
+    /// outputs have one row per tree. Distances are then kept in memory
+    /// until the whole tree is compared.
+    #[arg(long, conflicts_with_all = ["sqlite", "distance_groups"])]
+    compact_rows: bool,
+    /// Keep parsed trees in a temporary file instead of memory until they
+    /// are compared, trading disk IO for memory to compare more trees than
+    /// fit in memory. Reference trees shared by several trees are only
+    /// written once.
+    #[arg(long, conflicts_with_all = ["tip_coverage", "rogue_taxa"])]
+    spill_to_disk: bool,
     /// If specified compare topologies
     #[arg(short, long)]
     topology: bool,
+    /// Add the Align distance (Nye et al. 2006) to the topology comparison
+    #[arg(long)]
+    align: bool,
+    /// Only keep tips whose name matches this regex, pruning all other tips
+    /// from both trees before comparing them. The `n_tips` column of the
+    /// topology output then holds the number of retained tips.
+    #[arg(long)]
+    keep_tips: Option<Regex>,
+    /// Add an RF distance weighted by the support of reference splits to the
+    /// topology comparison. Supports are read from internal node labels of
+    /// the reference and divided by the highest support of the tree. Each
+    /// comparison split missing from the reference counts for 1.
+    #[arg(long)]
+    support_weighted_rf: bool,
+    /// Add an RF distance where splits are weighted by the weights of this
+    /// file to the topology comparison. Each line defines a split: a comma
+    /// separated list of the tips on one side of it and its weight,
+    /// separated by a tab. Each split found in only one of the trees counts
+    /// for its weight, or for 1 if it is not in the file.
+    #[arg(long)]
+    split_weights: Option<PathBuf>,
+    /// Add the RF distance on rooted clades to the topology comparison: the
+    /// number of clades (sets of tips below an internal node) found in only
+    /// one of the trees. Contrary to the RF distance on bipartitions, it
+    /// depends on the position of the root.
+    #[arg(long)]
+    clade_rf: bool,
+    /// Add the precision (fraction of splits of the compared tree found in
+    /// the reference), recall (fraction of reference splits recovered) and
+    /// F1 score of the splits to the topology comparison
+    #[arg(long)]
+    prec_recall: bool,
+    /// Add the height of both trees (distance from the root to the farthest
+    /// tip) and their difference (compared - reference) to the topology
+    /// comparison. Trees must have branch lengths.
+    #[arg(long)]
+    height_diff: bool,
+    /// Add the diameter of both trees (length of the longest path between
+    /// two tips) and their difference (compared - reference) to the topology
+    /// comparison. Unlike heights, diameters do not depend on where trees
+    /// are rooted. Trees must have branch lengths.
+    #[arg(long)]
+    diameter_diff: bool,
+    /// Add the KF score divided by the total branch length of the reference
+    /// to the topology comparison, as a scale-invariant companion to the raw
+    /// score. Reference trees must have branch lengths.
+    #[arg(long)]
+    normalize_kf: bool,
+    /// Add the clustering information distance (Smith, 2020), an information
+    /// theoretic generalization of the RF distance in bits, to the topology
+    /// comparison. Partially similar splits count less than completely
+    /// different ones.
+    #[arg(long)]
+    rf_info: bool,
+    /// Add the size of the maximum agreement subtree (the largest set of
+    /// tips on which both trees agree, as rooted) and this size divided by
+    /// the number of tips to the topology comparison. It is less sensitive
+    /// to a few misplaced tips than the RF distance, but takes quadratic
+    /// time and memory in the number of tips.
+    #[arg(long)]
+    mast: bool,
+    /// Add an approximate SPR distance to the topology comparison: the
+    /// number of tips greedily pruned from both trees, each time the one
+    /// leaving the smallest RF distance, until their unrooted topologies
+    /// agree. Exact SPR distances are NP-hard to compute; this one is an
+    /// upper bound, since each pruned tip can be moved back with one SPR
+    /// move. It takes O(k n³) time with `n` tips, `k` of them pruned.
+    #[arg(long)]
+    spr: bool,
+    /// Treat polytomies of the reference as unresolved relationships rather
+    /// than errors. Adds to the topology comparison an asymmetric RF
+    /// distance counting the reference splits missing from the compared
+    /// tree and the compared splits contradicting a reference split, but
+    /// not the compared splits that only resolve a polytomy of the
+    /// reference. `is_refinement` is true when this distance is 0, i.e. the
+    /// compared tree only refines the reference. The `rf` column keeps the
+    /// standard, symmetric RF distance.
+    #[arg(long)]
+    allow_refinement: bool,
+    /// Add to the topology comparison the sum of squared length differences
+    /// of the branches common to both trees, to measure length disagreement
+    /// on the shared topology only. Branches are selected as for the branch
+    /// comparison (see `--include-tips` and `--tips-only-branches`).
+    #[arg(long)]
+    shared_weighted_rf: bool,
+    /// Statistical add-on: add an empirical p-value of the RF distance to the
+    /// topology comparison, from this many references with randomly shuffled
+    /// tip labels (seeded with `--seed`). The p-value is the fraction of
+    /// shuffled references at least as close to the compared tree, counting
+    /// the observed one. This multiplies the cost of topology comparisons.
+    #[arg(long)]
+    rf_null: Option<usize>,
+    /// How to weight reference splits without support in the support
+    /// weighted RF: as 1, or report an error for the tree
+    #[arg(long, value_enum, default_value_t = MissingSupport::One)]
+    missing_support: MissingSupport,
+    /// Re-root both trees at the midpoint of their longest tip-to-tip path
+    /// before comparing them. This discards the original rooting of the
+    /// trees and requires branch lengths: trees without them are reported
+    /// as errors.
+    #[arg(long)]
+    midpoint_root: bool,
+    /// Rescale both trees so that their root-to-tip height is 1 before
+    /// comparing them (after midpoint rooting), to compare relative branch
+    /// lengths of trees on different time scales. All outputs hold the
+    /// rescaled lengths. Trees that are not ultrametric are rescaled by
+    /// their largest root-to-tip distance, with a warning.
+    #[arg(long)]
+    unit_height: bool,
+    /// Make the roots of both trees comparable before comparing them, as
+    /// trees differing only by a basal bifurcation or trifurcation have
+    /// different splits: resolve roots with more than two children into a
+    /// bifurcation, or collapse bifurcating roots into a trifurcation. The
+    /// root degrees of the trees as read are added to the topology output.
+    #[arg(long, value_enum)]
+    normalize_root: Option<RootNormalization>,
+    /// Check if the clades defined in this file are monophyletic in each
+    /// tree. Each line defines a clade: its name and a comma separated list
+    /// of tips, separated by a tab. Tips missing from a tree are ignored.
+    #[arg(long)]
+    clades_file: Option<PathBuf>,
+    /// Split tip labels on this character, to group tips by the field given
+    /// by `--label-field` (e.g. `|` for `species|location|date` labels)
+    #[arg(long, requires = "label_field")]
+    label_delimiter: Option<char>,
+    /// Check if the groups of tips sharing this field of their labels are
+    /// monophyletic in each tree, instead of clades read from a file. Fields
+    /// are counted from 1 and groups are defined on the reference tree, each
+    /// group being written as a clade named after the field. Tips with fewer
+    /// fields are ignored.
+    #[arg(long, requires = "label_delimiter", conflicts_with = "clades_file", value_parser = parse_label_field)]
+    label_field: Option<usize>,
+    /// Match labeled internal nodes between trees and check if the clades
+    /// below them agree. Unlabeled internal nodes are ignored, and labels
+    /// present on several nodes of a tree are reported without comparing
+    /// their clades.
+    #[arg(long)]
+    internal_labels: bool,
+    /// Check if trees are identical once written as canonical newick strings
+    /// (sorted children, trimmed labels, branch lengths and internal labels
+    /// ignored), and output a hash of each canonical string to help find
+    /// duplicates
+    #[arg(long)]
+    canonical_equal: bool,
+    /// Keep the labels of internal nodes (e.g. supports) in the canonical
+    /// newick strings of `--canonical-equal`
+    #[arg(long, requires = "canonical_equal")]
+    canonical_internal_labels: bool,
+    /// Count the cherries (pairs of tips that are the only two children of
+    /// their parent) of both trees, their difference (compared - reference)
+    /// and the number of cherries found in both trees. The children of a
+    /// polytomy do not form cherries.
+    #[arg(long)]
+    cherries: bool,
+    /// For trees with the same unrooted topology as their reference, check
+    /// if their roots are at the same position and count the branches
+    /// between both roots (a root with two children being in the middle of
+    /// a branch). Trees with a different unrooted topology have an empty
+    /// `same_root` and `root_distance`, as root positions cannot be matched.
+    #[arg(long)]
+    root_agreement: bool,
+    /// Write the least squares regression of the compared lengths of common
+    /// branches on their reference lengths, with one row per tree: the
+    /// number of branches used, slope, intercept and R². Branches are
+    /// selected as for the branch comparison, which does not need to be
+    /// enabled.
+    #[arg(long)]
+    branch_regression: bool,
+    /// Write the compatibility of each non-trivial split of the reference
+    /// with the compared tree, with one row per split keyed by a hash of its
+    /// clade (the side of the split without the first tip in alphabetical
+    /// order). A split is compatible if no split of the compared tree
+    /// conflicts with it, `n_conflicts` counting the ones that do.
+    #[arg(long)]
+    compatibility_matrix: bool,
+    /// Compare topologies after collapsing the branches of the compared tree
+    /// with a support lower than each of these comma separated thresholds
+    /// (e.g. `0,50,70,90`). Supports are read from internal node labels. The
+    /// topology output then has one row per tree and threshold, with a
+    /// `threshold` column.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = ["multi_ref", "baseline", "sqlite"]
+    )]
+    collapse_sweep: Vec<f64>,
+    /// Instead of comparing trees to references, compare the trees of each
+    /// group given by `--group-regex` with each other (e.g. replicates of a
+    /// dataset), and write the mean and median RF distance of each group to
+    /// a `_consistency.csv` file. The only positional argument is then the
+    /// directory of trees to compare.
+    #[arg(long, requires = "group_regex", conflicts_with_all = ["cmp_trees", "jobs_file", "multi_ref"])]
+    self_consistency: bool,
+    /// Only parse the newick files of the reference and comparison
+    /// directories (and of `--multi-ref`), without comparing them, and write
+    /// whether each file could be parsed, with the parsing error, to a
+    /// `_validation.csv` file
+    #[arg(long, conflicts_with_all = ["jobs_file", "self_consistency", "cmp_matrices"])]
+    validate_newick: bool,
+    /// Compare small built-in trees with all modalities and check the
+    /// results against known values, to make sure the program works without
+    /// providing any data. No files are read or written.
+    #[arg(long, exclusive = true)]
+    self_test: bool,
+    /// Measure the throughput of comparisons on this many pairs of random
+    /// trees generated in memory (seeded with `--seed`), instead of reading
+    /// trees. Trees are compared with the enabled modalities and written to
+    /// the outputs as in a normal run, then the number of trees compared per
+    /// second and the peak memory use are reported.
+    #[arg(long, value_name = "N_TREES", conflicts_with_all = ["ref_trees", "cmp_trees", "jobs_file", "multi_ref", "cmp_matrices", "self_consistency", "validate_newick", "sqlite"])]
+    benchmark: Option<usize>,
+    /// Number of tips of the random trees generated with `--benchmark`
+    #[arg(long, default_value_t = 100, requires = "benchmark")]
+    benchmark_tips: usize,
+    /// Print a plain English description of how a single tree differs from
+    /// its reference instead of writing outputs: the clades found in only
+    /// one of them, the RF distance they add up to and the shared branches
+    /// whose length changed the most. The reference and comparison
+    /// directories must each hold exactly one tree, compared whatever their
+    /// ids.
+    #[arg(
+        long,
+        conflicts_with_all = ["jobs_file", "multi_ref", "ref_newick", "cmp_matrices", "self_consistency", "validate_newick", "benchmark"]
+    )]
+    explain: bool,
     /// If specified compare branches
     #[arg(short, long)]
     branches: bool,
     /// Compare everything: topology, branches and pairwise distances.
     #[arg(short, long)]
     all: bool,
-    /// Exit the program early on error instead of listing them at the end
+    /// Exit the program early on error instead of listing them at the end.
+    /// Without this flag, the run keeps going and ends with a tally of the
+    /// trees that were compared, failed, missing a reference or skipped.
     #[arg(short, long)]
     strict: bool,
+    /// How to handle tip pairs missing from one of the distance matrices:
+    /// write them as NaN, drop them, or report an error for the tree
+    #[arg(long, value_enum, default_value_t = NanPolicy::Emit)]
+    nan_policy: NanPolicy,
+    /// Only write tip pairs whose distance is at most this value to the
+    /// distance output. Pairs with a missing (NaN) distance are kept.
+    #[arg(long)]
+    max_distance: Option<f64>,
+    /// Which distances are checked against `--max-distance`: the reference
+    /// one, the comparison one, both of them or either of them
+    #[arg(long, value_enum, default_value_t = DistanceSide::Ref, requires = "max_distance")]
+    max_distance_on: DistanceSide,
+    /// Only write distances between tips of different groups, the groups of
+    /// both tips being added as `group_1` and `group_2` columns. Each line of
+    /// the file assigns a tip to a group: the tip name and the group name,
+    /// separated by a tab. Pairs with a tip without a group are skipped.
+    #[arg(long, conflicts_with = "sqlite")]
+    distance_groups: Option<PathBuf>,
+    /// Add a `dist_transform` column to the distance output, derived from
+    /// both distances of each pair. Undefined values (a ratio to a reference
+    /// distance of 0, the log ratio of distances that are not positive) and
+    /// missing distances give NaN.
+    #[arg(long, value_enum, conflicts_with = "sqlite")]
+    distance_transform: Option<DistanceTransform>,
+    /// Divide the distances between tips of each tree by the diameter of
+    /// that tree (its largest distance between two tips), so that distances
+    /// are between 0 and 1 and comparable across trees of different scales.
+    /// This applies before `--max-distance` and `--distance-transform`.
+    /// Distances of a tree with a diameter of 0 are left at 0.
+    #[arg(long)]
+    normalize_by_diameter: bool,
+    /// Summarize the distance comparison of each tree in a `_dist_summary`
+    /// output, with the Pearson correlation and the root mean squared
+    /// difference of the compared distances, weighting each pair of tips by
+    /// its reference distance `d`: 1 (uniform), 1 / d (inverse) or 1 / d²
+    /// (inverse-sq). With inverse weights, pairs with d = 0 are left out and
+    /// counted in `n_zero_dist`. Missing distances are always left out.
+    #[arg(long, value_enum, value_name = "SCHEME", conflicts_with = "sqlite")]
+    distance_weight: Option<DistanceWeight>,
+    /// Memory-map newick files at least this large (in bytes) instead of
+    /// reading them through buffered IO (0 = never memory-map). Reading
+    /// files through a memory map is as fast from 256 KiB, and faster from
+    /// 1 MiB on (see the Benchmarking section of the README), hence the
+    /// default of 1 MiB.
+    #[arg(long, default_value_t = 1024 * 1024)]
+    mmap_threshold: u64,
+    /// Retry reading a tree file this many times on transient IO errors
+    /// (e.g. EIO on networked filesystems), waiting a bit longer before each
+    /// attempt. Malformed newick files are never retried.
+    #[arg(long, default_value_t = 0)]
+    io_retries: u32,
+    /// Translate the tip labels of comparison trees with this table before
+    /// comparing them. Each line holds a comparison label and the matching
+    /// reference label, separated by a tab.
+    #[arg(long)]
+    tip_map: Option<PathBuf>,
+    /// What to do with tips of comparison trees missing from the tip map:
+    /// keep their label, or report an error for the tree
+    #[arg(long, value_enum, default_value_t = io::UnmappedTips::Keep)]
+    unmapped_tips: io::UnmappedTips,
+    /// File listing ids of trees to skip, one per line. These trees are not
+    /// parsed and are reported separately from errors and missing references
+    #[arg(long)]
+    skip_ids: Option<PathBuf>,
+    /// Fail instead of warning when the reference and comparison trees are
+    /// read from the same directory, or when comparison tree files are
+    /// identical to the files of their reference
+    #[arg(long)]
+    no_self_compare: bool,
+    /// Manifest of the tree files compared by a previous run, with their
+    /// modification time and checksum, and the checksums of their reference
+    /// and of the comparison options. Only trees whose file, reference or
+    /// options changed since that run are compared again, the rows of the
+    /// other trees being kept
+    /// from the previous outputs, which must have the same columns. The
+    /// manifest is then updated, or created if it does not exist. With a jobs
+    /// file, only the files of the trees to compare are checked.
+    #[arg(
+        long,
+        value_name = "MANIFEST",
+        conflicts_with_all = ["sqlite", "group_regex", "partition_by_ref_group", "sample_fraction", "self_consistency", "validate_newick", "cmp_matrices"]
+    )]
+    since_last_run: Option<PathBuf>,
+    /// Resume an interrupted run writing to the same outputs: each tree is
+    /// only compared on the modalities (topology, branches, distances) whose
+    /// output has no rows for it yet. The rows of the last tree of each
+    /// output are dropped and computed again, in case they were cut off.
+    /// Previous outputs are moved to `<path>.previous` until their rows are
+    /// copied, and read from there if a run stopped before that.
+    /// Other modalities cannot be resumed.
+    #[arg(
+        long,
+        conflicts_with_all = ["since_last_run", "sqlite", "group_regex", "partition_by_ref_group", "split_fraction", "marker_in_id", "cmp_matrices", "self_consistency", "validate_newick", "benchmark", "explain"]
+    )]
+    resume: bool,
+    /// Only compare a random sample of roughly this fraction of the trees
+    /// paired with a reference (between 0 and 1). Trees are sampled from a
+    /// hash of their id and of the seed, so a sample is reproducible given
+    /// the seed. Trees left out are not reported as errors.
+    #[arg(long, value_parser = parse_fraction)]
+    sample_fraction: Option<f64>,
+    /// Randomly split the comparisons into `train` and `test` output files,
+    /// with roughly this fraction of the trees (between 0 and 1) in `train`.
+    /// Trees are assigned from a hash of their id and of the seed, so all
+    /// the rows of a tree are in the same split and splits are reproducible
+    /// given the seed.
+    #[arg(
+        long,
+        value_parser = parse_fraction,
+        conflicts_with_all = ["group_regex", "partition_by_ref_group", "sqlite", "since_last_run"]
+    )]
+    split_fraction: Option<f64>,
+    /// Seed used to sample trees with `--sample-fraction`, to split them
+    /// with `--split-fraction` and to shuffle references with `--rf-null`
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Write a tip coverage report of the comparison trees, including the
+    /// ones without a reference: the number of tips of each tree, how many
+    /// tips of the union of all trees it is missing, and the sizes of the
+    /// union and intersection of tips.
+    #[arg(long)]
+    tip_coverage: bool,
+    /// Rank the tips of the trees by how often they sit in disagreeing
+    /// positions relative to the reference: for each tip, the decrease of
+    /// the RF distance when it is pruned from both trees, averaged over the
+    /// trees where it appears. Tips are written to a `_rogues.csv` file,
+    /// rogue taxa first. Trees are used as they are read, and must have the
+    /// same tips as their reference.
+    #[arg(long, conflicts_with = "multi_ref")]
+    rogue_taxa: bool,
+    /// Topology output of a previous run. The differences in rf, norm_rf
+    /// and kf_score between each tree and its row with the same id in this
+    /// file are written to a `_delta.csv` file. Ids present in only one of
+    /// the runs are reported at the end.
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+    /// Also write the topology metrics in long format to a `_metrics.csv`
+    /// file, with one row per tree and metric (`id, metric, value, marker`),
+    /// e.g. for faceted plots
+    #[arg(long, conflicts_with_all = ["sqlite", "collapse_sweep"])]
+    long_metrics: bool,
+    /// Only write topology rows satisfying this predicate on a metric of
+    /// the topology output, e.g. `norm_rf>0.3`. Supported operators are >,
+    /// >=, <, <=, == and !=. When given several times, rows must satisfy all
+    /// predicates. Rows where the metric was not computed are left out, and
+    /// other outputs are not filtered.
+    #[arg(long)]
+    filter: Vec<MetricFilter>,
+    /// Cache the parsed reference trees in this file. If the cache is newer
+    /// than all the reference trees and was built with the same read options
+    /// (`--id-segments`, `--tip-map`), it is loaded instead of parsing them
+    /// again, otherwise it is rebuilt.
+    #[arg(long)]
+    ref_cache: Option<PathBuf>,
+    /// How to report progress: interactive bars, periodic plain text lines,
+    /// periodic JSON events or nothing
+    #[arg(long, value_enum, default_value_t = ProgressFormat::Bar)]
+    progress_format: ProgressFormat,
+    /// Print the total run time and the time spent in each phase at the end
+    /// of the run. Trees are compared while outputs are written, so the time
+    /// spent writing is included in the comparison time.
+    #[arg(long)]
+    timings: bool,
     /// Number of threads to use in parallel (0 = all available threads)
     #[arg(long, default_value_t = 0)]
     threads: usize,
+    /// Overwrite output files if they already exist
+    #[arg(long)]
+    force: bool,
+    /// Write topology, branch and distance comparisons to tables of a new
+    /// SQLite database at this path instead of csv files. Tables have the
+    /// same columns as the csv files (see README for the schema).
+    #[arg(long, conflicts_with = "group_regex")]
+    sqlite: Option<PathBuf>,
+    /// Add an `mtime` column to the csv outputs with the modification time
+    /// of the compared tree file, as an ISO-8601 timestamp (UTC)
+    #[arg(long, conflicts_with = "sqlite")]
+    include_mtime: bool,
+    /// Add a `source_path` column to the csv outputs with the path of the
+    /// compared tree file, to trace rows back to their file when ids collide
+    #[arg(long, conflicts_with = "sqlite")]
+    include_path: bool,
+    /// Write a topology row for trees that could not be compared, with NaN
+    /// metrics and the error message in an added `error` column, so that
+    /// every tree read is represented in the outputs. These rows are kept by
+    /// `--filter` and left out of the `--long-metrics` and `--baseline`
+    /// outputs. Errors are still reported at the end of the run.
+    #[arg(long, conflicts_with_all = ["sqlite", "collapse_sweep"])]
+    emit_failures: bool,
+    /// Write the outcome of each compared tree to this csv file: whether its
+    /// topology, branch and distance comparisons succeeded (empty if not
+    /// enabled) and the error message of the failed ones. Trees that could
+    /// not be read or paired with a reference are not compared and are only
+    /// reported at the end of the run.
+    #[arg(long, value_name = "PATH")]
+    status_log: Option<PathBuf>,
+    /// Rename columns of the csv outputs with a JSON object mapping column
+    /// names to the names to write in the headers, e.g. `{"rf":
+    /// "robinson_foulds"}`. Unknown column names are reported as errors.
+    #[arg(long)]
+    rename_columns: Option<PathBuf>,
+    /// Round floats of the csv outputs (topology metrics, branch lengths,
+    /// distances...) to this number of significant figures, to make the
+    /// files smaller. Floats are written with full precision by default.
+    #[arg(long, value_name = "N")]
+    float_precision: Option<usize>,
+    /// Start each csv output with a `# phylocompare schema v<N>` comment line
+    /// identifying its column layout, for parsers to detect format changes
+    #[arg(long)]
+    schema_header: bool,
     /// Do not compress output csv using gzip
     #[arg(short, long)]
     no_compression: bool,
@@ -60,165 +692,1931 @@ struct Cli {
 
 fn main() -> Result<()> {
     let args = Cli::parse();
+    let start = Instant::now();
+
+    if args.float_precision == Some(0) {
+        bail!("The float precision must be at least 1 significant figure")
+    }
+    if args.id_segments == Some(0) {
+        bail!("Ids must be made of at least 1 segment")
+    }
+    let mut write_opts = io::WriteOpts {
+        float_precision: args.float_precision,
+        column_names: args
+            .rename_columns
+            .as_deref()
+            .map(io::read_column_names)
+            .transpose()?,
+        schema_header: args.schema_header,
+        output_paths: [
+            ("topo", &args.topo_out),
+            ("brlen", &args.brlen_out),
+            ("dist", &args.dist_out),
+        ]
+        .into_iter()
+        .filter_map(|(suffix, path)| Some((suffix, path.clone()?)))
+        .collect(),
+        ..Default::default()
+    };
+    if let (Some(position), Some(marker)) = (args.marker_in_id, args.marker.as_deref()) {
+        write_opts.set_id_marker(marker, position, &args.marker_separator);
+    }
+    let write_opts = Arc::new(write_opts);
 
     // Build thread-pool
     rayon::ThreadPoolBuilder::new()
         .num_threads(args.threads)
         .build_global()?;
 
+    if args.self_test {
+        return self_test();
+    }
+
     // Check that we have trees to compare to reference
-    if args.cmp_trees.is_empty() {
+    if args.jobs_file.is_none()
+        && args.multi_ref.is_none()
+        && args.ref_newick.is_none()
+        && !args.self_consistency
+        && !args.validate_newick
+        && args.cmp_matrices.is_none()
+        && args.benchmark.is_none()
+        && args.cmp_trees.is_empty()
+    {
         bail!("You must specify at least 1 directory to compare to the reference");
     }
 
-    // Check that ref_trees is a directory
-    io::check_dir(&args.ref_trees)?;
+    // Check that ref_trees is a directory, references can only be read from
+    // a zip archive when paired with comparison trees
+    if let Some(ref_dir) = args.ref_trees.as_deref() {
+        io::check_dir(ref_dir, !args.self_consistency)?;
+    }
+    if let Some(pool_dir) = args.multi_ref.as_deref() {
+        io::check_dir(pool_dir, false)?;
+    }
+    if let Some(matrix_dir) = args.cmp_matrices.as_deref() {
+        io::check_dir(matrix_dir, false)?;
+    }
 
     // Set up comparison mode
     let compare_topo = args.topology || args.all;
     let compare_lens = args.lengths || args.all;
     let compare_dist = args.distances || args.all;
 
-    if !compare_topo && !compare_lens && !compare_dist {
+    let opts = CompOpts {
+        topology: compare_topo,
+        lengths: compare_lens,
+        distances: compare_dist,
+        include_tips: args.include_tips,
+        tips_only_branches: args.tips_only_branches,
+        nan_policy: args.nan_policy,
+        align: args.align,
+        midpoint_root: args.midpoint_root,
+        unit_height: args.unit_height,
+        normalize_root: args.normalize_root,
+        clades: args
+            .clades_file
+            .as_deref()
+            .map(io::read_clades)
+            .transpose()?,
+        label_field: args
+            .label_delimiter
+            .zip(args.label_field)
+            .map(|(delimiter, field)| comp::LabelField { delimiter, field }),
+        internal_labels: args.internal_labels,
+        keep_tips: args.keep_tips.clone(),
+        topological_distances: args.topological_distances,
+        support_weighted_rf: args.support_weighted_rf.then_some(args.missing_support),
+        split_weights: args
+            .split_weights
+            .as_deref()
+            .map(io::read_split_weights)
+            .transpose()?,
+        canonical: args.canonical_equal,
+        canonical_internal_labels: args.canonical_internal_labels,
+        cherries: args.cherries,
+        root_agreement: args.root_agreement,
+        branch_regression: args.branch_regression,
+        compatibility: args.compatibility_matrix,
+        top_branch_diffs: args.top_branch_diffs,
+        clade_rf: args.clade_rf,
+        prec_recall: args.prec_recall,
+        height_diff: args.height_diff,
+        diameter_diff: args.diameter_diff,
+        normalize_kf: args.normalize_kf,
+        max_distance: args.max_distance,
+        max_distance_on: args.max_distance_on,
+        distance_transform: args.distance_transform,
+        normalize_by_diameter: args.normalize_by_diameter,
+        distance_weight: args.distance_weight,
+        distance_groups: args
+            .distance_groups
+            .as_deref()
+            .map(io::read_distance_groups)
+            .transpose()?,
+        rf_null: args.rf_null,
+        seed: args.seed,
+        min_depth: args.min_depth,
+        max_depth: args.max_depth,
+        depth_on: args.depth_on,
+        rf_info: args.rf_info,
+        mast: args.mast,
+        spr: args.spr,
+        allow_refinement: args.allow_refinement,
+        shared_weighted_rf: args.shared_weighted_rf,
+        collapse_sweep: (!args.collapse_sweep.is_empty()).then(|| args.collapse_sweep.clone()),
+    };
+
+    if args.multi_ref.is_some() && !opts.topology {
+        bail!("Comparing to multiple references requires comparing topologies")
+    }
+
+    if args.cmp_matrices.is_some()
+        && (!opts.distances
+            || opts.topology
+            || opts.lengths
+            || opts.clades.is_some()
+            || opts.label_field.is_some()
+            || opts.internal_labels
+            || opts.canonical
+            || opts.cherries
+            || opts.root_agreement
+            || opts.branch_regression
+            || opts.compatibility)
+    {
+        bail!("Distance matrices can only be compared with --distances")
+    }
+
+    if opts.distance_weight.is_some() && !opts.distances {
+        bail!("Summarizing distances requires comparing distances")
+    }
+
+    if opts.normalize_by_diameter && !opts.distances {
+        bail!("Normalizing distances by tree diameter requires comparing distances")
+    }
+
+    if args.resume
+        && (opts.clades.is_some()
+            || opts.label_field.is_some()
+            || opts.internal_labels
+            || opts.canonical
+            || opts.cherries
+            || opts.root_agreement
+            || opts.branch_regression
+            || opts.compatibility
+            || opts.distance_weight.is_some())
+    {
+        bail!("Only topology, branch and distance comparisons can be resumed")
+    }
+
+    if args.baseline.is_some() && !opts.topology {
+        bail!("Comparing to a baseline requires comparing topologies")
+    }
+
+    if args.emit_failures && !opts.topology {
+        bail!("Writing rows of failed comparisons requires comparing topologies")
+    }
+
+    if args.long_metrics && !opts.topology {
+        bail!("Writing metrics in long format requires comparing topologies")
+    }
+
+    if !opts.has_modality()
+        && !args.npy
+        && !args.condensed_distances
+        && args.dot.is_none()
+        && args.nexus.is_none()
+        && !args.self_consistency
+        && !args.validate_newick
+        && !args.explain
+    {
         bail!(
-            "You must specify at least one modality to compare: topology, branches, lengths or all"
+            "You must specify at least one modality to compare: topology, branches, lengths, clades, internal labels, canonical, cherries, root agreement, branch regression, compatibility matrix or all"
         )
     }
 
-    // Read reference trees
-    let ref_trees = io::read_refs(&args.ref_trees)?;
-    eprintln!("Reference trees loaded: {}", ref_trees.len());
+    let read_opts = io::ReadOpts {
+        mmap_threshold: args.mmap_threshold,
+        io_retries: args.io_retries,
+        tip_map: args
+            .tip_map
+            .as_deref()
+            .map(|path| io::TipMap::from_file(path, args.unmapped_tips))
+            .transpose()?
+            .map(Arc::new),
+        id_segments: args.id_segments,
+    };
 
-    // init output files
     let zipped = !args.no_compression;
-    let dist_path = io::get_suffixed_filenme(&args.output_prefix, "dist", "csv", zipped)?;
-    let mut dist_writer = io::get_output(dist_path.clone(), zipped, compare_dist)?;
+    if args.self_consistency {
+        return self_consistency(&args, &read_opts, &write_opts, zipped);
+    }
+    if args.validate_newick {
+        return validate_newick(&args, &read_opts, &write_opts, zipped);
+    }
+    if args.explain {
+        return explain_pair(&args, &read_opts, &write_opts);
+    }
 
-    let topo_path = io::get_suffixed_filenme(&args.output_prefix, "topo", "csv", zipped)?;
-    let mut topo_writer = io::get_output(topo_path.clone(), zipped, compare_topo)?;
+    // Outputs of the previous run are updated rather than overwritten
+    let mut manifest = args
+        .since_last_run
+        .as_deref()
+        .map(|path| manifest::Manifest::read(path, manifest::options_checksum(&opts, &read_opts)))
+        .transpose()?;
+    let previous_rows = match manifest.as_ref() {
+        Some(manifest) if manifest.has_previous() => Some(io::Outputs::read_previous(
+            &args.output_prefix,
+            zipped,
+            &opts,
+            &write_opts,
+        )?),
+        _ if args.resume => Some(io::Outputs::read_previous(
+            &args.output_prefix,
+            zipped,
+            &opts,
+            &write_opts,
+        )?),
+        _ => None,
+    };
+    let resume = match previous_rows.as_ref() {
+        Some(rows) if args.resume => Some(io::Resume::new(rows)?),
+        _ => None,
+    };
 
-    let brlen_path = io::get_suffixed_filenme(&args.output_prefix, "brlen", "csv", zipped)?;
-    let mut brlen_writer = io::get_output(brlen_path.clone(), zipped, compare_lens)?;
+    let overwrite = args.force || previous_rows.is_some();
+
+    // Check for existing outputs before doing any work
+    if !overwrite && args.sqlite.is_none() {
+        let groups = match args.split_fraction {
+            Some(_) => vec![Some("train"), Some("test")],
+            None => vec![None],
+        };
+        for group in groups {
+            io::Outputs::check_existing(&args.output_prefix, group, zipped, &opts, &write_opts)?;
+        }
+    }
+
+    let skip_ids = match args.skip_ids.as_deref() {
+        Some(path) => io::read_ids(path)?,
+        None => HashSet::new(),
+    };
+
+    if let Some(matrix_dir) = args.cmp_matrices.as_deref() {
+        return compare_matrices(
+            &args,
+            matrix_dir,
+            &opts,
+            &read_opts,
+            &write_opts,
+            &skip_ids,
+            zipped,
+        );
+    }
+
+    // init output files
+    let new_outputs = |group: Option<&str>| {
+        io::Outputs::new(
+            &args.output_prefix,
+            group,
+            zipped,
+            &opts,
+            &write_opts,
+            overwrite,
+        )
+    };
+    let mut sqlite = args
+        .sqlite
+        .as_deref()
+        .map(|path| SqliteOutput::new(path, &opts, args.force))
+        .transpose()?;
+    // No csv files are written when writing to a database, or only to the
+    // files of the splits
+    let mut outputs = match sqlite {
+        Some(_) => io::Outputs::default(),
+        None if args.split_fraction.is_some() => io::Outputs::default(),
+        None => new_outputs(None)?,
+    };
+    // Group outputs are created lazily as new groups are encountered
+    let mut group_outputs: HashMap<String, io::Outputs> = HashMap::new();
+
+    // On SIGINT/SIGTERM, stop comparing new trees so that outputs of the
+    // trees already compared are flushed and finalized. A second signal
+    // exits immediately.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_flag = interrupted.clone();
+    ctrlc::set_handler(move || {
+        if handler_flag.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, finishing the trees being compared...");
+    })
+    .context("Could not set signal handler")?;
 
     let mut errors = vec![];
+    let mut warnings = vec![];
     let mut not_found = vec![];
+    // Comparison trees without a reference, only kept for the tip coverage
+    let mut unpaired = vec![];
+    let mut skipped = vec![];
+    let mut unchanged = HashSet::new();
+    let mut ref_checksums = manifest::RefChecksums::default();
+    // Trees with rows for all modalities in the outputs being resumed
+    let mut resumed = vec![];
+    // Comparison tree files identical to the file of their reference
+    let mut identical = vec![];
     let mut pairs = vec![];
+    let mut pool = None;
+    // Trees waiting to be compared are written there if requested
+    let spill = args.spill_to_disk.then(cache::SpillFile::new).transpose()?;
+    // Paths of comparison tree files are only kept if requested
+    let source_path = |path: &Path| args.include_path.then(|| path.display().to_string());
+    // Reference ids of comparison trees, to partition outputs by reference
+    let mut ref_ids: HashMap<String, String> = HashMap::new();
+    // Modification times of comparison trees, if requested
+    let mut mtimes: HashMap<String, String> = HashMap::new();
+
+    let ref_metadata = args
+        .ref_metadata
+        .as_deref()
+        .map(|path| {
+            io::read_ref_metadata(path, &args.metadata_cmp_column, &args.metadata_ref_column)
+        })
+        .transpose()?;
 
     // Load tree pairs
-    let spinner = init_spinner(ref_trees.len() as u64);
-    spinner.set_message("Loading Trees");
-    for pair in io::trees_iter(&args.cmp_trees[0])? {
-        let (id, tree) = match pair {
-            Ok(p) => p,
-            Err(e) => {
-                if args.strict {
-                    return Err(e);
-                }
-                errors.push(e);
+    let load_start = Instant::now();
+    let mut ref_time = Duration::ZERO;
+    if let Some(n_trees) = args.benchmark {
+        pairs = benchmark_pairs(&args, n_trees, spill.as_ref())?;
+    } else if let Some(jobs_file) = args.jobs_file.as_deref() {
+        let jobs = io::read_jobs(jobs_file)?;
+        let spinner = Progress::spinner(args.progress_format, "Loading jobs", jobs.len() as u64);
+        for job in jobs {
+            if interrupted.load(Ordering::Relaxed) {
+                break;
+            }
+            if skip_ids.contains(&job.id) {
+                skipped.push(job.id);
+                spinner.inc(1);
                 continue;
             }
+            if resume
+                .as_ref()
+                .is_some_and(|r| r.opts_for(&job.id, &opts).is_none())
+            {
+                resumed.push(job.id);
+                spinner.inc(1);
+                continue;
+            }
+            if let Some(manifest) = manifest.as_mut() {
+                let unchanged = ref_checksums.get(&job.ref_path).and_then(|reference| {
+                    manifest.is_unchanged(&job.id, &job.cmp_path, &reference)
+                });
+                match unchanged {
+                    Ok(true) => {
+                        unchanged.insert(job.id);
+                        spinner.inc(1);
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(e) if args.strict => return Err(e),
+                    Err(e) => errors.push(e),
+                }
+            }
+            if io::identical_files(&job.ref_path, &job.cmp_path) {
+                identical.push(job.id.clone());
+            }
+            let source = source_path(&job.cmp_path);
+            // Jobs sharing a reference file share its distances
+            let ref_key = job.ref_path.display().to_string();
+            match io::read_job(&job, &read_opts) {
+                Ok((id, reftree, cmptree, mtime)) => {
+                    if args.partition_by_ref_group.is_some() {
+                        ref_ids.insert(id.clone(), io::get_file_id(&job.ref_path, &read_opts)?);
+                    }
+                    if args.include_mtime {
+                        mtimes.insert(id.clone(), io::format_mtime(mtime));
+                    }
+                    let reftree = TaskTree::new(reftree, spill.as_ref())?;
+                    let cmptree = TaskTree::new(cmptree, spill.as_ref())?;
+                    pairs.push((id, Some((ref_key, reftree)), cmptree, source))
+                }
+                Err(e) => {
+                    if args.strict {
+                        return Err(e);
+                    }
+                    errors.push(e);
+                }
+            }
+            spinner.inc(1)
+        }
+        spinner.finish("Loaded jobs");
+    } else {
+        // With multiple references, the first positional argument holds the
+        // trees to compare, as it does when swapping directories
+        let first_cmp_dir = args.cmp_trees.first().map(|p| p.as_path());
+        if args.swap && args.cmp_trees.len() > 1 {
+            bail!("Only one directory of trees to compare can be used with --swap")
+        }
+        let all_cmp_dirs = args.cmp_trees.iter().map(|p| p.as_path()).collect();
+        let (ref_dir, cmp_dirs): (_, Vec<_>) = match args.multi_ref.as_deref() {
+            Some(pool_dir) => (
+                Some(pool_dir),
+                args.ref_trees.as_deref().into_iter().collect(),
+            ),
+            None if args.swap => (
+                first_cmp_dir,
+                args.ref_trees.as_deref().into_iter().collect(),
+            ),
+            None if args.ref_newick.is_some() => {
+                (None, args.ref_trees.as_deref().into_iter().collect())
+            }
+            None => (args.ref_trees.as_deref(), all_cmp_dirs),
         };
+        if cmp_dirs.is_empty() {
+            bail!("Missing directory of trees to compare")
+        }
+        for cmp_dir in cmp_dirs.iter() {
+            if let Some(ref_dir) = ref_dir.filter(|ref_dir| io::same_path(ref_dir, cmp_dir)) {
+                check_self_compare(
+                    args.no_self_compare,
+                    &format!(
+                        "Reference and comparison trees are read from the same path: {}",
+                        ref_dir.display()
+                    ),
+                )?;
+            }
+        }
+        // Paths of reference files, to find comparison files identical to them
+        let ref_paths: HashMap<_, _> = match ref_dir.filter(|d| d.is_dir()) {
+            Some(ref_dir) if args.multi_ref.is_none() => io::newick_paths(ref_dir)?
+                .filter_map(|path| Some((io::get_file_id(&path, &read_opts).ok()?, path)))
+                .collect(),
+            _ => HashMap::new(),
+        };
+
+        // Read reference trees
+        let ref_start = Instant::now();
+        let ref_trees = match args.ref_newick.as_deref() {
+            Some(newick) => io::read_inline_ref(newick)?,
+            None => cache::read_refs(
+                ref_dir.context("Missing reference directory")?,
+                &read_opts,
+                args.ref_cache.as_deref(),
+            )?,
+        };
+        ref_time = ref_start.elapsed();
+        eprintln!("Reference trees loaded: {}", ref_trees.len());
+        let ref_trees = if args.multi_ref.is_some() {
+            pool = Some(comp::RefPool::new(ref_trees)?);
+            HashMap::new()
+        } else {
+            ref_trees
+        };
+
+        let mut spilled_refs = HashMap::new();
+        let dir_paths = cmp_dirs
+            .iter()
+            .map(|dir| Ok(io::newick_paths(dir)?.collect_vec()))
+            .collect::<Result<Vec<_>>>()?;
+        // There may be any number of references for the trees to compare,
+        // with a pool or an inline reference
+        let n_trees = dir_paths.iter().map(|paths| paths.len() as u64).sum();
+        let spinner = Progress::spinner(args.progress_format, "Loading trees", n_trees);
+
+        // Checksum of the reference of a tree: its file, or the whole
+        // reference input when references are not read from one file each
+        let mut ref_checksum = |id: &str| -> Result<String> {
+            if let Some(newick) = args.ref_newick.as_deref() {
+                return Ok(transform::hash_string(newick));
+            }
+            let ref_dir = ref_dir.context("Missing reference directory")?;
+            let ref_path = find_reference(&ref_trees, id, ref_metadata.as_ref())
+                .and_then(|(ref_id, _)| ref_paths.get(ref_id));
+            ref_checksums.get(ref_path.map_or(ref_dir, |path| path.as_path()))
+        };
+
+        // Skip trees before parsing them
+        let mut dir_of_ids = HashMap::new();
+        let mut to_read = vec![];
+        for (i, paths) in dir_paths.into_iter().enumerate() {
+            let mut dir_to_read = vec![];
+            for path in paths {
+                if let Ok(id) = io::get_file_id(&path, &read_opts) {
+                    if *dir_of_ids.entry(id.clone()).or_insert(i) != i {
+                        bail!("Tree {id} is found in several directories of trees to compare")
+                    }
+                    if skip_ids.contains(&id) {
+                        skipped.push(id);
+                        spinner.inc(1);
+                        continue;
+                    }
+                    if resume
+                        .as_ref()
+                        .is_some_and(|r| r.opts_for(&id, &opts).is_none())
+                    {
+                        resumed.push(id);
+                        spinner.inc(1);
+                        continue;
+                    }
+                    if let Some(manifest) = manifest.as_mut() {
+                        let unchanged = ref_checksum(&id)
+                            .and_then(|reference| manifest.is_unchanged(&id, &path, &reference));
+                        match unchanged {
+                            Ok(true) => {
+                                unchanged.insert(id);
+                                spinner.inc(1);
+                                continue;
+                            }
+                            Ok(false) => {}
+                            Err(e) if args.strict => return Err(e),
+                            Err(e) => errors.push(e),
+                        }
+                    }
+                }
+                dir_to_read.push(path);
+            }
+            to_read.push(dir_to_read);
+        }
 
-        if let Some(reftree) = ref_trees.get(&id) {
-            pairs.push((id, reftree.clone(), tree));
+        // Trees are parsed by one loader thread, or one per directory
+        let loaders = if args.parallel_dirs {
+            to_read
         } else {
-            not_found.push(id)
+            vec![to_read.concat()]
+        };
+        let (loaded_sender, loaded) = bounded(CHANNEL_CAPACITY);
+        for paths in loaders {
+            let sender = loaded_sender.clone();
+            let loader_opts = read_opts.clone();
+            let loader_interrupted = interrupted.clone();
+            thread::spawn(move || {
+                for path in paths {
+                    if loader_interrupted.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let tree = io::read_cmp_tree(&path, &loader_opts);
+                    if sender.send((path, tree)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(loaded_sender);
+
+        for (path, tree) in loaded {
+            if interrupted.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let (id, tree, mtime) = match tree {
+                Ok(p) => p,
+                Err(e) => {
+                    if args.strict {
+                        return Err(e);
+                    }
+                    errors.push(e);
+                    spinner.inc(1);
+                    continue;
+                }
+            };
+
+            if args.include_mtime {
+                mtimes.insert(id.clone(), io::format_mtime(mtime));
+            }
+
+            // Trees compared to the reference pool have no single reference
+            let reftree = match pool.as_ref() {
+                Some(pool) => pool.has_match(&tree).then_some(None),
+                None => {
+                    let lookup_id = match args.ref_newick {
+                        Some(_) => io::INLINE_REF_ID,
+                        None => id.as_str(),
+                    };
+                    match find_reference(&ref_trees, lookup_id, ref_metadata.as_ref()) {
+                        Some((ref_id, r)) => {
+                            // References are spilled once, however many trees use them
+                            let reftree = match spill.as_ref() {
+                                Some(spill) => match spilled_refs.entry(ref_id.clone()) {
+                                    Entry::Occupied(e) => TaskTree::Spilled(*e.get()),
+                                    Entry::Vacant(e) => {
+                                        TaskTree::Spilled(*e.insert(spill.push(r)?))
+                                    }
+                                },
+                                None => TaskTree::Loaded(r.clone()),
+                            };
+                            Some(Some((ref_id.clone(), reftree)))
+                        }
+                        None => None,
+                    }
+                }
+            };
+            if let (Some(Some((ref_id, _))), Some(_)) = (&reftree, &args.partition_by_ref_group) {
+                ref_ids.insert(id.clone(), ref_id.clone());
+            }
+            if let Some(Some((ref_id, _))) = &reftree {
+                if ref_paths
+                    .get(ref_id)
+                    .is_some_and(|ref_path| io::identical_files(ref_path, &path))
+                {
+                    identical.push(id.clone());
+                }
+            }
+            match reftree {
+                Some(reftree) => {
+                    let tree = TaskTree::new(tree, spill.as_ref())?;
+                    pairs.push((id, reftree, tree, source_path(&path)))
+                }
+                None => {
+                    if args.tip_coverage {
+                        unpaired.push((id.clone(), tree, source_path(&path)));
+                    }
+                    not_found.push(id)
+                }
+            }
+            spinner.inc(1)
+        }
+        spinner.finish("Loaded reference trees");
+    }
+    let load_time = load_start.elapsed() - ref_time;
+
+    let n_read_errors = errors.len();
+
+    if !identical.is_empty() {
+        let n_identical = identical.len();
+        report_ids("Identical to the file of their reference", identical);
+        check_self_compare(
+            args.no_self_compare,
+            &format!("{n_identical} comparison tree files are identical to their reference"),
+        )?;
+    }
+
+    // Keep the rows of unchanged trees from the previous run, or of the trees
+    // it finished comparing when resuming it
+    if let Some(previous_rows) = previous_rows {
+        outputs.write_previous(previous_rows, |suffix, id| match resume.as_ref() {
+            Some(resume) => resume.has_rows(suffix, id),
+            None => unchanged.contains(id),
+        })?;
+    }
+
+    // Sample paired trees
+    let mut n_unsampled = 0;
+    if let Some(fraction) = args.sample_fraction {
+        let n_pairs = pairs.len();
+        pairs.retain(|(id, _, _, _)| is_sampled(id, args.seed, fraction));
+        n_unsampled = n_pairs - pairs.len();
+    }
+
+    // Report tip coverage across comparison trees, with or without a
+    // reference
+    if args.tip_coverage {
+        let path = io::get_suffixed_filenme(&args.output_prefix, "coverage", "csv", zipped)?;
+        io::check_overwrite([&path], args.force)?;
+        let coverage = comp::tip_coverage(
+            pairs
+                .iter()
+                .filter_map(|(id, _, tree, _)| Some((id.as_str(), tree.as_loaded()?)))
+                .chain(unpaired.iter().map(|(id, tree, _)| (id.as_str(), tree))),
+        );
+        if let Some(first) = coverage.first() {
+            eprintln!(
+                "Tips in all trees: {}, tips in any tree: {}",
+                first.intersection_size, first.union_size
+            );
+        }
+        let sources: HashMap<_, _> = pairs
+            .iter()
+            .map(|(id, _, _, source)| (id.as_str(), source))
+            .chain(unpaired.iter().map(|(id, _, source)| (id.as_str(), source)))
+            .collect();
+        let mut writer = io::from_writer(io::init_writer(path.clone(), zipped)?, &write_opts);
+        for record in coverage {
+            let provenance = io::Provenance {
+                source_path: sources.get(record.id.as_str()).and_then(|s| (*s).clone()),
+                mtime: mtimes.get(record.id.as_str()).cloned(),
+                marker: args.marker.clone(),
+            };
+            writer.serialize_with_provenance(record, &provenance)?;
         }
-        spinner.inc(1)
+        writer.flush()?;
+        eprintln!("Wrote tip coverage to:  {}", path.display());
     }
-    spinner.finish_with_message("Loaded reference trees");
+
+    // Rank rogue taxa across compared trees
+    if args.rogue_taxa {
+        let path = io::get_suffixed_filenme(&args.output_prefix, "rogues", "csv", zipped)?;
+        io::check_overwrite([&path], args.force)?;
+        let (rogues, rogue_errors) =
+            comp::rogue_taxa(pairs.par_iter().filter_map(|(id, reference, tree, _)| {
+                let (_, reftree) = reference.as_ref()?;
+                Some((id.as_str(), reftree.as_loaded()?, tree.as_loaded()?))
+            }));
+        if let Some(e) = rogue_errors.into_iter().next().filter(|_| args.strict) {
+            return Err(e);
+        }
+        errors.extend(rogue_errors);
+        let mut writer = io::from_writer(io::init_writer(path.clone(), zipped)?, &write_opts);
+        for mut record in rogues {
+            record.marker = args.marker.clone();
+            writer.serialize(record)?;
+        }
+        writer.flush()?;
+        eprintln!("Wrote rogue taxa to:  {}", path.display());
+    }
+
+    // Differences with a previous run
+    let mut baseline = args
+        .baseline
+        .as_deref()
+        .map(io::read_baseline)
+        .transpose()?;
+    let mut delta_output = match baseline {
+        None => None,
+        Some(_) => {
+            let path = io::get_suffixed_filenme(&args.output_prefix, "delta", "csv", zipped)?;
+            io::check_overwrite([&path], args.force)?;
+            let writer = io::from_writer(io::init_writer(path.clone(), zipped)?, &write_opts);
+            Some((path, writer))
+        }
+    };
+    let mut not_in_baseline = vec![];
+
+    // Topology metrics in long format
+    let mut metrics_output = if args.long_metrics {
+        let path = io::get_suffixed_filenme(&args.output_prefix, "metrics", "csv", zipped)?;
+        io::check_overwrite([&path], args.force)?;
+        let writer = io::from_writer(io::init_writer(path.clone(), zipped)?, &write_opts);
+        Some((path, writer))
+    } else {
+        None
+    };
+
+    // Outcome of each compared tree
+    let mut status_output = match args.status_log.as_ref() {
+        Some(path) => {
+            io::check_overwrite([path], args.force)?;
+            let writer = io::from_writer(io::init_writer(path.clone(), false)?, &write_opts);
+            Some((path, writer))
+        }
+        None => None,
+    };
 
     // Compare trees
-    let (sender, receiver) = unbounded();
+    let compare_start = Instant::now();
+    let mut write_time = Duration::ZERO;
+    let (sender, receiver) = bounded(CHANNEL_CAPACITY);
+    let worker_opts = opts.clone();
+    let worker_interrupted = interrupted.clone();
+    let emit_failures = args.emit_failures;
+    // Distances are not streamed to keep the whole record of each tree
+    let keep_distances = args.json_per_tree || args.compact_rows;
+    let npy_prefix = args.npy.then(|| args.output_prefix.clone());
+    // Outputs of groups are only created once a tree of the group is
+    // written, but they are checked before comparing any tree. Trees compared
+    // to a reference pool only get their reference, and group, once compared.
+    if !overwrite {
+        let groups: HashSet<_> = pairs
+            .iter()
+            .filter_map(|(id, _, _, _)| match args.partition_by_ref_group.as_ref() {
+                Some(re) => get_group(re, ref_ids.get(id)?),
+                None => get_group(args.group_regex.as_ref()?, id),
+            })
+            .collect();
+        for group in groups {
+            io::Outputs::check_existing(
+                &args.output_prefix,
+                Some(&group),
+                zipped,
+                &opts,
+                &write_opts,
+            )?;
+        }
+    }
+
+    // Files written for each tree are checked before comparing any tree
+    let paired_ids = pairs
+        .iter()
+        .filter(|(_, reference, _, _)| reference.is_some())
+        .map(|(id, _, _, _)| id.as_str());
+    for (dir, ext) in [(args.dot.as_deref(), "dot"), (args.nexus.as_deref(), "nex")] {
+        if let Some(dir) = dir {
+            check_tree_files(dir, ext, paired_ids.clone(), args.force)?;
+        }
+    }
+    if let Some(prefix) = npy_prefix.as_ref() {
+        npy::check_existing(prefix, paired_ids.clone(), args.force)?;
+    }
+    let dot_dir = args.dot.clone();
+    let nexus_dir = args.nexus.clone();
+    let float_precision = args.float_precision;
+    let condensed = args
+        .condensed_distances
+        .then(|| {
+            condensed::CondensedWriter::new(
+                &args.output_prefix,
+                zipped,
+                paired_ids.clone(),
+                args.force,
+            )
+        })
+        .transpose()?;
+    let bar = Progress::bar(args.progress_format, "Comparing trees", pairs.len() as u64);
+    // Distances of references shared by several trees are only computed once
+    let ref_dist_cache = opts.distances.then(|| {
+        dist::RefDistCache::new(
+            pairs
+                .iter()
+                .filter_map(|(_, reference, _, _)| reference.as_ref())
+                .map(|(ref_key, _)| ref_key.clone())
+                .counts(),
+        )
+    });
 
     thread::spawn(move || {
         pairs
             .into_par_iter()
-            .progress_count(ref_trees.len() as u64)
-            .for_each_with(&sender, |sender, (id, reftree, cmptree)| {
-                let res = comp::compare_trees(
-                    id,
-                    &reftree,
-                    &cmptree,
-                    compare_topo,
-                    compare_lens,
-                    compare_dist,
-                    args.include_tips,
-                );
+            .for_each_with(&sender, |sender, (id, reference, cmptree, source)| {
+                if worker_interrupted.load(Ordering::Relaxed) {
+                    return;
+                }
+
+                let (reference, cmptree) = match load_task(reference, cmptree, spill.as_ref()) {
+                    Ok(task) => task,
+                    Err(e) => {
+                        bar.inc(1);
+                        let e = e.context(format!("Could not compare tree: {id}"));
+                        if let Err(e) = sender.send(Err(e)) {
+                            eprintln!("Error sending: {e:?}")
+                        }
+                        return;
+                    }
+                };
+
+                // Distances are sent in chunks as they are computed
+                let chunk_id = Arc::new(id.clone());
+                let mut send_dists = |dists: Vec<DistanceRecord>| {
+                    let chunk = ComparisonRecord {
+                        id: chunk_id.clone(),
+                        distances: Some(dists),
+                        is_chunk: true,
+                        provenance: io::Provenance {
+                            source_path: source.clone(),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    };
+                    if let Err(e) = sender.send(Ok(Box::new(chunk))) {
+                        eprintln!("Error sending: {e:?}")
+                    }
+                };
 
+                let dist_sink: Option<&mut comp::DistSink> = if keep_distances {
+                    None
+                } else {
+                    Some(&mut send_dists)
+                };
+
+                // Modalities already in the outputs being resumed are skipped
+                let tree_opts = resume
+                    .as_ref()
+                    .and_then(|resume| resume.opts_for(&id, &worker_opts))
+                    .unwrap_or(Cow::Borrowed(&worker_opts));
+
+                let res = match (reference, pool.as_ref()) {
+                    (Some((ref_key, reftree)), _) => {
+                        // Files of each tree are written from the trees as
+                        // they are compared
+                        let per_tree = npy_prefix.is_some()
+                            || condensed.is_some()
+                            || dot_dir.is_some()
+                            || nexus_dir.is_some();
+                        let written = if per_tree {
+                            comp::transform_trees(&id, &reftree, &cmptree, &worker_opts).and_then(
+                                |(reftree, cmptree, _)| {
+                                    if let Some(prefix) = npy_prefix.as_ref() {
+                                        npy::write_distances(
+                                            prefix,
+                                            &id,
+                                            &reftree,
+                                            &cmptree,
+                                            &worker_opts,
+                                        )?;
+                                    }
+                                    if let Some(condensed) = condensed.as_ref() {
+                                        condensed.write(&id, &reftree, &cmptree, &worker_opts)?;
+                                    }
+                                    if let Some(dir) = dot_dir.as_deref() {
+                                        dot::write_conflicts(dir, &id, &reftree, &cmptree)?;
+                                    }
+                                    if let Some(dir) = nexus_dir.as_deref() {
+                                        nexus::write_annotated(
+                                            dir,
+                                            &id,
+                                            &reftree,
+                                            &cmptree,
+                                            float_precision,
+                                        )?;
+                                    }
+                                    Ok(())
+                                },
+                            )
+                        } else {
+                            Ok(())
+                        };
+                        written.and_then(|_| {
+                            comp::compare_trees(
+                                &id,
+                                &reftree,
+                                &cmptree,
+                                &tree_opts,
+                                dist_sink,
+                                ref_dist_cache
+                                    .as_ref()
+                                    .map(|cache| (ref_key.as_str(), cache)),
+                            )
+                        })
+                    }
+                    (None, Some(pool)) => pool.compare_best(&id, &cmptree, &tree_opts),
+                    (None, None) => Err(anyhow!("No reference tree")),
+                }
+                .context(format!("Could not compare tree: {id}"))
+                .map(|mut record| {
+                    record.provenance.source_path = source.clone();
+                    record
+                });
+                let res = match res {
+                    // Failures are sent as records to log the status of
+                    // their tree and drop its streamed distances
+                    Err(e) => Ok(Box::new(ComparisonRecord {
+                        id: chunk_id.clone(),
+                        topology: emit_failures.then(|| TopologyRecord {
+                            best_ref_id: pool.is_some().then(String::new),
+                            ..TopologyRecord::failed(
+                                chunk_id.clone(),
+                                cmptree.n_leaves(),
+                                &e,
+                                &worker_opts,
+                            )
+                        }),
+                        failure: Some(e),
+                        provenance: io::Provenance {
+                            source_path: source.clone(),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    })),
+                    res => res,
+                };
+
+                bar.inc(1);
                 match sender.send(res) {
                     Ok(_) => {}
                     Err(e) => eprintln!("Error sending: {e:?}"),
                 };
             });
+        bar.finish("Compared trees");
         drop(sender);
     });
 
+    let mut n_compared = 0;
+    // Records are written one at a time, writing them in chunks was not
+    // faster (see the Benchmarking section of the README)
     for record in receiver {
-        let record = record?;
+        let mut record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                if args.strict {
+                    return Err(e);
+                }
+                errors.push(e);
+                continue;
+            }
+        };
+        record.provenance.mtime = mtimes.get(record.id.as_str()).cloned();
+        record.provenance.marker = args.marker.clone();
+        warnings.append(&mut record.warnings);
+        // Whether the streamed distances of the tree are complete, once its
+        // last record is received
+        let finished =
+            (!record.is_chunk).then(|| record.failure.is_none() && record.distance_error.is_none());
+        if let Some((_, writer)) = status_output.as_mut().filter(|_| !record.is_chunk) {
+            let status = StatusRecord::new(&record, &opts);
+            writer.serialize_with_provenance(status, &record.provenance)?;
+        }
+        // Rows of failed comparisons are written, but the tree was not
+        // compared
+        let failed = record.failure.is_some();
+        if let Some(e) = record.failure.take() {
+            if args.strict {
+                return Err(e);
+            }
+            errors.push(e);
+            if !args.emit_failures {
+                finish_tree(
+                    &mut outputs,
+                    &mut group_outputs,
+                    sqlite.as_mut(),
+                    &record.id,
+                    false,
+                )?;
+                continue;
+            }
+        } else if !record.is_chunk {
+            n_compared += 1;
+            if let Some(manifest) = manifest.as_mut() {
+                manifest.confirm(&record.id);
+            }
+        }
+        if let Some(e) = record.distance_error.take() {
+            let e = e.context(format!(
+                "Could not compare distances of tree: {}",
+                record.id
+            ));
+            if args.strict {
+                return Err(e);
+            }
+            errors.push(e);
+        }
+        // The error column is empty for trees that were compared
+        if args.emit_failures {
+            for topo in record
+                .topology
+                .iter_mut()
+                .chain(record.topology_sweep.iter_mut().flatten())
+            {
+                topo.error.get_or_insert_with(String::new);
+            }
+        }
+
+        // Rows of failed comparisons only go to the main outputs: they have
+        // no metrics to derive other rows from, and are never filtered out
+        let write_start = Instant::now();
+        if let (Some(baseline), Some((_, writer)), Some(topo)) = (
+            baseline.as_mut(),
+            delta_output.as_mut(),
+            record.topology.as_ref(),
+        ) {
+            match baseline.remove(topo.id.as_str()) {
+                _ if failed => {}
+                Some(base) => {
+                    let delta = DeltaRecord::new(topo, &base);
+                    writer.serialize_with_provenance(delta, &record.provenance)?;
+                }
+                None => not_in_baseline.push(topo.id.to_string()),
+            }
+        }
 
-        if let Some(mut topo) = record.topology {
-            topo.marker = args.marker.clone();
-            topo_writer.as_mut().map(|w| w.serialize(topo));
+        if args.json_per_tree {
+            io::write_json_record(
+                &args.output_prefix,
+                &record,
+                zipped,
+                &write_opts,
+                args.force,
+            )?;
         }
 
-        if let Some(brlens) = record.branches {
-            for mut brlen in brlens {
-                brlen.marker = args.marker.clone();
-                brlen_writer.as_mut().map(|w| w.serialize(brlen));
+        // Only keep topology rows satisfying the filters
+        if !args.filter.is_empty() && !failed {
+            let keep = |topo: &TopologyRecord| args.filter.iter().all(|f| f.matches(topo));
+            if record.topology.as_ref().is_some_and(|topo| !keep(topo)) {
+                record.topology = None;
+            }
+            if let Some(sweep) = record.topology_sweep.as_mut() {
+                sweep.retain(keep);
             }
         }
 
-        if let Some(dists) = record.distances {
-            for mut dist in dists {
-                dist.marker = args.marker.clone();
-                dist_writer.as_mut().map(|w| w.serialize(dist));
+        if let (Some((_, writer)), Some(topo)) = (
+            metrics_output.as_mut().filter(|_| !failed),
+            record.topology.as_ref(),
+        ) {
+            for metric in MetricRecord::from_topology(topo) {
+                writer.serialize_with_provenance(metric, &record.provenance)?;
             }
         }
+
+        let id = record.id.clone();
+        if let Some(db) = sqlite.as_mut() {
+            db.insert(&record)?;
+        } else {
+            let group = if let Some(fraction) = args.split_fraction {
+                Some(get_split(&record.id, args.seed, fraction).to_string())
+            } else if let Some(re) = args.partition_by_ref_group.as_ref() {
+                record
+                    .topology
+                    .as_ref()
+                    .and_then(|topo| topo.best_ref_id.as_ref())
+                    .or_else(|| ref_ids.get(record.id.as_str()))
+                    .and_then(|ref_id| get_group(re, ref_id))
+            } else {
+                args.group_regex
+                    .as_ref()
+                    .and_then(|re| get_group(re, &record.id))
+            };
+
+            let outputs = match group {
+                None => &mut outputs,
+                Some(group) => match group_outputs.entry(group) {
+                    Entry::Occupied(e) => e.into_mut(),
+                    Entry::Vacant(e) => {
+                        let group_output = new_outputs(Some(e.key()))?;
+                        e.insert(group_output)
+                    }
+                },
+            };
+            write_record(outputs, *record, &opts, args.compact_rows)?;
+        }
+
+        if let Some(complete) = finished {
+            finish_tree(
+                &mut outputs,
+                &mut group_outputs,
+                sqlite.as_mut(),
+                &id,
+                complete,
+            )?;
+        }
+        write_time += write_start.elapsed();
     }
+    let compare_time = compare_start.elapsed();
 
-    dist_writer.as_mut().map(|w| w.flush());
-    brlen_writer.as_mut().map(|w| w.flush());
-    topo_writer.as_mut().map(|w| w.flush());
+    if let Some((_, writer)) = delta_output.as_mut() {
+        writer.flush()?;
+    }
+    if let Some((_, writer)) = metrics_output.as_mut() {
+        writer.flush()?;
+    }
+    if let Some((_, writer)) = status_output.as_mut() {
+        writer.flush()?;
+    }
+    outputs.flush()?;
+    if let Some(db) = sqlite.as_mut() {
+        db.flush()?;
+    }
+    for group_output in group_outputs.values_mut() {
+        group_output.flush()?;
+    }
+
+    let summary = Summary {
+        compared: n_compared,
+        read_errors: n_read_errors,
+        compare_errors: errors.len() - n_read_errors,
+        not_found: not_found.len(),
+        skipped: skipped.len(),
+        warnings: warnings.len(),
+    };
 
-    if !not_found.is_empty() {
-        let n = not_found.len();
-        eprintln!("Could not find reference {n} trees:");
-        for tree in not_found.into_iter().take(10) {
-            eprintln!("\t- {}", tree)
+    report_ids("Could not find reference for", not_found);
+    report_ids("Skipped by request", skipped);
+    if !unchanged.is_empty() {
+        eprintln!(
+            "Kept previous results of {} trees unchanged since the last run",
+            unchanged.len()
+        );
+    }
+    if !resumed.is_empty() {
+        eprintln!(
+            "Kept previous results of {} trees already compared",
+            resumed.len()
+        );
+    }
+    if n_unsampled > 0 {
+        eprintln!("Left out by sampling {n_unsampled} trees");
+    }
+    if let Some(baseline) = baseline {
+        report_ids("Not found in the baseline", not_in_baseline);
+        report_ids(
+            "Only found in the baseline",
+            baseline.into_keys().sorted().collect(),
+        );
+    }
+
+    if !warnings.is_empty() {
+        eprintln!("There were warnings comparing some trees:");
+        for warning in warnings {
+            eprintln!("WARNING: {warning}");
         }
-        if n > 10 {
-            eprintln!("\t- ...")
+    }
+    if !errors.is_empty() {
+        eprintln!("There were errors reading or comparing some trees:");
+        for err in errors {
+            eprintln!("{:#}", err);
         }
     }
 
+    outputs.report();
+    if let Some(db) = sqlite.as_ref() {
+        db.report();
+    }
+    for group_output in group_outputs.values() {
+        group_output.report();
+    }
+    if let Some((path, _)) = &delta_output {
+        eprintln!("Wrote baseline differences to:  {}", path.display())
+    }
+    if let Some((path, _)) = &metrics_output {
+        eprintln!("Wrote long format metrics to:  {}", path.display())
+    }
+    if let Some((path, _)) = &status_output {
+        eprintln!("Wrote tree status to:  {}", path.display())
+    }
+
+    summary.report();
+
+    if args.benchmark.is_some() {
+        eprintln!(
+            "Compared {n_compared} trees in {:.3}s: {:.1} trees/s",
+            compare_time.as_secs_f64(),
+            n_compared as f64 / compare_time.as_secs_f64()
+        );
+        match peak_memory() {
+            Some(kb) => eprintln!("Peak memory: {:.1} MiB", kb as f64 / 1024.),
+            None => eprintln!("Peak memory: unavailable on this platform"),
+        }
+    }
+
+    if args.timings {
+        eprintln!("Timings:");
+        for (phase, time) in [
+            ("loading references", ref_time),
+            ("loading trees", load_time),
+            ("comparing trees", compare_time),
+            ("  of which writing", write_time),
+            ("total", start.elapsed()),
+        ] {
+            eprintln!("  {phase:<20} {:>10.3}s", time.as_secs_f64());
+        }
+    }
+
+    // Trees that were not compared must be compared by the next run
+    let was_interrupted = interrupted.load(Ordering::Relaxed);
+    if let Some(manifest) = manifest.as_ref().filter(|_| !was_interrupted) {
+        manifest.write()?;
+        manifest.report();
+    }
+
+    // Outputs are finalized when dropped, on returning
+    if was_interrupted {
+        bail!("Run interrupted, outputs only hold the trees compared before the interruption")
+    }
+
+    Ok(())
+}
+
+// Compare replicate trees of the same group with each other, writing the
+// mean and median RF distance of each group
+fn self_consistency(
+    args: &Cli,
+    read_opts: &io::ReadOpts,
+    write_opts: &Arc<io::WriteOpts>,
+    zipped: bool,
+) -> Result<()> {
+    let re = args
+        .group_regex
+        .as_ref()
+        .context("Self-consistency requires a group regex")?;
+    let dir = args
+        .ref_trees
+        .as_deref()
+        .context("Missing directory of trees to compare")?;
+
+    let path = io::get_suffixed_filenme(&args.output_prefix, "consistency", "csv", zipped)?;
+    io::check_overwrite([&path], args.force)?;
+
+    let mut errors = vec![];
+    let mut ungrouped = vec![];
+    let mut groups: HashMap<String, Vec<_>> = HashMap::new();
+    for res in io::trees_iter(dir, read_opts)? {
+        match res {
+            Ok((id, tree, _)) => match get_group(re, &id) {
+                Some(group) => groups.entry(group).or_default().push(tree),
+                None => ungrouped.push(id),
+            },
+            Err(e) if args.strict => return Err(e),
+            Err(e) => errors.push(e),
+        }
+    }
+
+    // Groups are sorted so that the output does not depend on thread scheduling
+    let groups: Vec<_> = groups
+        .into_iter()
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .collect();
+    let records: Vec<_> = groups
+        .par_iter()
+        .map(|(group, trees)| {
+            comp::ConsistencyRecord::from_trees(group, trees)
+                .context(format!("Could not compare trees of group: {group}"))
+        })
+        .collect();
+
+    let mut writer = io::from_writer(io::init_writer(path.clone(), zipped)?, write_opts);
+    for record in records {
+        match record {
+            Ok(mut record) => {
+                record.marker = args.marker.clone();
+                writer.serialize(record)?;
+            }
+            Err(e) if args.strict => return Err(e),
+            Err(e) => errors.push(e),
+        }
+    }
+    writer.flush()?;
+
+    report_ids("No group found for", ungrouped);
     if !errors.is_empty() {
-        eprintln!("There were errors reading some trees:");
+        eprintln!("There were errors reading or comparing some trees:");
         for err in errors {
-            eprintln!("{}", err);
+            eprintln!("{:#}", err);
         }
     }
+    eprintln!("Wrote self-consistency to:  {}", path.display());
+
+    Ok(())
+}
+
+// Parse all newick files and report which ones could not be parsed
+fn validate_newick(
+    args: &Cli,
+    read_opts: &io::ReadOpts,
+    write_opts: &Arc<io::WriteOpts>,
+    zipped: bool,
+) -> Result<()> {
+    let path = io::get_suffixed_filenme(&args.output_prefix, "validation", "csv", zipped)?;
+    io::check_overwrite([&path], args.force)?;
 
-    if let Some(_) = dist_writer {
-        eprintln!("Wrote distance comparison to:  {}", dist_path.display())
+    let dirs = args
+        .ref_trees
+        .iter()
+        .chain(args.cmp_trees.iter())
+        .chain(args.multi_ref.iter());
+    let mut tree_paths = vec![];
+    let mut archives = vec![];
+    for dir in dirs {
+        if io::is_zip(dir) {
+            archives.push(dir.clone());
+        } else {
+            tree_paths.extend(io::newick_paths(dir)?);
+        }
     }
-    if let Some(_) = topo_writer {
-        eprintln!("Wrote topology comparison to:  {}", topo_path.display())
+    tree_paths.sort();
+
+    let bar = Progress::bar(
+        args.progress_format,
+        "Parsing trees",
+        (tree_paths.len() + archives.len()) as u64,
+    );
+    // Archives are parsed as a whole
+    let results: Vec<_> = tree_paths
+        .par_iter()
+        .map(|p| io::read_tree(p, read_opts).map(|_| ()))
+        .chain(
+            archives
+                .par_iter()
+                .map(|p| io::zip_trees(p, read_opts).map(|_| ())),
+        )
+        .map(|res| {
+            bar.inc(1);
+            res
+        })
+        .collect();
+    bar.finish("Parsed trees");
+
+    let mut n_failed = 0;
+    let mut writer = io::from_writer(io::init_writer(path.clone(), zipped)?, write_opts);
+    for (tree_path, res) in tree_paths.iter().chain(archives.iter()).zip(results) {
+        let mut record = io::ValidationRecord {
+            path: tree_path.display().to_string(),
+            marker: args.marker.clone(),
+            ..Default::default()
+        };
+        match res {
+            Ok(_) => record.status = "ok",
+            Err(e) => {
+                n_failed += 1;
+                record.status = "failed";
+                record.error = Some(format!("{e:#}"));
+            }
+        }
+        writer.serialize(record)?;
+    }
+    writer.flush()?;
+
+    eprintln!("Wrote newick validation to:  {}", path.display());
+    let n_files = tree_paths.len() + archives.len();
+    if n_failed > 0 {
+        bail!("{n_failed} of {n_files} newick files could not be parsed")
+    }
+    eprintln!("All {n_files} newick files could be parsed");
+
+    Ok(())
+}
+
+// Compare reference trees to precomputed distance matrices
+fn compare_matrices(
+    args: &Cli,
+    matrix_dir: &Path,
+    opts: &CompOpts,
+    read_opts: &io::ReadOpts,
+    write_opts: &Arc<io::WriteOpts>,
+    skip_ids: &HashSet<String>,
+    zipped: bool,
+) -> Result<()> {
+    let ref_dir = args
+        .ref_trees
+        .as_deref()
+        .context("Missing reference directory")?;
+    let ref_trees = cache::read_refs(ref_dir, read_opts, args.ref_cache.as_deref())?;
+    eprintln!("Reference trees loaded: {}", ref_trees.len());
+
+    let mut errors = vec![];
+    let mut not_found = vec![];
+    let mut skipped = vec![];
+    let mut matrices = vec![];
+    for path in io::phylip_paths(matrix_dir)? {
+        if let Ok(id) = io::get_file_id(&path, read_opts) {
+            if skip_ids.contains(&id) {
+                skipped.push(id);
+                continue;
+            }
+        }
+        match io::read_matrix(&path, read_opts) {
+            Ok((id, dists)) => match ref_trees.get(&id) {
+                Some(reftree) => matrices.push((id, reftree, dists)),
+                None => not_found.push(id),
+            },
+            Err(e) if args.strict => return Err(e),
+            Err(e) => errors.push(e),
+        }
+    }
+    let n_read_errors = errors.len();
+
+    let bar = Progress::bar(
+        args.progress_format,
+        "Comparing matrices",
+        matrices.len() as u64,
+    );
+    let records: Vec<_> = matrices
+        .par_iter()
+        .map(|(id, reftree, dists)| {
+            let res = comp::compare_matrix(id, reftree, dists, opts)
+                .context(format!("Could not compare matrix: {id}"));
+            bar.inc(1);
+            res
+        })
+        .collect();
+    bar.finish("Compared matrices");
+
+    let mut outputs = io::Outputs::new(
+        &args.output_prefix,
+        None,
+        zipped,
+        opts,
+        write_opts,
+        args.force,
+    )?;
+    let mut n_compared = 0;
+    for record in records {
+        match record {
+            Ok(mut record) => {
+                n_compared += 1;
+                record.provenance.marker = args.marker.clone();
+                write_record(&mut outputs, *record, opts, args.compact_rows)?;
+            }
+            Err(e) if args.strict => return Err(e),
+            Err(e) => errors.push(e),
+        }
     }
-    if let Some(_) = brlen_writer {
-        eprintln!("Wrote branch   comparison to:  {}", brlen_path.display())
+    outputs.flush()?;
+
+    let summary = Summary {
+        compared: n_compared,
+        read_errors: n_read_errors,
+        compare_errors: errors.len() - n_read_errors,
+        not_found: not_found.len(),
+        skipped: skipped.len(),
+        warnings: 0,
+    };
+
+    report_ids("Could not find reference for", not_found);
+    report_ids("Skipped by request", skipped);
+    if !errors.is_empty() {
+        eprintln!("There were errors reading or comparing some trees:");
+        for err in errors {
+            eprintln!("{:#}", err);
+        }
     }
+    outputs.report();
+    summary.report();
 
     Ok(())
 }
 
-fn init_spinner(len: u64) -> ProgressBar {
-    let bar = ProgressBar::new(len);
-    bar.enable_steady_tick(Duration::from_millis(80));
-    let spinner_style = ProgressStyle::with_template("{spinner:.cyan} {wide_msg}")
-        .unwrap()
-        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏");
-    bar.set_style(spinner_style);
+// Counts of what happened to the trees of a run
+struct Summary {
+    compared: usize,
+    read_errors: usize,
+    compare_errors: usize,
+    not_found: usize,
+    skipped: usize,
+    warnings: usize,
+}
+
+impl Summary {
+    fn report(&self) {
+        let status = if self.read_errors + self.compare_errors + self.not_found == 0 {
+            "Run completed without errors"
+        } else {
+            "Run completed WITH ERRORS"
+        };
+        eprintln!(
+            "{status}: {} trees compared, {} errors reading trees, {} errors comparing trees, {} missing references, {} skipped, {} warnings",
+            self.compared,
+            self.read_errors,
+            self.compare_errors,
+            self.not_found,
+            self.skipped,
+            self.warnings
+        );
+    }
+}
+
+// Compare small hardcoded trees with all modalities, checking metrics with
+// known values
+fn self_test() -> Result<()> {
+    let parse = |newick: &str| -> Result<Tree> {
+        let mut tree = Tree::from_newick(newick)?;
+        tree.reset_depths()?;
+        Ok(tree)
+    };
+    let reftree = parse("((A:1,B:1):1,(C:1,D:1):1,E:1);")?;
+    let same = parse("((D:1,C:1):1,E:1,(B:1,A:1):1);")?;
+    let swapped = parse("((A:1,C:1):1,(B:1,D:1):1,E:1);")?;
+
+    let opts = CompOpts {
+        topology: true,
+        lengths: true,
+        distances: true,
+        clades: Some(vec![comp::Clade {
+            name: "AB".into(),
+            tips: vec!["A".into(), "B".into()],
+        }]),
+        internal_labels: true,
+        canonical: true,
+        cherries: true,
+        root_agreement: true,
+        ..Default::default()
+    };
+    let identical = comp::compare_trees("identical", &reftree, &same, &opts, None, None)?;
+    let different = comp::compare_trees("different", &reftree, &swapped, &opts, None, None)?;
+
+    let topo = identical.topology.as_ref().context("Missing topology")?;
+    let other_topo = different.topology.as_ref().context("Missing topology")?;
+    let dists = identical.distances.as_deref().unwrap_or_default();
+    let branches = identical.branches.as_deref().unwrap_or_default();
+    let clade = |record: &ComparisonRecord| {
+        record
+            .clades
+            .as_ref()
+            .and_then(|clades| clades.first())
+            .and_then(|clade| clade.monophyletic_in_cmp)
+    };
+
+    let checks = [
+        ("RF distance of identical trees is 0", topo.rf == 0.),
+        ("KF score of identical trees is 0", topo.kf_score == 0.),
+        (
+            "RF distance of trees with 2 different splits is 4",
+            other_topo.rf == 4.,
+        ),
+        (
+            "Distances of identical trees are equal for all 10 tip pairs",
+            dists.len() == 10 && dists.iter().all(|d| d.ref_dist == d.cmp_dist),
+        ),
+        (
+            "Branch lengths of identical trees are equal",
+            !branches.is_empty() && branches.iter().all(|b| b.ref_len == b.cmp_len),
+        ),
+        (
+            "Canonical newick strings of identical trees are equal",
+            identical.canonical.as_ref().is_some_and(|c| c.identical),
+        ),
+        (
+            "Identical trees share their 2 cherries",
+            identical
+                .cherries
+                .as_ref()
+                .is_some_and(|c| c.shared_cherries == 2),
+        ),
+        (
+            "Roots of identical trees coincide",
+            identical
+                .roots
+                .as_ref()
+                .is_some_and(|r| r.same_root == Some(true)),
+        ),
+        (
+            "Clade AB is only monophyletic in the identical tree",
+            clade(&identical) == Some(true) && clade(&different) == Some(false),
+        ),
+    ];
+
+    let mut n_failed = 0;
+    for (check, passed) in checks {
+        if !passed {
+            n_failed += 1;
+        }
+        eprintln!("{} {check}", if passed { "PASS" } else { "FAIL" });
+    }
+    if n_failed > 0 {
+        bail!("{n_failed} self-test checks failed")
+    }
+    eprintln!("All self-test checks passed");
+
+    Ok(())
+}
+
+// Generate pairs of random trees to compare, each tree having its own
+// reference
+#[allow(clippy::type_complexity)]
+fn benchmark_pairs(
+    args: &Cli,
+    n_trees: usize,
+    spill: Option<&cache::SpillFile>,
+) -> Result<Vec<(String, Option<(String, TaskTree)>, TaskTree, Option<String>)>> {
+    let (n_tips, base_seed) = (args.benchmark_tips, args.seed);
+    let seed = |side: &str, i: usize| transform::hash_u64(&format!("{base_seed}:{side}:{i}"));
+    let trees = (0..n_trees)
+        .into_par_iter()
+        .map(|i| {
+            Ok((
+                i.to_string(),
+                transform::random_tree(n_tips, seed("ref", i))?,
+                transform::random_tree(n_tips, seed("cmp", i))?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    eprintln!("Generated {n_trees} pairs of random trees with {n_tips} tips");
+
+    trees
+        .into_iter()
+        .map(|(id, reftree, cmptree)| {
+            let reference = (id.clone(), TaskTree::new(reftree, spill)?);
+            Ok((id, Some(reference), TaskTree::new(cmptree, spill)?, None))
+        })
+        .collect()
+}
+
+// Print how the only comparison tree differs from the only reference
+fn explain_pair(args: &Cli, read_opts: &io::ReadOpts, write_opts: &io::WriteOpts) -> Result<()> {
+    let ref_dir = args
+        .ref_trees
+        .as_deref()
+        .context("Missing reference directory")?;
+    let refs = io::read_refs(ref_dir, read_opts)?;
+    let cmp_paths = args
+        .cmp_trees
+        .iter()
+        .map(|dir| io::newick_paths(dir))
+        .flatten_ok()
+        .collect::<Result<Vec<_>>>()?;
+    if refs.len() != 1 || cmp_paths.len() != 1 {
+        bail!(
+            "Explaining a comparison requires exactly 1 reference and 1 comparison tree, found {} and {}",
+            refs.len(),
+            cmp_paths.len()
+        )
+    }
+
+    let (ref_id, reftree) = refs.into_iter().next().context("Missing reference tree")?;
+    let (cmp_id, cmptree, _) = io::read_cmp_tree(&cmp_paths[0], read_opts)?;
+    let text = explain::explain(
+        &ref_id,
+        &reftree,
+        &cmp_id,
+        &cmptree,
+        write_opts.float_precision,
+    )
+    .context(format!("Could not compare tree: {cmp_id}"))?;
+    print!("{text}");
+
+    Ok(())
+}
+
+// Peak resident memory of the process in kB, read from /proc on Linux
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+// Get the trees of a comparison, reading them back if they were spilled
+fn load_task(
+    reference: Option<(String, TaskTree)>,
+    cmptree: TaskTree,
+    spill: Option<&cache::SpillFile>,
+) -> Result<(Option<(String, Tree)>, Tree)> {
+    let reference = match reference {
+        Some((ref_key, reftree)) => Some((ref_key, reftree.load(spill)?)),
+        None => None,
+    };
+
+    Ok((reference, cmptree.load(spill)?))
+}
+
+// Write the streamed distances of a tree once it was fully compared, or drop
+// them, so that a failure does not leave part of them in the outputs
+fn finish_tree(
+    outputs: &mut io::Outputs,
+    group_outputs: &mut HashMap<String, io::Outputs>,
+    sqlite: Option<&mut SqliteOutput>,
+    id: &str,
+    complete: bool,
+) -> Result<()> {
+    if let Some(db) = sqlite {
+        return db.finish(id, complete);
+    }
+    for outputs in std::iter::once(outputs).chain(group_outputs.values_mut()) {
+        if complete {
+            outputs.commit(id)?;
+        } else {
+            outputs.discard(id);
+        }
+    }
+
+    Ok(())
+}
+
+// Create a directory of per-tree output files, checking that none of the
+// files of the trees exist yet unless they can be overwritten
+fn check_tree_files<'a>(
+    dir: &Path,
+    ext: &str,
+    ids: impl Iterator<Item = &'a str>,
+    force: bool,
+) -> Result<()> {
+    fs::create_dir_all(dir).context(format!("Could not create: {}", dir.display()))?;
+    io::check_overwrite(ids.map(|id| dir.join(format!("{id}.{ext}"))), force)
+}
+
+// Warn about comparing trees to themselves, or fail if it is forbidden
+fn check_self_compare(forbidden: bool, message: &str) -> Result<()> {
+    if forbidden {
+        bail!("{message}, refusing to compare trees to themselves (--no-self-compare)")
+    }
+    eprintln!("WARNING: {message}, trees are compared to themselves");
+    Ok(())
+}
+
+// Print a summary of a list of tree ids
+fn report_ids(header: &str, ids: Vec<String>) {
+    if ids.is_empty() {
+        return;
+    }
+    let n = ids.len();
+    eprintln!("{header} {n} trees:");
+    for id in ids.into_iter().take(10) {
+        eprintln!("\t- {}", id)
+    }
+    if n > 10 {
+        eprintln!("\t- ...")
+    }
+}
+
+// Write the results of a comparison to the output files
+fn write_record(
+    outputs: &mut io::Outputs,
+    record: ComparisonRecord,
+    opts: &CompOpts,
+    compact: bool,
+) -> Result<()> {
+    let provenance = &record.provenance;
+
+    if let (Some(topo), Some((_, w))) = (record.topology, outputs.topo.as_mut()) {
+        w.serialize_with_provenance(topo, provenance)?;
+    }
+
+    if let (Some(sweep), Some((_, w))) = (record.topology_sweep, outputs.topo.as_mut()) {
+        for topo in sweep {
+            w.serialize_with_provenance(topo, provenance)?;
+        }
+    }
+
+    if let (Some(brlens), Some((_, w))) = (record.branches, outputs.brlen.as_mut()) {
+        if compact {
+            let row = CompactBranchRecord::new(record.id.clone(), &brlens)?;
+            w.serialize_with_provenance(row, provenance)?;
+        } else {
+            for brlen in brlens {
+                w.serialize_with_provenance(brlen, provenance)?;
+            }
+        }
+    }
+
+    if let (Some(dists), Some((_, w))) = (record.distances, outputs.dist.as_mut()) {
+        if record.is_chunk {
+            for dist in dists {
+                w.serialize_pending(&record.id, dist, provenance)?;
+            }
+        } else if compact {
+            let row = CompactDistanceRecord::new(record.id.clone(), &dists, opts)?;
+            w.serialize_with_provenance(row, provenance)?;
+        } else {
+            for dist in dists {
+                w.serialize_with_provenance(dist, provenance)?;
+            }
+        }
+    }
+
+    if let (Some(clades), Some((_, w))) = (record.clades, outputs.clades.as_mut()) {
+        for clade in clades {
+            w.serialize_with_provenance(clade, provenance)?;
+        }
+    }
+
+    if let (Some(nodes), Some((_, w))) = (record.internal_nodes, outputs.internal.as_mut()) {
+        for node in nodes {
+            w.serialize_with_provenance(node, provenance)?;
+        }
+    }
+
+    if let (Some(canonical), Some((_, w))) = (record.canonical, outputs.canonical.as_mut()) {
+        w.serialize_with_provenance(canonical, provenance)?;
+    }
+
+    if let (Some(cherries), Some((_, w))) = (record.cherries, outputs.cherries.as_mut()) {
+        w.serialize_with_provenance(cherries, provenance)?;
+    }
+
+    if let (Some(roots), Some((_, w))) = (record.roots, outputs.roots.as_mut()) {
+        w.serialize_with_provenance(roots, provenance)?;
+    }
+
+    if let (Some(regression), Some((_, w))) =
+        (record.branch_regression, outputs.regression.as_mut())
+    {
+        w.serialize_with_provenance(regression, provenance)?;
+    }
+
+    if let (Some(summary), Some((_, w))) = (record.distance_summary, outputs.dist_summary.as_mut())
+    {
+        w.serialize_with_provenance(summary, provenance)?;
+    }
+
+    if let (Some(splits), Some((_, w))) = (record.compatibility, outputs.compat.as_mut()) {
+        for split in splits {
+            w.serialize_with_provenance(split, provenance)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Parse a fraction, between 0 and 1
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if !(0. ..=1.).contains(&fraction) {
+        return Err(format!("{fraction} is not between 0 and 1"));
+    }
+    Ok(fraction)
+}
+
+// Parse the index of a label field, starting at 1
+fn parse_label_field(s: &str) -> Result<usize, String> {
+    let field: usize = s.parse().map_err(|e| format!("{e}"))?;
+    if field == 0 {
+        return Err("fields are counted from 1".into());
+    }
+    Ok(field)
+}
+
+// Check if a tree is part of the sample. This only depends on the tree id and
+// the seed, not on the order in which trees are read.
+fn is_sampled(id: &str, seed: u64, fraction: f64) -> bool {
+    let hash = transform::hash_u64(&format!("{seed}:{id}"));
+    (hash as f64 / u64::MAX as f64) < fraction
+}
+
+// Assign a tree to the train or test split. As for sampling, this only
+// depends on the tree id and the seed, hashed differently so that splitting
+// sampled trees does not put them all in the same split.
+fn get_split(id: &str, seed: u64, fraction: f64) -> &'static str {
+    let hash = transform::hash_u64(&format!("{seed}:split:{id}"));
+    if (hash as f64 / u64::MAX as f64) < fraction {
+        "train"
+    } else {
+        "test"
+    }
+}
+
+// Extract the group name from a tree id
+fn get_group(re: &Regex, id: &str) -> Option<String> {
+    let caps = re.captures(id)?;
+    let group = caps.get(1).or_else(|| caps.get(0))?;
 
-    bar
+    Some(group.as_str().replace(std::path::MAIN_SEPARATOR, "_"))
 }
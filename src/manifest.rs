@@ -0,0 +1,195 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use phylocompare::{
+    comp::CompOpts,
+    io::{self, format_mtime, ReadOpts},
+    transform::{hash_bytes, hash_string},
+};
+
+/// Modification time and checksum of a compared tree file, with the
+/// checksums of its reference and of the options it was compared with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub id: String,
+    pub path: String,
+    pub mtime: String,
+    pub checksum: String,
+    // Missing from manifests written before they were added, so that their
+    // trees are compared again
+    #[serde(default)]
+    pub reference: String,
+    #[serde(default)]
+    pub options: String,
+}
+
+impl ManifestEntry {
+    fn new(id: &str, path: &Path, reference: &str, options: &str) -> Result<Self> {
+        let context = || format!("Could not fingerprint file: {}", path.display());
+        let mtime = fs::metadata(path)
+            .and_then(|m| m.modified())
+            .with_context(context)?;
+        let bytes = fs::read(path).with_context(context)?;
+
+        Ok(Self {
+            id: id.into(),
+            path: path.display().to_string(),
+            mtime: format_mtime(mtime),
+            checksum: hash_bytes(&bytes),
+            reference: reference.into(),
+            options: options.into(),
+        })
+    }
+}
+
+/// Checksum of the options trees are compared with, including the options
+/// used to read them
+pub fn options_checksum(opts: &CompOpts, read_opts: &ReadOpts) -> String {
+    // Distance groups are sorted to not depend on the order of the map
+    let groups = opts.distance_groups.iter().flatten().sorted().collect_vec();
+    let opts = CompOpts {
+        distance_groups: None,
+        ..opts.clone()
+    };
+    let tip_map = read_opts.tip_map.as_ref().map(|map| map.checksum());
+
+    hash_string(&format!(
+        "{opts:?}\n{groups:?}\n{tip_map:?}\n{:?}",
+        read_opts.id_segments
+    ))
+}
+
+/// Checksums of reference inputs, computed once however many trees use them.
+/// The checksum of a directory is made of the checksums of its tree files.
+#[derive(Default)]
+pub struct RefChecksums(HashMap<PathBuf, String>);
+
+impl RefChecksums {
+    pub fn get(&mut self, path: &Path) -> Result<String> {
+        if let Some(checksum) = self.0.get(path) {
+            return Ok(checksum.clone());
+        }
+
+        let context = || format!("Could not fingerprint reference: {}", path.display());
+        let checksum = if path.is_dir() {
+            let checksums = io::newick_paths(path)
+                .with_context(context)?
+                .sorted()
+                .map(|path| Ok(hash_bytes(&fs::read(&path).with_context(context)?)))
+                .collect::<Result<Vec<_>>>()?;
+            hash_string(&checksums.join("\n"))
+        } else {
+            hash_bytes(&fs::read(path).with_context(context)?)
+        };
+        self.0.insert(path.into(), checksum.clone());
+
+        Ok(checksum)
+    }
+}
+
+/// Input files of the previous run, used to only compare trees whose file
+/// changed since then. The manifest of the current run is made of the trees
+/// that were unchanged and the trees that were compared again.
+pub struct Manifest {
+    path: PathBuf,
+    // Checksum of the options of the current run
+    options: String,
+    previous: Option<HashMap<String, ManifestEntry>>,
+    // Trees that changed, until they are compared
+    pending: HashMap<String, ManifestEntry>,
+    entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Read the manifest of the previous run, if there was one. `options` is
+    /// the checksum of the options of the current run.
+    pub fn read(path: &Path, options: String) -> Result<Self> {
+        let previous = if path.exists() {
+            let context = || format!("Could not read manifest: {}", path.display());
+            let file = File::open(path).with_context(context)?;
+            let previous = csv::Reader::from_reader(file)
+                .deserialize()
+                .map(|row| {
+                    let entry: ManifestEntry = row.with_context(context)?;
+                    Ok((entry.id.clone(), entry))
+                })
+                .collect::<Result<_>>()?;
+            Some(previous)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            path: path.into(),
+            options,
+            previous,
+            pending: HashMap::new(),
+            entries: vec![],
+        })
+    }
+
+    /// Whether there was a previous run to update
+    pub fn has_previous(&self) -> bool {
+        self.previous.is_some()
+    }
+
+    /// Check if a tree file has the same modification time and checksum as
+    /// in the previous run, and was compared to the same reference (given by
+    /// its checksum) with the same options. Changed trees are only added to
+    /// the manifest once they are compared, with [`Manifest::confirm`].
+    pub fn is_unchanged(&mut self, id: &str, path: &Path, reference: &str) -> Result<bool> {
+        let entry = ManifestEntry::new(id, path, reference, &self.options)?;
+        let unchanged = self
+            .previous
+            .as_ref()
+            .and_then(|previous| previous.get(id))
+            .is_some_and(|previous| {
+                previous.mtime == entry.mtime
+                    && previous.checksum == entry.checksum
+                    && previous.reference == entry.reference
+                    && previous.options == entry.options
+            });
+
+        if unchanged {
+            self.entries.push(entry);
+        } else {
+            self.pending.insert(id.into(), entry);
+        }
+
+        Ok(unchanged)
+    }
+
+    /// Add a tree to the manifest after comparing it
+    pub fn confirm(&mut self, id: &str) {
+        if let Some(entry) = self.pending.remove(id) {
+            self.entries.push(entry)
+        }
+    }
+
+    /// Overwrite the manifest with the trees of the current run
+    pub fn write(&self) -> Result<()> {
+        let file = File::create(&self.path).context(format!(
+            "Could not create manifest: {}",
+            self.path.display()
+        ))?;
+        let mut writer = csv::Writer::from_writer(file);
+        for entry in self.entries.iter() {
+            writer.serialize(entry)?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    // Print where the manifest was written
+    pub fn report(&self) {
+        eprintln!("Wrote run manifest to:  {}", self.path.display())
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use phylotree::tree::Tree;
+
+use phylocompare::{
+    io::round_float,
+    splits::{get_branches, get_clades, get_union_taxa},
+};
+
+// Quote a label for Newick if it has characters with a meaning in Newick
+fn quote(label: &str) -> String {
+    if label.contains(|c: char| c.is_whitespace() || "()[]':;,".contains(c)) {
+        format!("'{}'", label.replace('\'', "''"))
+    } else {
+        label.into()
+    }
+}
+
+/// Write the reference tree as a Nexus file to `<dir>/<id>.nex`, each branch
+/// being annotated with the absolute difference between its length and the
+/// length of the same branch in the comparison tree, as a `[&diff=...]`
+/// comment read by tree viewers such as FigTree. Branches missing from the
+/// comparison tree are not annotated. Differences are rounded to `precision`
+/// significant figures if set.
+pub fn write_annotated(
+    dir: &Path,
+    id: &str,
+    reftree: &Tree,
+    cmptree: &Tree,
+    precision: Option<usize>,
+) -> Result<()> {
+    let path = dir.join(format!("{id}.nex"));
+
+    let taxa = get_union_taxa(reftree, cmptree)?;
+    let ref_branches = get_branches(reftree, &taxa)?;
+    let cmp_branches = get_branches(cmptree, &taxa)?;
+    let clades = get_clades(reftree, &taxa)?;
+
+    // Newick strings of the subtrees, built from the tips up
+    let root = reftree.get_root()?;
+    let mut subtrees: HashMap<_, String> = HashMap::new();
+    for node_id in reftree.preorder(&root)?.into_iter().rev() {
+        let node = reftree.get(&node_id)?;
+        let mut newick = if node.is_tip() {
+            String::new()
+        } else {
+            let children: Vec<_> = node
+                .children
+                .iter()
+                .map(|child| subtrees.remove(child).unwrap_or_default())
+                .collect();
+            format!("({})", children.join(","))
+        };
+        newick.push_str(&quote(node.name.as_deref().unwrap_or_default()));
+
+        if node.parent.is_some() {
+            // Both branches below a bifurcating root are one branch, with
+            // the same difference
+            let mut split = clades[&node_id].clone();
+            if split.contains(0) {
+                split.toggle_range(..);
+            }
+            if let (Some(branch), Some(other)) =
+                (ref_branches.get(&split), cmp_branches.get(&split))
+            {
+                newick.push_str(&format!(
+                    "[&diff={}]",
+                    round_float((branch.1 - other.1).abs(), precision)
+                ));
+            }
+            if let Some(len) = node.parent_edge {
+                newick.push_str(&format!(":{len}"));
+            }
+        }
+        subtrees.insert(node_id, newick);
+    }
+    let newick = subtrees.remove(&root).unwrap_or_default();
+
+    let mut writer = BufWriter::new(
+        File::create(&path).context(format!("Could not write: {}", path.display()))?,
+    );
+    writeln!(writer, "#NEXUS")?;
+    writeln!(writer, "begin trees;")?;
+    writeln!(writer, "\ttree {} = [&R] {newick};", quote(id))?;
+    writeln!(writer, "end;")?;
+    writer.flush()?;
+
+    Ok(())
+}
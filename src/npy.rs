@@ -0,0 +1,87 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use phylotree::tree::Tree;
+
+use phylocompare::{
+    comp::{tip_distances, CompOpts},
+    io::{check_overwrite, get_suffixed_filenme},
+};
+
+// Write a square float64 matrix in the NumPy .npy format (version 1.0)
+fn write_npy(path: &Path, matrix: &[f64], size: usize) -> Result<()> {
+    let mut header =
+        format!("{{'descr': '<f8', 'fortran_order': False, 'shape': ({size}, {size}), }}");
+    // The magic string, version and header length take 10 bytes, and the
+    // data must start on a multiple of 64 bytes
+    let padding = 63 - (10 + header.len()) % 64;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())?;
+    for value in matrix {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+// Paths of the reference and comparison matrices, and of the taxa, of a tree
+fn paths(prefix: &PathBuf, id: &str) -> Result<[PathBuf; 3]> {
+    Ok([
+        get_suffixed_filenme(prefix, &format!("{id}_ref_dist"), "npy", false)?,
+        get_suffixed_filenme(prefix, &format!("{id}_cmp_dist"), "npy", false)?,
+        get_suffixed_filenme(prefix, &format!("{id}_taxa"), "json", false)?,
+    ])
+}
+
+/// Check that none of the files of these trees exist yet, unless they can
+/// be overwritten
+pub fn check_existing<'a>(
+    prefix: &PathBuf,
+    ids: impl Iterator<Item = &'a str>,
+    force: bool,
+) -> Result<()> {
+    let files = ids
+        .map(|id| paths(prefix, id))
+        .collect::<Result<Vec<_>>>()?;
+    check_overwrite(files.iter().flatten(), force)
+}
+
+/// Write the reference and comparison distance matrices of a tree as .npy
+/// files, along with a JSON file listing the taxon of each row. Both matrices
+/// follow the order of the reference tips, distances involving tips missing
+/// from the comparison tree being NaN.
+pub fn write_distances(
+    prefix: &PathBuf,
+    id: &str,
+    reftree: &Tree,
+    cmptree: &Tree,
+    opts: &CompOpts,
+) -> Result<()> {
+    let (ref_dists, cmp_dists) = tip_distances(reftree, cmptree, opts)?;
+    let taxa = &ref_dists.taxa;
+
+    let [ref_path, cmp_path, taxa_path] = paths(prefix, id)?;
+    write_npy(&ref_path, &ref_dists.matrix(taxa), taxa.len())
+        .context(format!("Could not write: {}", ref_path.display()))?;
+    write_npy(&cmp_path, &cmp_dists.matrix(taxa), taxa.len())
+        .context(format!("Could not write: {}", cmp_path.display()))?;
+    serde_json::to_writer(
+        BufWriter::new(
+            File::create(&taxa_path)
+                .context(format!("Could not write: {}", taxa_path.display()))?,
+        ),
+        taxa,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,153 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_json::json;
+
+// Time between two progress lines in plain and json formats
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How progress is reported on stderr
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    /// Interactive spinners and progress bars
+    #[default]
+    Bar,
+    /// Periodic plain text lines
+    Plain,
+    /// Periodic JSON events, one per line
+    Json,
+    /// No progress reporting
+    None,
+}
+
+/// Progress of one phase of the run, reported in the chosen format
+pub struct Progress {
+    format: ProgressFormat,
+    phase: &'static str,
+    total: u64,
+    done: AtomicU64,
+    start: Instant,
+    last_report: Mutex<Instant>,
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    fn new(
+        format: ProgressFormat,
+        phase: &'static str,
+        total: u64,
+        bar: Option<ProgressBar>,
+    ) -> Self {
+        Self {
+            format,
+            phase,
+            total,
+            done: AtomicU64::new(0),
+            start: Instant::now(),
+            last_report: Mutex::new(Instant::now()),
+            bar,
+        }
+    }
+
+    /// Progress shown as a spinner with a message in the `bar` format
+    pub fn spinner(format: ProgressFormat, phase: &'static str, total: u64) -> Self {
+        let bar = (format == ProgressFormat::Bar).then(|| init_spinner(total, phase));
+        Self::new(format, phase, total, bar)
+    }
+
+    /// Progress shown as a progress bar with an ETA in the `bar` format
+    pub fn bar(format: ProgressFormat, phase: &'static str, total: u64) -> Self {
+        let bar = (format == ProgressFormat::Bar).then(|| init_bar(total));
+        Self::new(format, phase, total, bar)
+    }
+
+    pub fn inc(&self, delta: u64) {
+        let done = self.done.fetch_add(delta, Ordering::Relaxed) + delta;
+        if let Some(bar) = &self.bar {
+            bar.inc(delta);
+            return;
+        }
+
+        if let Ok(mut last) = self.last_report.try_lock() {
+            if last.elapsed() >= REPORT_INTERVAL {
+                *last = Instant::now();
+                self.report("progress", done);
+            }
+        }
+    }
+
+    pub fn finish(&self, message: &'static str) {
+        if let Some(bar) = &self.bar {
+            bar.finish_with_message(message);
+            return;
+        }
+        self.report("finish", self.done.load(Ordering::Relaxed));
+    }
+
+    // Estimated time left, extrapolated from the average time per item
+    fn eta(&self, done: u64) -> Option<Duration> {
+        if done == 0 || done >= self.total {
+            return None;
+        }
+        let per_item = self.start.elapsed().as_secs_f64() / done as f64;
+        Some(Duration::from_secs_f64(
+            per_item * (self.total - done) as f64,
+        ))
+    }
+
+    fn report(&self, event: &str, done: u64) {
+        let eta = self.eta(done);
+        match self.format {
+            ProgressFormat::Plain => match eta {
+                Some(eta) => eprintln!(
+                    "[{}] {event}: {done}/{} (ETA {}s)",
+                    self.phase,
+                    self.total,
+                    eta.as_secs()
+                ),
+                None => eprintln!("[{}] {event}: {done}/{}", self.phase, self.total),
+            },
+            ProgressFormat::Json => eprintln!(
+                "{}",
+                json!({
+                    "event": event,
+                    "phase": self.phase,
+                    "done": done,
+                    "total": self.total,
+                    "eta_secs": eta.map(|eta| eta.as_secs()),
+                })
+            ),
+            ProgressFormat::Bar | ProgressFormat::None => {}
+        }
+    }
+}
+
+fn init_bar(len: u64) -> ProgressBar {
+    let bar = ProgressBar::new(len);
+    let bar_style =
+        ProgressStyle::with_template("{wide_bar:.cyan/blue} {pos}/{len} ({elapsed}, ETA {eta})")
+            .unwrap()
+            .progress_chars("##-");
+    bar.set_style(bar_style);
+
+    bar
+}
+
+fn init_spinner(len: u64, message: &'static str) -> ProgressBar {
+    let bar = ProgressBar::new(len);
+    bar.enable_steady_tick(Duration::from_millis(80));
+    let spinner_style = ProgressStyle::with_template("{spinner:.cyan} {wide_msg}")
+        .unwrap()
+        .tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏");
+    bar.set_style(spinner_style);
+    bar.set_message(message);
+
+    bar
+}
@@ -0,0 +1,1136 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use fixedbitset::FixedBitSet;
+use phylotree::tree::{Comparison, Node, NodeId, Tree};
+
+/// Sorted tip labels of a tree, used to index tips in split bitsets
+pub fn get_taxa(tree: &Tree) -> Result<Vec<String>> {
+    let mut taxa = tree
+        .get_leaves()
+        .iter()
+        .map(|id| {
+            tree.get(id)?
+                .name
+                .clone()
+                .context("All tips must be named to compare splits")
+        })
+        .collect::<Result<Vec<_>>>()?;
+    taxa.sort();
+
+    Ok(taxa)
+}
+
+/// Sorted union of the tip labels of two trees
+pub fn get_union_taxa(reftree: &Tree, cmptree: &Tree) -> Result<Vec<String>> {
+    let mut taxa = get_taxa(reftree)?;
+    taxa.extend(get_taxa(cmptree)?);
+    taxa.sort();
+    taxa.dedup();
+
+    Ok(taxa)
+}
+
+/// Get the set of tips below each node of the tree, encoded as bitsets
+/// indexed on `taxa`
+pub fn get_clades(tree: &Tree, taxa: &[String]) -> Result<HashMap<NodeId, FixedBitSet>> {
+    let root = tree.get_root()?;
+    let mut clades: HashMap<NodeId, FixedBitSet> = HashMap::new();
+
+    for id in tree.postorder(&root)? {
+        let node = tree.get(&id)?;
+        let mut clade = FixedBitSet::with_capacity(taxa.len());
+        if node.is_tip() {
+            let name = node.name.as_deref().unwrap_or_default();
+            let idx = taxa
+                .binary_search_by(|t| t.as_str().cmp(name))
+                .ok()
+                .context(format!("Tip `{name}` is not in the taxa index"))?;
+            clade.insert(idx);
+        } else {
+            for child in node.children.iter() {
+                clade.union_with(&clades[child]);
+            }
+        }
+        clades.insert(id, clade);
+    }
+
+    Ok(clades)
+}
+
+/// Get the clades below labeled internal nodes, keyed by label. Labels found
+/// on several nodes map to `None`.
+pub fn get_labeled_clades(
+    tree: &Tree,
+    taxa: &[String],
+) -> Result<HashMap<String, Option<FixedBitSet>>> {
+    let mut labeled = HashMap::new();
+
+    for (id, clade) in get_clades(tree, taxa)? {
+        let node = tree.get(&id)?;
+        let Some(label) = node.name.as_ref().filter(|_| !node.is_tip()) else {
+            continue;
+        };
+        labeled
+            .entry(label.clone())
+            .and_modify(|c| *c = None)
+            .or_insert(Some(clade));
+    }
+
+    Ok(labeled)
+}
+
+/// Check if each set of tips forms a clade in the tree. Tips that are not
+/// in the tree are ignored, if none of the tips are in the tree the result
+/// is `None`.
+pub fn monophyly(tree: &Tree, tip_sets: &[&[String]]) -> Result<Vec<Option<bool>>> {
+    let taxa = get_taxa(tree)?;
+    let clades: HashSet<_> = get_clades(tree, &taxa)?.into_values().collect();
+
+    Ok(tip_sets
+        .iter()
+        .map(|tips| {
+            let mut target = FixedBitSet::with_capacity(taxa.len());
+            for tip in tips.iter() {
+                if let Ok(idx) = taxa.binary_search(tip) {
+                    target.insert(idx);
+                }
+            }
+            if target.count_ones(..) == 0 {
+                None
+            } else {
+                Some(clades.contains(&target))
+            }
+        })
+        .collect())
+}
+
+/// Get the non-trivial splits (bipartitions) of a tree. Splits are stored
+/// as the side that does not contain the first taxon, so that both sides of
+/// a bipartition have the same representation.
+pub fn get_splits(tree: &Tree, taxa: &[String]) -> Result<HashSet<FixedBitSet>> {
+    let n = taxa.len();
+    let splits = get_clades(tree, taxa)?
+        .into_values()
+        .map(|mut clade| {
+            if clade.contains(0) {
+                clade.toggle_range(..);
+            }
+            clade
+        })
+        .filter(|split| {
+            let size = split.count_ones(..);
+            size > 1 && size + 1 < n
+        })
+        .collect();
+
+    Ok(splits)
+}
+
+/// Precision (fraction of compared splits found in the reference), recall
+/// (fraction of reference splits found in the compared tree) and F1 score of
+/// the splits of a compared tree. Ratios without any split are NaN, and the
+/// F1 score is 0 when no split is shared.
+pub fn precision_recall(
+    ref_splits: &HashSet<FixedBitSet>,
+    cmp_splits: &HashSet<FixedBitSet>,
+) -> (f64, f64, f64) {
+    let shared = ref_splits.intersection(cmp_splits).count() as f64;
+    let ratio = |total: usize| {
+        if total == 0 {
+            f64::NAN
+        } else {
+            shared / total as f64
+        }
+    };
+    let (precision, recall) = (ratio(cmp_splits.len()), ratio(ref_splits.len()));
+    let f1 = if precision + recall == 0. {
+        0.
+    } else {
+        2. * precision * recall / (precision + recall)
+    };
+
+    (precision, recall, f1)
+}
+
+/// Check if two splits, stored as the side without the first taxon, can be
+/// found in the same tree
+pub fn are_compatible(a: &FixedBitSet, b: &FixedBitSet) -> bool {
+    a.is_disjoint(b) || a.is_subset(b) || b.is_subset(a)
+}
+
+/// Asymmetric RF distance to a reference with polytomies: the number of
+/// reference splits missing from the compared tree, plus the number of
+/// compared splits contradicting (incompatible with) a reference split.
+/// Compared splits resolving a polytomy of the reference are not counted,
+/// so the distance is 0 if and only if the compared tree is a refinement of
+/// the reference.
+pub fn refinement_rf(
+    ref_splits: &HashSet<FixedBitSet>,
+    cmp_splits: &HashSet<FixedBitSet>,
+) -> usize {
+    let missing = ref_splits.difference(cmp_splits).count();
+    let contradicting = cmp_splits
+        .difference(ref_splits)
+        .filter(|split| !ref_splits.iter().all(|r| are_compatible(split, r)))
+        .count();
+
+    missing + contradicting
+}
+
+/// Get the non-trivial clades of a rooted tree: sets of tips below an
+/// internal node, excluding the clade of all tips
+pub fn get_rooted_clades(tree: &Tree, taxa: &[String]) -> Result<HashSet<FixedBitSet>> {
+    let n = taxa.len();
+    let clades = get_clades(tree, taxa)?
+        .into_values()
+        .filter(|clade| {
+            let size = clade.count_ones(..);
+            size > 1 && size < n
+        })
+        .collect();
+
+    Ok(clades)
+}
+
+/// Robinson-Foulds distance on rooted clades (clusters): the number of
+/// non-trivial clades present in only one of the trees. Unlike the RF
+/// distance on bipartitions, the two sides of a split are not equivalent,
+/// so the root position matters: re-rooting a tree changes its clades but
+/// not its bipartitions.
+pub fn clade_rf(reftree: &Tree, cmptree: &Tree) -> Result<f64> {
+    let taxa = get_taxa(reftree)?;
+    if taxa != get_taxa(cmptree)? {
+        bail!("Trees must have the same tips to compare their clades")
+    }
+    let ref_clades = get_rooted_clades(reftree, &taxa)?;
+    let cmp_clades = get_rooted_clades(cmptree, &taxa)?;
+
+    Ok(ref_clades.symmetric_difference(&cmp_clades).count() as f64)
+}
+
+/// Size of the maximum agreement subtree (MAST) of two rooted trees: the
+/// largest number of tips such that both trees, restricted to these tips,
+/// have the same topology.
+///
+/// It is computed with the dynamic programming algorithm of Steel & Warnow
+/// (1993) over all pairs of nodes `(u, v)`, from the tips up: the MAST of the
+/// subtrees below `u` and `v` either lies below one child of `u` (or of `v`),
+/// or pairs children of `u` with children of `v`, the best pairing being a
+/// maximum weight matching. This takes O(n²) time and memory for binary
+/// trees with `n` tips, with an additional O(d³) matching for each pair of
+/// nodes with `d` children in multifurcating trees.
+///
+/// Reference:
+/// - Steel M., Warnow T. (1993) Kaikoura tree theorems: computing the
+///   maximum agreement subtree. Information Processing Letters 48(2):77-82.
+pub fn mast_size(reftree: &Tree, cmptree: &Tree) -> Result<usize> {
+    let taxa = get_taxa(reftree)?;
+    if taxa != get_taxa(cmptree)? {
+        bail!("Trees must have the same tips to compute their agreement subtree")
+    }
+    let ref_clades = get_clades(reftree, &taxa)?;
+    let cmp_clades = get_clades(cmptree, &taxa)?;
+
+    // Nodes in postorder, so that children are handled before their parent
+    let ref_nodes = reftree.postorder(&reftree.get_root()?)?;
+    let cmp_nodes = cmptree.postorder(&cmptree.get_root()?)?;
+    let ref_index: HashMap<_, _> = ref_nodes.iter().enumerate().map(|(i, n)| (*n, i)).collect();
+    let cmp_index: HashMap<_, _> = cmp_nodes.iter().enumerate().map(|(i, n)| (*n, i)).collect();
+
+    let mut mast = vec![vec![0_u32; cmp_nodes.len()]; ref_nodes.len()];
+    for (i, u) in ref_nodes.iter().enumerate() {
+        let u_children: Vec<_> = reftree
+            .get(u)?
+            .children
+            .iter()
+            .map(|c| ref_index[c])
+            .collect();
+        for (j, v) in cmp_nodes.iter().enumerate() {
+            let v_children: Vec<_> = cmptree
+                .get(v)?
+                .children
+                .iter()
+                .map(|c| cmp_index[c])
+                .collect();
+
+            // A tip agrees with a subtree if the subtree contains it
+            if u_children.is_empty() || v_children.is_empty() {
+                mast[i][j] = u32::from(!ref_clades[u].is_disjoint(&cmp_clades[v]));
+                continue;
+            }
+
+            let below_u = u_children.iter().map(|&c| mast[c][j]).max().unwrap_or(0);
+            let below_v = v_children.iter().map(|&c| mast[i][c]).max().unwrap_or(0);
+            let matched = match (u_children.as_slice(), v_children.as_slice()) {
+                (&[u1, u2], &[v1, v2]) => {
+                    u32::max(mast[u1][v1] + mast[u2][v2], mast[u1][v2] + mast[u2][v1])
+                }
+                _ => {
+                    // Maximum weight matching, as a minimum cost assignment
+                    let size = u_children.len().max(v_children.len());
+                    let mut costs = vec![vec![0.; size]; size];
+                    for (a, &uc) in u_children.iter().enumerate() {
+                        for (b, &vc) in v_children.iter().enumerate() {
+                            costs[a][b] = -f64::from(mast[uc][vc]);
+                        }
+                    }
+                    hungarian(&costs)
+                        .into_iter()
+                        .enumerate()
+                        .map(|(a, b)| -costs[a][b])
+                        .sum::<f64>() as u32
+                }
+            };
+            mast[i][j] = below_u.max(below_v).max(matched);
+        }
+    }
+
+    // Roots are last in postorder
+    Ok(mast
+        .last()
+        .and_then(|row| row.last())
+        .copied()
+        .unwrap_or_default() as usize)
+}
+
+/// Get the cherries of a tree: pairs of tips that are the only two children
+/// of their parent. The children of a polytomy do not form cherries. Tips
+/// of a pair are sorted by name.
+pub fn get_cherries(tree: &Tree) -> Result<HashSet<(String, String)>> {
+    let root = tree.get_root()?;
+    let mut cherries = HashSet::new();
+
+    for id in tree.preorder(&root)? {
+        let node = tree.get(&id)?;
+        let &[a, b] = node.children.as_slice() else {
+            continue;
+        };
+        let (a, b) = (tree.get(&a)?, tree.get(&b)?);
+        if !(a.is_tip() && b.is_tip()) {
+            continue;
+        }
+        let name = |n: &Node| {
+            n.name
+                .clone()
+                .context("All tips must be named to compare cherries")
+        };
+        let (a, b) = (name(a)?, name(b)?);
+        cherries.insert(if a <= b { (a, b) } else { (b, a) });
+    }
+
+    Ok(cherries)
+}
+
+// Restrict splits to the tips other than `removed`, keeping non-trivial ones
+// stored as the side without the first remaining tip
+fn prune_splits(splits: &HashSet<FixedBitSet>, removed: usize, n: usize) -> HashSet<FixedBitSet> {
+    let first = usize::from(removed == 0);
+    splits
+        .iter()
+        .map(|split| {
+            let mut split = split.clone();
+            split.set(removed, false);
+            if split.contains(first) {
+                split.toggle_range(..);
+                split.set(removed, false);
+            }
+            split
+        })
+        .filter(|split| {
+            let size = split.count_ones(..);
+            size > 1 && size + 2 < n
+        })
+        .collect()
+}
+
+/// Decrease of the RF distance between two trees when each tip is pruned
+/// from both of them, as a measure of how much each tip is misplaced. The
+/// trees must have the same tips. This takes O(n³) time with `n` tips.
+pub fn rf_drop_by_tip(reftree: &Tree, cmptree: &Tree) -> Result<Vec<(String, f64)>> {
+    let (n, ref_splits, cmp_splits) = get_shared_splits(reftree, cmptree)?;
+    let taxa = get_taxa(reftree)?;
+    let rf = ref_splits.symmetric_difference(&cmp_splits).count();
+
+    Ok(taxa
+        .into_iter()
+        .enumerate()
+        .map(|(i, tip)| {
+            let ref_pruned = prune_splits(&ref_splits, i, n);
+            let cmp_pruned = prune_splits(&cmp_splits, i, n);
+            let pruned_rf = ref_pruned.symmetric_difference(&cmp_pruned).count();
+            (tip, rf as f64 - pruned_rf as f64)
+        })
+        .collect())
+}
+
+// Restrict splits to the `kept` tips, keeping non-trivial ones stored as
+// the side without the first kept tip
+fn restrict_splits(splits: &HashSet<FixedBitSet>, kept: &FixedBitSet) -> HashSet<FixedBitSet> {
+    let n = kept.count_ones(..);
+    let first = kept.ones().next();
+    splits
+        .iter()
+        .map(|split| {
+            let mut split = split.clone();
+            split.intersect_with(kept);
+            if first.is_some_and(|first| split.contains(first)) {
+                split.toggle_range(..);
+                split.intersect_with(kept);
+            }
+            split
+        })
+        .filter(|split| {
+            let size = split.count_ones(..);
+            size > 1 && size + 1 < n
+        })
+        .collect()
+}
+
+/// Approximate SPR distance between two trees with the same tips, as
+/// unrooted trees: the number of tips pruned from both trees until they
+/// have the same splits, greedily pruning the tip whose removal leaves the
+/// smallest RF distance (the first one on ties). Each pruned tip can be
+/// regrafted at its place with one SPR move, so this is an upper bound of
+/// the SPR distance, itself at least the TBR distance. It is 0 only for
+/// identical topologies. This takes O(k n³) time with `n` tips, `k` of
+/// them pruned.
+pub fn approx_spr(reftree: &Tree, cmptree: &Tree) -> Result<usize> {
+    let (n, mut ref_splits, mut cmp_splits) = get_shared_splits(reftree, cmptree)?;
+    let mut kept = FixedBitSet::with_capacity(n);
+    kept.insert_range(..);
+
+    let mut n_pruned = 0;
+    while ref_splits != cmp_splits {
+        let pruned_rf = |tip: usize| {
+            let mut kept = kept.clone();
+            kept.set(tip, false);
+            restrict_splits(&ref_splits, &kept)
+                .symmetric_difference(&restrict_splits(&cmp_splits, &kept))
+                .count()
+        };
+        let Some(tip) = kept.ones().min_by_key(|&tip| pruned_rf(tip)) else {
+            break;
+        };
+
+        kept.set(tip, false);
+        ref_splits = restrict_splits(&ref_splits, &kept);
+        cmp_splits = restrict_splits(&cmp_splits, &kept);
+        n_pruned += 1;
+    }
+
+    Ok(n_pruned)
+}
+
+/// Get the splits of both trees, checking that they share the same tips
+pub fn get_shared_splits(
+    reftree: &Tree,
+    cmptree: &Tree,
+) -> Result<(usize, HashSet<FixedBitSet>, HashSet<FixedBitSet>)> {
+    let taxa = get_taxa(reftree)?;
+    if taxa != get_taxa(cmptree)? {
+        bail!("Trees must have the same tips to compare their splits")
+    }
+
+    Ok((
+        taxa.len(),
+        get_splits(reftree, &taxa)?,
+        get_splits(cmptree, &taxa)?,
+    ))
+}
+
+/// Distance between the root positions of two trees with the same unrooted
+/// topology, in number of branches of the unrooted tree. A root with two
+/// children lies at the middle of a branch of the unrooted tree, so the
+/// distance is a multiple of 0.5, and 0 if the roots coincide.
+pub fn root_distance(reftree: &Tree, cmptree: &Tree, taxa: &[String]) -> Result<f64> {
+    let ref_root = reftree.get_root()?;
+    let cmp_root = cmptree.get_root()?;
+    let ref_clades = get_clades(reftree, taxa)?;
+    let cmp_clades = get_clades(cmptree, taxa)?;
+    let normalize = |clade: &FixedBitSet| {
+        let mut split = clade.clone();
+        if split.contains(0) {
+            split.toggle_range(..);
+        }
+        split
+    };
+
+    // Distance of each node of the reference to its root, the two branches
+    // below a bifurcating root being halves of a single branch
+    let ref_bifurcating = reftree.get(&ref_root)?.children.len() == 2;
+    let mut dists = HashMap::from([(ref_root, 0.)]);
+    for id in reftree.preorder(&ref_root)? {
+        let Some(parent) = reftree.get(&id)?.parent else {
+            continue;
+        };
+        let len = if parent == ref_root && ref_bifurcating {
+            0.5
+        } else {
+            1.
+        };
+        dists.insert(id, dists[&parent] + len);
+    }
+
+    let cmp_children = &cmptree.get(&cmp_root)?.children;
+    if cmp_children.len() == 2 {
+        // The compared root is on the branch inducing this split
+        let split = normalize(&cmp_clades[&cmp_children[0]]);
+        for (id, clade) in ref_clades.iter() {
+            let Some(parent) = reftree.get(id)?.parent else {
+                continue;
+            };
+            if normalize(clade) != split {
+                continue;
+            }
+            if parent == ref_root && ref_bifurcating {
+                return Ok(0.);
+            }
+            return Ok(dists[id] - 0.5);
+        }
+    } else {
+        // The compared root is on the node with the same components
+        let components: HashSet<_> = cmp_children.iter().map(|c| &cmp_clades[c]).collect();
+        for (id, clade) in ref_clades.iter() {
+            let node = reftree.get(id)?;
+            if node.is_tip() || (*id == ref_root && ref_bifurcating) {
+                continue;
+            }
+            let mut rest = clade.clone();
+            rest.toggle_range(..);
+            let mut node_components: HashSet<_> =
+                node.children.iter().map(|c| &ref_clades[c]).collect();
+            if node.parent.is_some() {
+                node_components.insert(&rest);
+            }
+            if node_components == components {
+                return Ok(dists[id]);
+            }
+        }
+    }
+
+    bail!("Could not find the root of the compared tree in the reference")
+}
+
+/// A branch, described by its depth (number of branches between the root and
+/// the lower node of the branch) and its length
+pub type Branch = (usize, f64);
+
+/// Branches exclusive to the reference, exclusive to the compared tree, and
+/// branches common to both
+pub type BranchPairs = (Vec<Branch>, Vec<Branch>, Vec<(Branch, Branch)>);
+
+/// Get all the branches of a tree, terminal ones included, keyed by the
+/// split they induce. The two branches below a bifurcating root induce the
+/// same split and are merged into a single branch.
+pub fn get_branches(tree: &Tree, taxa: &[String]) -> Result<HashMap<FixedBitSet, Branch>> {
+    let root = tree.get_root()?;
+    let clades = get_clades(tree, taxa)?;
+    let mut depths = HashMap::from([(root, 0)]);
+    let mut branches: HashMap<FixedBitSet, Branch> = HashMap::new();
+
+    for id in tree.preorder(&root)? {
+        let node = tree.get(&id)?;
+        let Some(parent) = node.parent else {
+            continue;
+        };
+        let depth = depths[&parent] + 1;
+        depths.insert(id, depth);
+
+        let len = node
+            .parent_edge
+            .context("All branches must have a length to compare them")?;
+        let mut split = clades[&id].clone();
+        if split.contains(0) {
+            split.toggle_range(..);
+        }
+
+        branches
+            .entry(split)
+            .and_modify(|(_, l)| *l += len)
+            .or_insert((depth, len));
+    }
+
+    Ok(branches)
+}
+
+/// Pair the branches of two trees by the split they induce.
+///
+/// Only internal branches are returned, unless `include_tips` is set. If
+/// `tips_only` is set only terminal branches are returned.
+pub fn compare_branches(
+    reftree: &Tree,
+    cmptree: &Tree,
+    include_tips: bool,
+    tips_only: bool,
+) -> Result<BranchPairs> {
+    let (n, ref_branches, cmp_branches) = get_shared_branches(reftree, cmptree)?;
+    Ok(pair_branches(
+        n,
+        &ref_branches,
+        &cmp_branches,
+        include_tips,
+        tips_only,
+    ))
+}
+
+/// Compare the topologies of two trees and pair their branches, extracting
+/// their splits only once. Branches are paired as in [`compare_branches`].
+///
+/// Topologies are compared as phylotree's `compare_topologies` does, over
+/// internal branches only, the two branches below a bifurcating root being
+/// a single branch: the RF distance is the number of splits found in only
+/// one of the trees, normalized by the total number of splits of both
+/// trees. The weighted RF and the branch score (Kuhner & Felsenstein) are
+/// the sum of absolute length differences and the square root of the sum
+/// of squared length differences, a branch missing from a tree having a
+/// length of 0.
+pub fn compare_topologies_and_branches(
+    reftree: &Tree,
+    cmptree: &Tree,
+    include_tips: bool,
+    tips_only: bool,
+) -> Result<(Comparison, BranchPairs)> {
+    let (n, ref_branches, cmp_branches) = get_shared_branches(reftree, cmptree)?;
+
+    let (mut rf, mut n_splits) = (0, 0);
+    let (mut weighted_rf, mut squares) = (0., 0.);
+    for (split, (_, len)) in ref_branches.iter() {
+        if is_terminal(split, n) {
+            continue;
+        }
+        n_splits += 1;
+        let other = match cmp_branches.get(split) {
+            Some((_, other)) => *other,
+            None => {
+                rf += 1;
+                0.
+            }
+        };
+        weighted_rf += (len - other).abs();
+        squares += (len - other) * (len - other);
+    }
+    for (split, (_, len)) in cmp_branches.iter() {
+        if is_terminal(split, n) {
+            continue;
+        }
+        n_splits += 1;
+        if !ref_branches.contains_key(split) {
+            rf += 1;
+            weighted_rf += len.abs();
+            squares += len * len;
+        }
+    }
+
+    let comparison = Comparison {
+        rf: rf as f64,
+        norm_rf: rf as f64 / n_splits as f64,
+        weighted_rf,
+        branch_score: squares.sqrt(),
+    };
+    let pairs = pair_branches(n, &ref_branches, &cmp_branches, include_tips, tips_only);
+
+    Ok((comparison, pairs))
+}
+
+// Check if a split is induced by a terminal branch, i.e. one side of the
+// split is a single tip
+fn is_terminal(split: &FixedBitSet, n_taxa: usize) -> bool {
+    let size = split.count_ones(..);
+    size <= 1 || size + 1 >= n_taxa
+}
+
+// Number of tips and branches of two trees with the same tips
+fn get_shared_branches(
+    reftree: &Tree,
+    cmptree: &Tree,
+) -> Result<(
+    usize,
+    HashMap<FixedBitSet, Branch>,
+    HashMap<FixedBitSet, Branch>,
+)> {
+    let taxa = get_taxa(reftree)?;
+    if taxa != get_taxa(cmptree)? {
+        bail!("Trees must have the same tips to compare their splits")
+    }
+
+    Ok((
+        taxa.len(),
+        get_branches(reftree, &taxa)?,
+        get_branches(cmptree, &taxa)?,
+    ))
+}
+
+// Pair branches by split, keeping internal branches, terminal ones too if
+// `include_tips` is set, or only terminal ones if `tips_only` is set
+fn pair_branches(
+    n_taxa: usize,
+    ref_branches: &HashMap<FixedBitSet, Branch>,
+    cmp_branches: &HashMap<FixedBitSet, Branch>,
+    include_tips: bool,
+    tips_only: bool,
+) -> BranchPairs {
+    let keep = |split: &FixedBitSet| {
+        let terminal = is_terminal(split, n_taxa);
+        if tips_only {
+            terminal
+        } else {
+            include_tips || !terminal
+        }
+    };
+    let (mut reference, mut compared, mut common) = (vec![], vec![], vec![]);
+
+    for (split, &branch) in ref_branches.iter().filter(|(split, _)| keep(split)) {
+        match cmp_branches.get(split) {
+            Some(&other) => common.push((branch, other)),
+            None => reference.push(branch),
+        }
+    }
+
+    for (split, &branch) in cmp_branches.iter().filter(|(split, _)| keep(split)) {
+        if !ref_branches.contains_key(split) {
+            compared.push(branch)
+        }
+    }
+
+    (reference, compared, common)
+}
+
+/// RF distance where each split of the reference that is missing from the
+/// compared tree is weighted by its support, read from internal node labels.
+///
+/// Supports are normalized by the highest support in the reference, so that
+/// weights are in [0, 1] whatever the scale of support values. Splits
+/// missing support get `missing_weight`, or an error is returned if it is
+/// `None`. Splits of the compared tree that are missing from the reference
+/// count for 1 each.
+pub fn support_weighted_rf(
+    reftree: &Tree,
+    cmptree: &Tree,
+    missing_weight: Option<f64>,
+) -> Result<f64> {
+    let taxa = get_taxa(reftree)?;
+    if taxa != get_taxa(cmptree)? {
+        bail!("Trees must have the same tips to compare their splits")
+    }
+    let n = taxa.len();
+    let cmp_splits = get_splits(cmptree, &taxa)?;
+
+    let mut ref_supports: HashMap<FixedBitSet, Option<f64>> = HashMap::new();
+    for (id, mut split) in get_clades(reftree, &taxa)? {
+        let node = reftree.get(&id)?;
+        if node.is_tip() || node.parent.is_none() {
+            continue;
+        }
+        if split.contains(0) {
+            split.toggle_range(..);
+        }
+        let size = split.count_ones(..);
+        if size < 2 || size + 1 >= n {
+            continue;
+        }
+
+        let support = node.name.as_deref().and_then(|s| s.parse::<f64>().ok());
+        let entry = ref_supports.entry(split).or_insert(support);
+        if entry.is_none() {
+            *entry = support;
+        }
+    }
+
+    let max_support = ref_supports
+        .values()
+        .flatten()
+        .fold(0., |a: f64, &b| a.max(b));
+
+    let mut dist = 0.;
+    for (split, support) in ref_supports.iter() {
+        if cmp_splits.contains(split) {
+            continue;
+        }
+        dist += match (support, missing_weight) {
+            (Some(_), _) if max_support <= 0. => 0.,
+            (Some(support), _) => support / max_support,
+            (None, Some(weight)) => weight,
+            (None, None) => bail!("A split of the reference tree has no support value"),
+        };
+    }
+    dist += cmp_splits
+        .iter()
+        .filter(|s| !ref_supports.contains_key(*s))
+        .count() as f64;
+
+    Ok(dist)
+}
+
+/// Weight of the split separating a set of tips from the other tips
+#[derive(Debug, Clone)]
+pub struct SplitWeight {
+    pub tips: Vec<String>,
+    pub weight: f64,
+}
+
+/// RF distance where each split found in only one of the trees counts for
+/// its weight, given as the set of tips on one side of the split. Splits
+/// without a weight, or with tips that are not in `taxa`, count for 1.
+pub fn split_weighted_rf(
+    ref_splits: &HashSet<FixedBitSet>,
+    cmp_splits: &HashSet<FixedBitSet>,
+    taxa: &[String],
+    weights: &[SplitWeight],
+) -> f64 {
+    let index: HashMap<_, _> = taxa
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.as_str(), i))
+        .collect();
+    let weights: HashMap<_, _> = weights
+        .iter()
+        .filter_map(|w| {
+            let mut split = FixedBitSet::with_capacity(taxa.len());
+            for tip in w.tips.iter() {
+                split.insert(*index.get(tip.as_str())?);
+            }
+            if split.contains(0) {
+                split.toggle_range(..);
+            }
+            Some((split, w.weight))
+        })
+        .collect();
+
+    ref_splits
+        .symmetric_difference(cmp_splits)
+        .map(|split| weights.get(split).copied().unwrap_or(1.))
+        .sum()
+}
+
+// Jaccard index of two sets of tips
+fn jaccard(a: &FixedBitSet, b: &FixedBitSet) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+/// Similarity between two splits `A|A'` and `B|B'` as defined by Nye et al.
+/// (2006): the best Jaccard pairing of the split sides,
+/// `max(min(J(A, B), J(A', B')), min(J(A, B'), J(A', B)))`.
+fn nye_similarity(a: &FixedBitSet, b: &FixedBitSet, n: usize) -> f64 {
+    let mut a_c = a.clone();
+    a_c.toggle_range(..n);
+    let mut b_c = b.clone();
+    b_c.toggle_range(..n);
+
+    f64::max(
+        f64::min(jaccard(a, b), jaccard(&a_c, &b_c)),
+        f64::min(jaccard(a, &b_c), jaccard(&a_c, b)),
+    )
+}
+
+/// Align distance between two sets of splits over `n` tips.
+///
+/// Each split of one tree is matched with at most one split of the other,
+/// matching `A` and `B` costing `1 - s(A, B)` where `s` is the similarity of
+/// Nye et al. (2006), and unmatched splits costing 1. The distance is the
+/// cost of the optimal matching, found with the Hungarian algorithm.
+/// Identical splits cost nothing, so the distance is at most the number of
+/// splits that differ, i.e. it is bounded by the RF distance, but partially
+/// similar splits are only penalized in proportion to how much they differ
+/// (as in the matching split distance of Bogdanowicz & Giaro, 2012).
+///
+/// References:
+/// - Nye T.M.W., Liò P., Gilks W.R. (2006) A novel algorithm and web-based
+///   tool for comparing two alternative phylogenetic trees.
+///   Bioinformatics 22(1):117-119.
+/// - Bogdanowicz D., Giaro K. (2012) Matching split distance for unrooted
+///   binary phylogenetic trees. IEEE/ACM TCBB 9(1):150-160.
+pub fn align_distance(ref_splits: &[FixedBitSet], cmp_splits: &[FixedBitSet], n: usize) -> f64 {
+    let size = ref_splits.len().max(cmp_splits.len());
+    if size == 0 {
+        return 0.;
+    }
+
+    // Pad the cost matrix with unit costs for unmatched splits
+    let mut costs = vec![vec![1.; size]; size];
+    for (i, a) in ref_splits.iter().enumerate() {
+        for (j, b) in cmp_splits.iter().enumerate() {
+            costs[i][j] = 1. - nye_similarity(a, b, n);
+        }
+    }
+
+    hungarian(&costs)
+        .into_iter()
+        .enumerate()
+        .map(|(i, j)| costs[i][j])
+        .sum()
+}
+
+// Entropy (in bits) of a partition of `n` tips in parts of the given sizes
+fn entropy(sizes: &[usize], n: usize) -> f64 {
+    sizes
+        .iter()
+        .filter(|&&size| size > 0)
+        .map(|&size| {
+            let p = size as f64 / n as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// Mutual information (in bits) of the partitions of `n` tips induced by two
+// splits: the entropy of each split minus the entropy of their joint
+// partition in up to 4 parts
+fn mutual_clustering_info(a: &FixedBitSet, b: &FixedBitSet, n: usize) -> f64 {
+    let (size_a, size_b) = (a.count_ones(..), b.count_ones(..));
+    let both = a.intersection(b).count();
+    let joint = [
+        both,
+        size_a - both,
+        size_b - both,
+        n + both - size_a - size_b,
+    ];
+
+    entropy(&[size_a, n - size_a], n) + entropy(&[size_b, n - size_b], n) - entropy(&joint, n)
+}
+
+/// Clustering information distance (Smith, 2020) between two sets of splits
+/// over `n` tips, in bits.
+///
+/// The clustering information of a split is the entropy of the partition of
+/// tips it induces, and the clustering information of a tree is the sum over
+/// its splits. The mutual clustering information of two trees is the maximum
+/// over matchings of their splits of the sum of the mutual information of
+/// matched splits, found with the Hungarian algorithm. The distance is
+/// `CI(T1) + CI(T2) - 2 * MCI(T1, T2)`: it is 0 for identical trees, and
+/// contrary to the RF distance partially similar splits contribute less than
+/// completely different ones.
+///
+/// Reference:
+/// - Smith M.R. (2020) Information theoretic generalized Robinson–Foulds
+///   metrics for comparing phylogenetic trees. Bioinformatics 36(20):
+///   5007-5013.
+pub fn clustering_info_distance(
+    ref_splits: &[FixedBitSet],
+    cmp_splits: &[FixedBitSet],
+    n: usize,
+) -> f64 {
+    let split_info = |split: &FixedBitSet| {
+        let size = split.count_ones(..);
+        entropy(&[size, n - size], n)
+    };
+    let info = ref_splits
+        .iter()
+        .chain(cmp_splits)
+        .map(split_info)
+        .sum::<f64>();
+
+    let size = ref_splits.len().max(cmp_splits.len());
+    if size == 0 {
+        return 0.;
+    }
+
+    // Maximize the mutual information by minimizing its opposite, unmatched
+    // splits sharing no information
+    let mut costs = vec![vec![0.; size]; size];
+    for (i, a) in ref_splits.iter().enumerate() {
+        for (j, b) in cmp_splits.iter().enumerate() {
+            costs[i][j] = -mutual_clustering_info(a, b, n);
+        }
+    }
+    let mutual_info: f64 = hungarian(&costs)
+        .into_iter()
+        .enumerate()
+        .map(|(i, j)| -costs[i][j])
+        .sum();
+
+    // Rounding errors could make identical trees slightly negative
+    (info - 2. * mutual_info).max(0.)
+}
+
+/// Minimum cost assignment on a square cost matrix (Hungarian algorithm,
+/// O(n³)). Returns the column assigned to each row.
+#[allow(clippy::needless_range_loop)]
+fn hungarian(costs: &[Vec<f64>]) -> Vec<usize> {
+    let n = costs.len();
+    // Potentials and matching are 1-indexed, index 0 being a sentinel
+    let mut u = vec![0.; n + 1];
+    let mut v = vec![0.; n + 1];
+    let mut matched = vec![0; n + 1];
+    let mut way = vec![0; n + 1];
+
+    for row in 1..=n {
+        matched[0] = row;
+        let mut col = 0;
+        let mut min_v = vec![f64::INFINITY; n + 1];
+        let mut used = vec![false; n + 1];
+
+        loop {
+            used[col] = true;
+            let cur_row = matched[col];
+            let mut delta = f64::INFINITY;
+            let mut next_col = 0;
+            for j in 1..=n {
+                if used[j] {
+                    continue;
+                }
+                let cur = costs[cur_row - 1][j - 1] - u[cur_row] - v[j];
+                if cur < min_v[j] {
+                    min_v[j] = cur;
+                    way[j] = col;
+                }
+                if min_v[j] < delta {
+                    delta = min_v[j];
+                    next_col = j;
+                }
+            }
+            for j in 0..=n {
+                if used[j] {
+                    u[matched[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_v[j] -= delta;
+                }
+            }
+            col = next_col;
+            if matched[col] == 0 {
+                break;
+            }
+        }
+
+        loop {
+            let prev = way[col];
+            matched[col] = matched[prev];
+            col = prev;
+            if col == 0 {
+                break;
+            }
+        }
+    }
+
+    let mut assignment = vec![0; n];
+    for (col, &row) in matched.iter().enumerate().skip(1) {
+        assignment[row - 1] = col - 1;
+    }
+
+    assignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Split of `n` tips holding the tips at these indices
+    fn split(n: usize, tips: &[usize]) -> FixedBitSet {
+        let mut split = FixedBitSet::with_capacity(n);
+        for &tip in tips {
+            split.insert(tip);
+        }
+        split
+    }
+
+    #[test]
+    fn align_distance_of_hand_checked_pairs() {
+        let n = 5;
+        let (ab, ac, abc) = (split(n, &[0, 1]), split(n, &[0, 2]), split(n, &[0, 1, 2]));
+
+        // AB|CDE and AC|BDE: the best pairing of sides is AB with AC (1/3)
+        // and CDE with BDE (1/2), so the similarity is 1/3
+        let dist = align_distance(&[ab.clone()], &[ac.clone()], n);
+        assert!((dist - 2. / 3.).abs() < 1e-9, "{dist}");
+
+        // Both matchings of the extra ABC|DE split cost 2/3
+        let dist = align_distance(&[ab.clone(), abc.clone()], &[ac, abc.clone()], n);
+        assert!((dist - 2. / 3.).abs() < 1e-9, "{dist}");
+
+        // Unmatched splits cost 1
+        let dist = align_distance(&[ab.clone(), abc], &[ab], n);
+        assert!((dist - 1.).abs() < 1e-9, "{dist}");
+    }
+
+    #[test]
+    fn f1_is_zero_without_shared_splits() {
+        let n = 5;
+        let ref_splits = HashSet::from([split(n, &[0, 1])]);
+        let cmp_splits = HashSet::from([split(n, &[0, 2])]);
+
+        let (precision, recall, f1) = precision_recall(&ref_splits, &cmp_splits);
+        assert_eq!((precision, recall, f1), (0., 0., 0.));
+    }
+
+    #[test]
+    fn clade_rf_depends_on_the_root() {
+        let balanced = Tree::from_newick("((A,B),(C,D));").unwrap();
+        let caterpillar = Tree::from_newick("(((A,B),C),D);").unwrap();
+
+        // Both trees have the AB|CD split, but CD and ABC are only clades
+        // of one of them
+        assert_eq!(clade_rf(&balanced, &caterpillar).unwrap(), 2.);
+        assert_eq!(clade_rf(&balanced, &balanced).unwrap(), 0.);
+    }
+
+    #[test]
+    fn topology_comparison_merges_the_root_branches() {
+        let reftree = Tree::from_newick("((A:1,B:1):1,(C:1,(D:1,E:1):1):1);").unwrap();
+        let cmptree = Tree::from_newick("((A:1,C:1):1,(B:1,(D:1,E:1):1):1);").unwrap();
+
+        // The root branches are the AB|CDE split of length 2 in the
+        // reference and AC|BDE in the compared tree, DE|ABC is shared with
+        // the same length and terminal branches are left out
+        let (comparison, (reference, compared, common)) =
+            compare_topologies_and_branches(&reftree, &cmptree, false, false).unwrap();
+        assert_eq!(comparison.rf, 2.);
+        assert_eq!(comparison.norm_rf, 0.5);
+        assert_eq!(comparison.weighted_rf, 4.);
+        assert!((comparison.branch_score - 8f64.sqrt()).abs() < 1e-9);
+        assert_eq!((reference.len(), compared.len(), common.len()), (1, 1, 1));
+    }
+
+    #[test]
+    fn clustering_info_distance_of_known_trees() {
+        let tree = Tree::from_newick("((A,B),(C,(D,(E,F))));").unwrap();
+        let (n, splits, _) = get_shared_splits(&tree, &tree).unwrap();
+        let splits: Vec<_> = splits.into_iter().collect();
+        assert!(clustering_info_distance(&splits, &splits, n).abs() < 1e-9);
+
+        // An even split of 4 tips holds 1 bit, shared with no other split
+        let dist = clustering_info_distance(&[split(4, &[0, 1])], &[], 4);
+        assert!((dist - 1.).abs() < 1e-9, "{dist}");
+
+        // ((A,B),(C,(D,E))) has the AB|CDE and ABC|DE splits, and
+        // ((A,C),(B,(D,E))) has AC|BDE and ABC|DE. Each 2|3 split holds
+        // h = H(2/5, 3/5) = 0.970951 bits, so CI(T1) + CI(T2) = 4h.
+        //
+        // Mutual information of the pairs of splits, h + h minus the entropy
+        // of the joint partition:
+        // - ABC|DE and ABC|DE: A,B,C / D,E, so h
+        // - AB|CDE and AC|BDE: A / B / C / D,E, so
+        //   2h - H(1/5, 1/5, 1/5, 2/5) = 1.941901 - 1.921928 = 0.019973
+        // - AB|CDE and ABC|DE (or AC|BDE and ABC|DE): A,B / C / D,E, so
+        //   2h - H(2/5, 1/5, 2/5) = 1.941901 - 1.521928 = 0.419973
+        //
+        // The best matching pairs the identical splits, for an MCI of
+        // h + 0.019973 = 0.990924 against 0.839946 for the other matching,
+        // so CID = 4h - 2 * 0.990924 = 1.901955.
+        let n = 5;
+        let ref_splits = [split(n, &[0, 1]), split(n, &[0, 1, 2])];
+        let cmp_splits = [split(n, &[0, 2]), split(n, &[0, 1, 2])];
+        let dist = clustering_info_distance(&ref_splits, &cmp_splits, n);
+        assert!((dist - 1.901955).abs() < 1e-6, "{dist}");
+
+        // The same splits, complemented as they are read from trees
+        let ref_splits = [split(n, &[2, 3, 4]), split(n, &[3, 4])];
+        let cmp_splits = [split(n, &[1, 3, 4]), split(n, &[3, 4])];
+        let dist = clustering_info_distance(&ref_splits, &cmp_splits, n);
+        assert!((dist - 1.901955).abs() < 1e-6, "{dist}");
+    }
+
+    #[test]
+    fn mast_size_of_caterpillar_and_balanced_trees() {
+        let balanced = Tree::from_newick("((A,B),(C,D));").unwrap();
+        let caterpillar = Tree::from_newick("(((A,B),C),D);").unwrap();
+
+        // Removing C or D leaves the same tree, e.g. ((A,B),C)
+        assert_eq!(mast_size(&balanced, &caterpillar).unwrap(), 3);
+        assert_eq!(mast_size(&caterpillar, &caterpillar).unwrap(), 4);
+    }
+}
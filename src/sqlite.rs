@@ -0,0 +1,252 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::{params, Connection};
+
+use phylocompare::{
+    comp::{CompOpts, ComparisonRecord},
+    io::check_overwrite,
+};
+
+// Number of rows inserted in a single transaction
+const BATCH_SIZE: usize = 10_000;
+
+// Schema of the database, the columns match the ones of the csv outputs
+const SCHEMA: &str = "
+CREATE TABLE topology (
+    id TEXT NOT NULL,
+    rf REAL,
+    norm_rf REAL,
+    weighted_rf REAL,
+    kf_score REAL,
+    n_tips INTEGER,
+    align_dist REAL,
+    support_weighted_rf REAL,
+    best_ref_id TEXT,
+    clade_rf REAL,
+    precision REAL,
+    recall REAL,
+    f1 REAL,
+    ref_height REAL,
+    cmp_height REAL,
+    height_diff REAL,
+    norm_kf_score REAL,
+    rf_p_value REAL,
+    rf_info REAL,
+    mast_size INTEGER,
+    norm_mast_size REAL,
+    split_weighted_rf REAL,
+    is_refinement INTEGER,
+    refinement_rf REAL,
+    shared_weighted_rf REAL,
+    ref_root_degree INTEGER,
+    cmp_root_degree INTEGER,
+    approx_spr INTEGER,
+    ref_diameter REAL,
+    cmp_diameter REAL,
+    diameter_diff REAL,
+    marker TEXT
+);
+CREATE TABLE branches (
+    id TEXT NOT NULL,
+    ref_len REAL,
+    ref_depth INTEGER,
+    cmp_len REAL,
+    cmp_depth INTEGER,
+    marker TEXT
+);
+CREATE TABLE distances (
+    id TEXT NOT NULL,
+    ref_dist REAL,
+    cmp_dist REAL,
+    marker TEXT
+);
+";
+
+/// SQLite database receiving the comparison records, instead of csv files
+pub struct SqliteOutput {
+    path: PathBuf,
+    conn: Connection,
+    // Number of rows inserted in the current transaction
+    pending: usize,
+    // Trees with streamed distances whose last record was not inserted yet
+    streaming: HashSet<String>,
+}
+
+impl SqliteOutput {
+    /// Create the database and its tables. An existing database is only
+    /// overwritten if `force` is set.
+    pub fn new(path: &Path, opts: &CompOpts, force: bool) -> Result<Self> {
+        if opts.clades.is_some()
+            || opts.label_field.is_some()
+            || opts.internal_labels
+            || opts.canonical
+            || opts.cherries
+            || opts.root_agreement
+            || opts.branch_regression
+            || opts.distance_weight.is_some()
+            || opts.compatibility
+        {
+            bail!("Only topology, branch and distance comparisons can be written to SQLite")
+        }
+
+        check_overwrite([path], force)?;
+        if path.exists() {
+            fs::remove_file(path)
+                .context(format!("Could not remove database: {}", path.display()))?;
+        }
+
+        let conn = Connection::open(path)
+            .context(format!("Could not create database: {}", path.display()))?;
+        conn.execute_batch(SCHEMA)?;
+        conn.execute_batch("BEGIN")?;
+
+        Ok(Self {
+            path: path.into(),
+            conn,
+            pending: 0,
+            streaming: HashSet::new(),
+        })
+    }
+
+    /// Insert the rows of a comparison record
+    pub fn insert(&mut self, record: &ComparisonRecord) -> Result<()> {
+        let marker = record.provenance.marker.as_deref();
+
+        if let Some(topo) = &record.topology {
+            self.conn
+                .prepare_cached(
+                    "INSERT INTO topology VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, ?27, ?28, ?29, ?30, ?31, ?32)",
+                )?
+                .execute(params![
+                    topo.id.as_str(),
+                    topo.rf,
+                    topo.norm_rf,
+                    topo.weighted_rf,
+                    topo.kf_score,
+                    topo.n_tips,
+                    topo.align_dist,
+                    topo.support_weighted_rf,
+                    topo.best_ref_id,
+                    topo.clade_rf,
+                    topo.precision,
+                    topo.recall,
+                    topo.f1,
+                    topo.ref_height,
+                    topo.cmp_height,
+                    topo.height_diff,
+                    topo.norm_kf_score,
+                    topo.rf_p_value,
+                    topo.rf_info,
+                    topo.mast_size.flatten(),
+                    topo.norm_mast_size,
+                    topo.split_weighted_rf,
+                    topo.is_refinement.flatten(),
+                    topo.refinement_rf,
+                    topo.shared_weighted_rf,
+                    topo.ref_root_degree.flatten(),
+                    topo.cmp_root_degree.flatten(),
+                    topo.approx_spr.flatten(),
+                    topo.ref_diameter,
+                    topo.cmp_diameter,
+                    topo.diameter_diff,
+                    marker,
+                ])?;
+            self.pending += 1;
+        }
+
+        if let Some(branches) = &record.branches {
+            let mut stmt = self
+                .conn
+                .prepare_cached("INSERT INTO branches VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+            for branch in branches {
+                stmt.execute(params![
+                    branch.id.as_str(),
+                    branch.ref_len,
+                    branch.ref_depth,
+                    branch.cmp_len,
+                    branch.cmp_depth,
+                    marker,
+                ])?;
+            }
+            self.pending += branches.len();
+        }
+
+        if let Some(distances) = &record.distances {
+            if record.is_chunk {
+                self.streaming.insert(record.id.to_string());
+            }
+            let mut stmt = self
+                .conn
+                .prepare_cached("INSERT INTO distances VALUES (?1, ?2, ?3, ?4)")?;
+            for dist in distances {
+                stmt.execute(params![
+                    dist.id.as_str(),
+                    dist.ref_dist,
+                    dist.cmp_dist,
+                    marker
+                ])?;
+            }
+            self.pending += distances.len();
+        }
+
+        if self.pending >= BATCH_SIZE {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+            self.pending = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Mark the streamed distances of a tree as complete once its last
+    /// record is received, or remove them if its comparison failed
+    pub fn finish(&mut self, id: &str, complete: bool) -> Result<()> {
+        if self.streaming.remove(id) && !complete {
+            self.discard_distances(id)?;
+        }
+        Ok(())
+    }
+
+    // Remove the streamed distances of a tree
+    fn discard_distances(&self, id: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM distances WHERE id = ?1")?
+            .execute(params![id])?;
+        Ok(())
+    }
+
+    /// Commit the remaining rows
+    pub fn flush(&mut self) -> Result<()> {
+        self.conn.execute_batch("COMMIT; BEGIN")?;
+        self.pending = 0;
+        Ok(())
+    }
+
+    // Print where the outputs were written
+    pub fn report(&self) {
+        eprintln!("Wrote comparisons to database: {}", self.path.display())
+    }
+}
+
+// Rows of the open transaction are committed when the output is dropped, so
+// that they are kept when the run stops early on an error. Distances of the
+// trees being compared are removed first, since they may be incomplete.
+impl Drop for SqliteOutput {
+    fn drop(&mut self) {
+        if self.conn.is_autocommit() {
+            return;
+        }
+        let committed = self
+            .streaming
+            .iter()
+            .try_for_each(|id| self.discard_distances(id))
+            .and_then(|_| Ok(self.conn.execute_batch("COMMIT")?));
+        if let Err(e) = committed {
+            eprintln!("Could not commit database: {e:#}");
+        }
+    }
+}
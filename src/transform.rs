@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use phylotree::tree::{Node, NodeId, Tree};
+use regex::Regex;
+
+type Adjacency = HashMap<NodeId, Vec<(NodeId, f64)>>;
+
+// Get the undirected, weighted adjacency list of a tree
+fn get_adjacency(tree: &Tree) -> Result<Adjacency> {
+    let root = tree.get_root()?;
+    let mut adj: Adjacency = HashMap::new();
+
+    for id in tree.preorder(&root)? {
+        adj.entry(id).or_default();
+        for child in tree.get(&id)?.children.iter() {
+            let len = tree
+                .get(child)?
+                .parent_edge
+                .context("All branches must have a length")?;
+            adj.entry(id).or_default().push((*child, len));
+            adj.entry(*child).or_default().push((id, len));
+        }
+    }
+
+    Ok(adj)
+}
+
+// Find the tip farthest away from `start`, returning it with its distance
+// and the predecessor of each visited node on the path from `start`
+fn farthest_tip(adj: &Adjacency, start: NodeId) -> (NodeId, f64, HashMap<NodeId, NodeId>) {
+    let mut previous = HashMap::new();
+    let mut stack = vec![(start, start, 0.)];
+    let (mut far, mut far_dist) = (start, 0.);
+
+    while let Some((node, from, dist)) = stack.pop() {
+        if adj[&node].len() == 1 && dist > far_dist {
+            (far, far_dist) = (node, dist);
+        }
+        for &(next, len) in adj[&node].iter().filter(|(n, _)| *n != from) {
+            previous.insert(next, node);
+            stack.push((next, node, dist + len));
+        }
+    }
+
+    (far, far_dist, previous)
+}
+
+/// Re-root a tree at the midpoint of its longest tip-to-tip path. The tree
+/// must have branch lengths. Unnamed nodes of degree 2 (like the former
+/// root) are removed, merging their branches.
+pub fn midpoint_root(tree: &Tree) -> Result<Tree> {
+    let adj = get_adjacency(tree).context("Midpoint rooting requires branch lengths")?;
+
+    let Some(&start) = tree.get_leaves().first() else {
+        bail!("Cannot midpoint root an empty tree")
+    };
+    let (tip_a, _, _) = farthest_tip(&adj, start);
+    let (tip_b, diameter, previous) = farthest_tip(&adj, tip_a);
+
+    // Walk back from tip_b until reaching the branch that contains the midpoint
+    let half = diameter / 2.;
+    let (mut node, mut walked) = (tip_b, 0.);
+    let (upper, lower, offset) = loop {
+        let Some(&next) = previous.get(&node) else {
+            bail!("Could not find the midpoint of the tree")
+        };
+        let len = adj[&node]
+            .iter()
+            .find_map(|&(n, l)| (n == next).then_some(l))
+            .unwrap_or_default();
+        if walked + len >= half {
+            break (node, next, half - walked);
+        }
+        walked += len;
+        node = next;
+    };
+    let branch_len = adj[&upper]
+        .iter()
+        .find_map(|&(n, l)| (n == lower).then_some(l))
+        .unwrap_or_default();
+
+    // Rebuild the tree from the new root
+    let mut rooted = Tree::new();
+    let root = rooted.add(Node::new());
+    let mut stack = vec![
+        (upper, lower, root, offset),
+        (lower, upper, root, branch_len - offset),
+    ];
+
+    while let Some((old, from, parent, len)) = stack.pop() {
+        let old_node = tree.get(&old)?;
+        let next: Vec<_> = adj[&old].iter().filter(|(n, _)| *n != from).collect();
+
+        if next.len() == 1 && old_node.name.is_none() {
+            let &(n, l) = next[0];
+            stack.push((n, old, parent, len + l));
+            continue;
+        }
+
+        let new_node = match &old_node.name {
+            Some(name) => Node::new_named(name),
+            None => Node::new(),
+        };
+        let id = rooted.add_child(new_node, parent, Some(len))?;
+        for &(n, l) in next {
+            stack.push((n, old, id, l));
+        }
+    }
+
+    rooted.reset_depths()?;
+
+    Ok(rooted)
+}
+
+/// Keep only the tips whose name matches the regex. Internal nodes left with
+/// a single child are removed, merging their branches.
+pub fn keep_tips(tree: &Tree, re: &Regex) -> Result<Tree> {
+    let root = tree.get_root()?;
+
+    // Count kept tips below each node
+    let mut kept: HashMap<NodeId, usize> = HashMap::new();
+    for id in tree.postorder(&root)? {
+        let node = tree.get(&id)?;
+        let n = if node.is_tip() {
+            usize::from(node.name.as_deref().is_some_and(|n| re.is_match(n)))
+        } else {
+            node.children.iter().map(|c| kept[c]).sum()
+        };
+        kept.insert(id, n);
+    }
+    if kept[&root] == 0 {
+        bail!("No tips left after selecting tips with: {re}")
+    }
+
+    let mut pruned = Tree::new();
+    let mut stack = vec![(root, None, None)];
+
+    while let Some((old, parent, len)) = stack.pop() {
+        let old_node = tree.get(&old)?;
+        let children: Vec<_> = old_node.children.iter().filter(|c| kept[*c] > 0).collect();
+
+        if children.len() == 1 {
+            let child = *children[0];
+            // A new root does not have a parent branch
+            let merged = match (parent, len, tree.get(&child)?.parent_edge) {
+                (None, _, _) => None,
+                (_, Some(a), Some(b)) => Some(a + b),
+                (_, a, b) => a.or(b),
+            };
+            stack.push((child, parent, merged));
+            continue;
+        }
+
+        let new_node = match &old_node.name {
+            Some(name) => Node::new_named(name),
+            None => Node::new(),
+        };
+        let id = match parent {
+            None => pruned.add(new_node),
+            Some(parent) => pruned.add_child(new_node, parent, len)?,
+        };
+        for child in children {
+            stack.push((*child, Some(id), tree.get(child)?.parent_edge));
+        }
+    }
+
+    pruned.reset_depths()?;
+
+    Ok(pruned)
+}
+
+/// Collapse internal branches with a support lower than the threshold. The
+/// support of a branch is read from the label of the node below it, and
+/// branches without support are kept. Children of a collapsed node are
+/// attached to its parent, the collapsed branch length being added to theirs.
+pub fn collapse_support(tree: &Tree, threshold: f64) -> Result<Tree> {
+    collapse_nodes(tree, |_, node| {
+        let support = node.name.as_deref().and_then(|s| s.parse::<f64>().ok());
+        !node.is_tip() && support.is_some_and(|s| s < threshold)
+    })
+}
+
+/// Turn a bifurcating root into a trifurcation, by collapsing the branch
+/// below the root leading to its first internal child. Both branches below
+/// the root form a single branch of the unrooted tree, so the length of the
+/// collapsed branch is added to the other one and tip distances are kept.
+/// Other roots are kept.
+pub fn trifurcate_root(tree: &Tree) -> Result<Tree> {
+    let root = tree.get_root()?;
+    let root_node = tree.get(&root)?;
+    let [first, second] = root_node.children[..] else {
+        return Ok(tree.clone());
+    };
+    let (internal, other) = if !tree.get(&first)?.is_tip() {
+        (first, second)
+    } else if !tree.get(&second)?.is_tip() {
+        (second, first)
+    } else {
+        return Ok(tree.clone());
+    };
+    let other_len = match (
+        tree.get(&internal)?.parent_edge,
+        tree.get(&other)?.parent_edge,
+    ) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    };
+
+    let mut trifurcated = Tree::new();
+    let new_root = trifurcated.add(match &root_node.name {
+        Some(name) => Node::new_named(name),
+        None => Node::new(),
+    });
+
+    // Children are pushed in reverse to keep their order, the children of
+    // the collapsed node taking its place
+    let mut stack = vec![];
+    for child in root_node.children.iter().rev() {
+        if *child == internal {
+            for grandchild in tree.get(child)?.children.iter().rev() {
+                stack.push((*grandchild, new_root, tree.get(grandchild)?.parent_edge));
+            }
+        } else {
+            stack.push((*child, new_root, other_len));
+        }
+    }
+    while let Some((old, parent, len)) = stack.pop() {
+        let old_node = tree.get(&old)?;
+        let new_node = match &old_node.name {
+            Some(name) => Node::new_named(name),
+            None => Node::new(),
+        };
+        let id = trifurcated.add_child(new_node, parent, len)?;
+        for child in old_node.children.iter().rev() {
+            stack.push((*child, id, tree.get(child)?.parent_edge));
+        }
+    }
+    trifurcated.reset_depths()?;
+
+    Ok(trifurcated)
+}
+
+/// Turn a root with more than two children into a bifurcation, by grouping
+/// all its children but the first under a new node, with a branch of length
+/// 0. Unrooted, the tree keeps the same splits.
+pub fn bifurcate_root(tree: &Tree) -> Result<Tree> {
+    let root = tree.get_root()?;
+    let root_node = tree.get(&root)?;
+    let Some((first, others)) = root_node.children.split_first() else {
+        return Ok(tree.clone());
+    };
+    if others.len() < 2 {
+        return Ok(tree.clone());
+    }
+
+    let mut resolved = Tree::new();
+    let new_root = resolved.add(match &root_node.name {
+        Some(name) => Node::new_named(name),
+        None => Node::new(),
+    });
+    // Only trees with branch lengths get a length for the new branch
+    let len = tree.get(first)?.parent_edge.map(|_| 0.);
+    let group = resolved.add_child(Node::new(), new_root, len)?;
+
+    let mut stack = vec![(*first, new_root)];
+    stack.extend(others.iter().map(|child| (*child, group)));
+    while let Some((old, parent)) = stack.pop() {
+        let old_node = tree.get(&old)?;
+        let new_node = match &old_node.name {
+            Some(name) => Node::new_named(name),
+            None => Node::new(),
+        };
+        let id = resolved.add_child(new_node, parent, old_node.parent_edge)?;
+        for child in old_node.children.iter().rev() {
+            stack.push((*child, id));
+        }
+    }
+    resolved.reset_depths()?;
+
+    Ok(resolved)
+}
+
+// Copy a tree without the non-root nodes selected by `collapse`. Children of
+// a collapsed node are attached to its parent, the collapsed branch length
+// being added to theirs.
+fn collapse_nodes(tree: &Tree, collapse: impl Fn(NodeId, &Node) -> bool) -> Result<Tree> {
+    let root = tree.get_root()?;
+    let mut collapsed = Tree::new();
+    let mut stack = vec![(root, None, None)];
+
+    while let Some((old, parent, len)) = stack.pop() {
+        let old_node = tree.get(&old)?;
+
+        let (parent, len) = match parent {
+            Some(parent) if collapse(old, old_node) => (parent, len),
+            _ => {
+                let new_node = match &old_node.name {
+                    Some(name) => Node::new_named(name),
+                    None => Node::new(),
+                };
+                let id = match parent {
+                    None => collapsed.add(new_node),
+                    Some(parent) => collapsed.add_child(new_node, parent, len)?,
+                };
+                (id, None)
+            }
+        };
+
+        for child in old_node.children.iter() {
+            let child_len = match (len, tree.get(child)?.parent_edge) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, b) => a.or(b),
+            };
+            stack.push((*child, Some(parent), child_len));
+        }
+    }
+
+    collapsed.reset_depths()?;
+
+    Ok(collapsed)
+}
+
+// Relative difference of tip depths under which a tree is ultrametric
+const ULTRAMETRIC_TOLERANCE: f64 = 1e-6;
+
+/// Rescale the branch lengths of a tree so that its height (the largest
+/// root-to-tip distance) is 1. Also returns whether the tree is ultrametric,
+/// i.e. all its tips are at the same distance from the root.
+pub fn unit_height(tree: &Tree) -> Result<(Tree, bool)> {
+    let root = tree.get_root()?;
+    let mut depths: HashMap<NodeId, f64> = HashMap::from([(root, 0.)]);
+    let (mut min_height, mut height) = (f64::INFINITY, 0_f64);
+    for id in tree.preorder(&root)? {
+        let node = tree.get(&id)?;
+        let Some(parent) = node.parent else {
+            continue;
+        };
+        let len = node
+            .parent_edge
+            .context("All branches must have a length to rescale the tree")?;
+        let depth = depths[&parent] + len;
+        depths.insert(id, depth);
+        if node.is_tip() {
+            min_height = min_height.min(depth);
+            height = height.max(depth);
+        }
+    }
+    if height <= 0. {
+        bail!("Trees must have a positive height to be rescaled")
+    }
+
+    let mut rescaled = Tree::new();
+    let mut stack = vec![(root, None)];
+    while let Some((old, parent)) = stack.pop() {
+        let old_node = tree.get(&old)?;
+        let new_node = match &old_node.name {
+            Some(name) => Node::new_named(name),
+            None => Node::new(),
+        };
+        let id = match parent {
+            None => rescaled.add(new_node),
+            Some(parent) => {
+                rescaled.add_child(new_node, parent, old_node.parent_edge.map(|l| l / height))?
+            }
+        };
+        // Children are pushed in reverse to keep their order
+        for child in old_node.children.iter().rev() {
+            stack.push((*child, Some(id)));
+        }
+    }
+    rescaled.reset_depths()?;
+
+    let ultrametric = (height - min_height) / height <= ULTRAMETRIC_TOLERANCE;
+
+    Ok((rescaled, ultrametric))
+}
+
+/// Canonical newick string of a tree: children are sorted and labels are
+/// trimmed, so that trees with the same topology and labels give the same
+/// string however they were written. Branch lengths are ignored and the
+/// tree is considered as rooted. Labels of internal nodes (e.g. supports)
+/// are left out unless `internal_labels` is set.
+pub fn canonical_newick(tree: &Tree, internal_labels: bool) -> Result<String> {
+    let root = tree.get_root()?;
+    let mut subtrees: HashMap<NodeId, String> = HashMap::new();
+
+    for id in tree.postorder(&root)? {
+        let node = tree.get(&id)?;
+        let label = node.name.as_deref().unwrap_or_default().trim();
+        let subtree = if node.is_tip() {
+            label.to_string()
+        } else {
+            let mut children = node
+                .children
+                .iter()
+                .map(|c| subtrees.remove(c).unwrap_or_default())
+                .collect::<Vec<_>>();
+            children.sort();
+            let label = if internal_labels { label } else { "" };
+            format!("({}){label}", children.join(","))
+        };
+        subtrees.insert(id, subtree);
+    }
+
+    Ok(format!("{};", subtrees.remove(&root).unwrap_or_default()))
+}
+
+// Next value of a splitmix64 generator
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+// Fisher-Yates shuffle drawn from a splitmix64 generator
+fn shuffle<T>(items: &mut [T], state: &mut u64) {
+    for i in (1..items.len()).rev() {
+        let j = (splitmix64(state) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// Shuffle the tip labels of a tree, keeping its shape. The permutation is
+/// drawn from a splitmix64 generator, and only depends on the seed.
+pub fn shuffle_tips(tree: &Tree, seed: u64) -> Result<Tree> {
+    let tips = tree.get_leaves();
+    let mut names = tips
+        .iter()
+        .map(|tip| Ok(tree.get(tip)?.name.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    let mut state = seed;
+    shuffle(&mut names, &mut state);
+
+    let mut shuffled = tree.clone();
+    for (tip, name) in tips.iter().zip(names) {
+        shuffled.get_mut(tip)?.name = name;
+    }
+
+    Ok(shuffled)
+}
+
+/// Random binary tree with tips named `t0`, `t1`... and branch lengths in
+/// (0, 1]. Clades are built top-down by splitting a shuffled list of tips
+/// at random points, drawn from a splitmix64 generator seeded with `seed`.
+pub fn random_tree(n_tips: usize, seed: u64) -> Result<Tree> {
+    if n_tips < 2 {
+        bail!("Random trees must have at least 2 tips")
+    }
+    let mut state = seed;
+    let mut names = (0..n_tips).map(|i| format!("t{i}")).collect_vec();
+    shuffle(&mut names, &mut state);
+
+    let mut tree = Tree::new();
+    let root = tree.add(Node::new());
+    let mut stack = vec![(root, 0, n_tips)];
+    while let Some((node, start, end)) = stack.pop() {
+        // Both sides of the split hold at least one tip
+        let mid = start + 1 + (splitmix64(&mut state) % (end - start - 1) as u64) as usize;
+        for (start, end) in [(start, mid), (mid, end)] {
+            let len = ((splitmix64(&mut state) >> 11) + 1) as f64 / (1_u64 << 53) as f64;
+            if end - start == 1 {
+                tree.add_child(Node::new_named(&names[start]), node, Some(len))?;
+            } else {
+                let child = tree.add_child(Node::new(), node, Some(len))?;
+                stack.push((child, start, end));
+            }
+        }
+    }
+    tree.reset_depths()?;
+
+    Ok(tree)
+}
+
+/// Stable 64 bit FNV-1a hash of a string
+pub fn hash_u64(s: &str) -> u64 {
+    hash_bytes_u64(s.as_bytes())
+}
+
+// Stable 64 bit FNV-1a hash of bytes
+fn hash_bytes_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Stable 64 bit FNV-1a hash of bytes, as hexadecimal
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:016x}", hash_bytes_u64(bytes))
+}
+
+/// Stable 64 bit FNV-1a hash of a string, as hexadecimal
+pub fn hash_string(s: &str) -> String {
+    format!("{:016x}", hash_u64(s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dist::TipDistances;
+
+    #[test]
+    fn trifurcate_root_keeps_distances() {
+        let tree = Tree::from_newick("((A:1,B:2):1.5,(C:1,D:3):0.5);").unwrap();
+        let trifurcated = trifurcate_root(&tree).unwrap();
+
+        let root = trifurcated.get_root().unwrap();
+        assert_eq!(trifurcated.get(&root).unwrap().children.len(), 3);
+
+        let before = TipDistances::from_lengths(&tree).unwrap();
+        let after = TipDistances::from_lengths(&trifurcated).unwrap();
+        for (tip_1, tip_2) in before.taxa.iter().tuple_combinations() {
+            let (a, b) = (before.get(tip_1, tip_2), after.get(tip_1, tip_2));
+            assert!((a.unwrap() - b.unwrap()).abs() < 1e-9, "{tip_1}, {tip_2}");
+        }
+    }
+
+    #[test]
+    fn canonical_newick_leaves_out_internal_labels() {
+        let tree = Tree::from_newick("((B,A)90,(D,C)75)root;").unwrap();
+        let other = Tree::from_newick("((C,D)50,(A,B));").unwrap();
+
+        assert_eq!(canonical_newick(&tree, false).unwrap(), "((A,B),(C,D));");
+        assert_eq!(
+            canonical_newick(&tree, false).unwrap(),
+            canonical_newick(&other, false).unwrap()
+        );
+        assert_eq!(
+            canonical_newick(&tree, true).unwrap(),
+            "((A,B)90,(C,D)75)root;"
+        );
+    }
+}